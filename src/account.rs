@@ -1,4 +1,5 @@
-mod account_model;
+pub mod account_model;
+pub mod token_store;
 
 use bevy::{
     prelude::*,
@@ -6,75 +7,177 @@ use bevy::{
 };
 use futures_lite::future;
 
+use crate::{
+    api_client::{self, ApiErrorEvent, ApiResult, CaoApiError, Retry},
+    cao_sim_client::cao_client::CaoClient,
+    server_config::{ServerConfig, ServerProfileChanged},
+};
+
 pub type AuthToken = String;
 pub type AuthTokenRef<'a> = &'a str;
 pub type LoginError = String;
 pub type LoginResult<T> = Result<T, LoginError>;
 pub type LoginRequestTask = Task<LoginResult<AuthToken>>;
 
+/// Refresh the token this many seconds before it expires
+pub const REFRESH_MARGIN_SECS: u64 = 60;
+/// Wait this long before retrying a refresh that failed without the server rejecting the token,
+/// also the minimum time between two refreshes
+pub const REFRESH_RETRY_SECS: u64 = 10;
+
 pub struct CurrentAuthToken(pub Option<AuthToken>);
 pub struct LastLoginError(pub Option<LoginError>);
+/// Profile of the logged in user
+pub struct CurrentUser(pub Option<account_model::UserInfo>);
+
+/// Details of the current token
+#[derive(Debug, Default, Clone)]
+pub struct AuthSession {
+    /// api url of the server that issued the token, it's not valid for other servers
+    pub api_url: String,
+    /// unix timestamp, seconds
+    pub expires_at: Option<u64>,
+    /// persist the token on disk
+    pub remember: bool,
+}
 
 #[derive(Default, Clone)]
 pub struct StartLoginEvent {
     pub username: String,
     pub password: String,
+    pub remember: bool,
 }
 
-async fn login(username: String, password: String) -> LoginResult<AuthToken> {
-    let mut res = surf::post(format!("{}/token", crate::API_BASE_URL))
-        .header("Content-Type", "application/x-www-form-urlencoded")
-        .body(format!(
-            "grant_type=&username={}&password={}&scope=&client_id=&client_secret=",
-            username, password,
-        ))
-        .await
-        .expect("Login request failed");
-
-    match res.status() {
-        surf::StatusCode::Ok => {
-            let body: account_model::LoginSuccess =
-                res.body_json().await.expect("Failed to read response");
-            debug!("Successful login");
-            Ok(body.access_token)
-        }
-        surf::StatusCode::UnprocessableEntity => {
-            let body: account_model::LoginUnprocEntity = res
-                .body_json()
-                .await
-                .expect("Failed to read unproc entity response");
-
-            debug!("{} {:?}", res.status(), body);
-
-            Err(format!(
-                "{}: {}",
-                body.detail[0].loc.last().map(|x| x.as_str()).unwrap_or(""),
-                body.detail[0].msg
-            ))
-        }
-        _ => {
-            let body: account_model::LoginError = res
-                .body_json()
-                .await
-                .expect("Failed to read error response");
+pub struct LogoutEvent;
+
+#[derive(Default, Clone)]
+pub struct StartRegisterEvent {
+    pub username: String,
+    pub email: String,
+    pub password: String,
+    pub password_confirm: String,
+    pub remember: bool,
+}
+
+/// Logs in with the credentials once the registration succeeds
+pub struct RegisterTask {
+    task: Task<LoginResult<()>>,
+    login: StartLoginEvent,
+}
+struct CurrentUserTask(Task<ApiResult<account_model::UserInfo>>);
+
+/// Marks login tasks started with the "remember me" option
+struct RememberToken;
+struct RefreshTokenTask(Task<ApiResult<AuthToken>>);
+
+async fn login(api_url: String, username: String, password: String) -> LoginResult<AuthToken> {
+    let body: account_model::LoginSuccess = api_client::send_json(Retry::Never, || {
+        Ok(surf::post(format!("{}/token", api_url))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(format!(
+                "grant_type=&username={}&password={}&scope=&client_id=&client_secret=",
+                username, password,
+            )))
+    })
+    .await
+    .map_err(|err| err.to_string())?;
+    debug!("Successful login");
+    Ok(body.access_token)
+}
+
+/// Client side checks of the registration form
+fn validate_registration(form: &StartRegisterEvent) -> LoginResult<()> {
+    if form.username.trim().is_empty() {
+        return Err("username: must not be empty".to_string());
+    }
+    if !form.email.contains('@') {
+        return Err("email: not a valid email address".to_string());
+    }
+    if form.password.is_empty() {
+        return Err("password: must not be empty".to_string());
+    }
+    if form.password != form.password_confirm {
+        return Err("password: the passwords do not match".to_string());
+    }
+    Ok(())
+}
+
+async fn register(api_url: String, form: account_model::RegisterForm) -> LoginResult<()> {
+    api_client::send(Retry::Never, || {
+        api_client::with_json(surf::post(format!("{}/users/register", api_url)), &form)
+    })
+    .await
+    .map_err(|err| err.to_string())?;
+    debug!("Successful registration");
+    Ok(())
+}
 
-            debug!("{} {:?}", res.status(), body);
+async fn fetch_current_user(
+    api_url: String,
+    token: AuthToken,
+) -> ApiResult<account_model::UserInfo> {
+    api_client::send_json(Retry::Transient, || {
+        Ok(surf::get(format!("{}/users/me", api_url)).header("Authorization", token.as_str()))
+    })
+    .await
+}
+
+async fn refresh(api_url: String, token: AuthToken) -> ApiResult<AuthToken> {
+    let body: account_model::LoginSuccess =
+        api_client::send_json(Retry::Transient, || {
+            Ok(surf::post(format!("{}/token/refresh", api_url))
+                .header("Authorization", token.as_str()))
+        })
+        .await?;
+    Ok(body.access_token)
+}
 
-            Err(body.detail)
+/// Install a newly issued token
+fn set_token(
+    raw_token: AuthToken,
+    remember: bool,
+    api_url: &str,
+    token: &mut CurrentAuthToken,
+    session: &mut AuthSession,
+) {
+    let expires_at = token_store::decode_expiry(raw_token.as_str());
+    if remember {
+        let stored = token_store::StoredToken {
+            token: raw_token.clone(),
+            expires_at,
+        };
+        if let Err(err) = token_store::save(api_url, stored) {
+            error!("Failed to store the token {:?}", err);
         }
     }
+    token.0 = Some(format!("Bearer {}", raw_token));
+    *session = AuthSession {
+        api_url: api_url.to_string(),
+        expires_at,
+        remember,
+    };
 }
 
 fn handle_tasks_system(
     mut cmd: Commands,
     mut token: ResMut<CurrentAuthToken>,
+    mut session: ResMut<AuthSession>,
     mut error: ResMut<LastLoginError>,
-    tasks: Query<(Entity, &mut LoginRequestTask)>,
+    config: Res<ServerConfig>,
+    tasks: Query<(Entity, &mut LoginRequestTask, Option<&RememberToken>)>,
 ) {
-    tasks.for_each_mut(|(e, mut t)| {
+    tasks.for_each_mut(|(e, mut t, remember)| {
         if let Some(res) = future::block_on(future::poll_once(&mut *t)) {
             match res {
-                Ok(t) => token.0 = Some(format!("Bearer {}", t)),
+                Ok(t) => {
+                    let api_url = config.current().api_base_url.as_str();
+                    if remember.is_none() {
+                        // a previously remembered token must not outlive this login
+                        token_store::clear(api_url)
+                            .unwrap_or_else(|err| error!("Failed to clear token {:?}", err));
+                    }
+                    set_token(t, remember.is_some(), api_url, &mut *token, &mut *session);
+                }
                 Err(e) => error.0 = Some(e),
             }
             cmd.entity(e).despawn_recursive();
@@ -82,15 +185,266 @@ fn handle_tasks_system(
     });
 }
 
+/// Log in with the token stored for the server at `api_url`, if it's still valid
+fn restore_token(api_url: &str, token: &mut CurrentAuthToken, session: &mut AuthSession) {
+    let stored = match token_store::load(api_url) {
+        Ok(Some(t)) => t,
+        Ok(None) => return,
+        Err(err) => {
+            error!("Failed to load the stored token {:?}", err);
+            return;
+        }
+    };
+    if stored.is_expired() {
+        debug!("Stored token expired");
+        token_store::clear(api_url).unwrap_or_else(|err| error!("Failed to clear token {:?}", err));
+        return;
+    }
+    info!("Restored the stored session");
+    token.0 = Some(format!("Bearer {}", stored.token));
+    *session = AuthSession {
+        api_url: api_url.to_string(),
+        expires_at: stored.expires_at,
+        remember: true,
+    };
+}
+
+fn restore_token_system(
+    mut token: ResMut<CurrentAuthToken>,
+    mut session: ResMut<AuthSession>,
+    config: Res<ServerConfig>,
+) {
+    restore_token(
+        config.current().api_base_url.as_str(),
+        &mut *token,
+        &mut *session,
+    );
+}
+
+/// The session belongs to the previous server, switch to the one stored for the new server
+fn server_changed_system(
+    mut cmd: Commands,
+    mut events: EventReader<ServerProfileChanged>,
+    mut token: ResMut<CurrentAuthToken>,
+    mut session: ResMut<AuthSession>,
+    config: Res<ServerConfig>,
+    logins: Query<Entity, With<LoginRequestTask>>,
+    refreshes: Query<Entity, With<RefreshTokenTask>>,
+) {
+    if events.iter().last().is_none() {
+        return;
+    }
+    for e in logins.iter().chain(refreshes.iter()) {
+        cmd.entity(e).despawn_recursive();
+    }
+    token.0 = None;
+    *session = AuthSession::default();
+    restore_token(
+        config.current().api_base_url.as_str(),
+        &mut *token,
+        &mut *session,
+    );
+}
+
+fn refresh_token_system(
+    mut cmd: Commands,
+    mut retry_at: Local<u64>,
+    task_pool: Res<IoTaskPool>,
+    mut token: ResMut<CurrentAuthToken>,
+    mut session: ResMut<AuthSession>,
+    mut error: ResMut<LastLoginError>,
+    mut api_errors: EventWriter<ApiErrorEvent>,
+    mut tasks: Query<(Entity, &mut RefreshTokenTask)>,
+) {
+    let mut in_flight = false;
+    for (e, mut t) in tasks.iter_mut() {
+        in_flight = true;
+        if let Some(res) = future::block_on(future::poll_once(&mut t.0)) {
+            cmd.entity(e).despawn_recursive();
+            // the user might have logged out while the refresh was in flight
+            if token.0.is_none() {
+                continue;
+            }
+            match res {
+                Ok(t) => {
+                    debug!("Token refreshed");
+                    // tokens living shorter than the margin would be refreshed every frame
+                    *retry_at = token_store::now_secs() + REFRESH_RETRY_SECS;
+                    let session = &mut *session;
+                    let api_url = session.api_url.clone();
+                    set_token(t, session.remember, api_url.as_str(), &mut *token, session);
+                }
+                // the token might still be valid, try again later
+                Err(err)
+                    if err.is_transient()
+                        && session
+                            .expires_at
+                            .map(|t| t > token_store::now_secs())
+                            .unwrap_or(false) =>
+                {
+                    *retry_at = token_store::now_secs() + REFRESH_RETRY_SECS;
+                    api_errors.send(ApiErrorEvent::new("Refreshing the session", err));
+                }
+                Err(err) => {
+                    warn!("Failed to refresh the token: {}", err);
+                    token.0 = None;
+                    *session = AuthSession::default();
+                    error.0 = Some(match err {
+                        CaoApiError::Unauthorized(_) => {
+                            "Session expired, please log in again".to_string()
+                        }
+                        err => err.to_string(),
+                    });
+                }
+            }
+        }
+    }
+    if in_flight || token_store::now_secs() < *retry_at {
+        return;
+    }
+    let current = match (token.0.as_ref(), session.expires_at) {
+        (Some(t), Some(expires_at))
+            if expires_at <= token_store::now_secs() + REFRESH_MARGIN_SECS =>
+        {
+            t.clone()
+        }
+        _ => return,
+    };
+    debug!("Refreshing the token");
+    let handle = task_pool.spawn(refresh(session.api_url.clone(), current));
+    cmd.spawn().insert(RefreshTokenTask(handle));
+}
+
+fn logout_system(
+    mut events: EventReader<LogoutEvent>,
+    mut token: ResMut<CurrentAuthToken>,
+    mut session: ResMut<AuthSession>,
+    client: Res<CaoClient>,
+) {
+    if events.iter().last().is_none() {
+        return;
+    }
+    info!("Logging out");
+    token.0 = None;
+    token_store::clear(session.api_url.as_str())
+        .unwrap_or_else(|err| error!("Failed to clear token {:?}", err));
+    *session = AuthSession::default();
+    client.send_unsubscribe_all();
+}
+
+fn setup_register_task_system(
+    mut cmd: Commands,
+    task_pool: Res<IoTaskPool>,
+    mut events: EventReader<StartRegisterEvent>,
+    mut error: ResMut<LastLoginError>,
+    config: Res<ServerConfig>,
+) {
+    for event in events.iter() {
+        if let Err(err) = validate_registration(event) {
+            error.0 = Some(err);
+            continue;
+        }
+        let form = account_model::RegisterForm {
+            username: event.username.clone(),
+            email: event.email.clone(),
+            pw: event.password.clone(),
+            pw_verify: event.password_confirm.clone(),
+        };
+        let task = task_pool.spawn(register(config.current().api_base_url.clone(), form));
+        cmd.spawn().insert(RegisterTask {
+            task,
+            login: StartLoginEvent {
+                username: event.username.clone(),
+                password: event.password.clone(),
+                remember: event.remember,
+            },
+        });
+        error.0 = None;
+    }
+}
+
+fn handle_register_tasks_system(
+    mut cmd: Commands,
+    mut error: ResMut<LastLoginError>,
+    mut login_event: EventWriter<StartLoginEvent>,
+    mut tasks: Query<(Entity, &mut RegisterTask)>,
+) {
+    for (e, mut t) in tasks.iter_mut() {
+        if let Some(res) = future::block_on(future::poll_once(&mut t.task)) {
+            match res {
+                Ok(()) => {
+                    info!("Registered {}", t.login.username);
+                    login_event.send(t.login.clone());
+                }
+                Err(err) => error.0 = Some(err),
+            }
+            cmd.entity(e).despawn_recursive();
+        }
+    }
+}
+
+/// Fetch the profile of the user whenever the token changes
+fn current_user_system(
+    mut cmd: Commands,
+    task_pool: Res<IoTaskPool>,
+    token: Res<CurrentAuthToken>,
+    mut user: ResMut<CurrentUser>,
+    config: Res<ServerConfig>,
+    mut api_errors: EventWriter<ApiErrorEvent>,
+    mut tasks: Query<(Entity, &mut CurrentUserTask)>,
+) {
+    if token.is_changed() {
+        for (e, _) in tasks.iter_mut() {
+            cmd.entity(e).despawn_recursive();
+        }
+        // refreshing the token does not change the user
+        let refreshed = user.0.is_some() && token.0.is_some();
+        if !refreshed {
+            user.0 = None;
+            if let Some(token) = token.0.as_ref() {
+                let task = task_pool.spawn(fetch_current_user(
+                    config.current().api_base_url.clone(),
+                    token.clone(),
+                ));
+                cmd.spawn().insert(CurrentUserTask(task));
+            }
+        }
+        return;
+    }
+    for (e, mut t) in tasks.iter_mut() {
+        if let Some(res) = future::block_on(future::poll_once(&mut t.0)) {
+            match res {
+                Ok(info) => user.0 = Some(info),
+                Err(err) => api_errors.send(ApiErrorEvent::new("Fetching the profile", err)),
+            }
+            cmd.entity(e).despawn_recursive();
+        }
+    }
+}
+
 fn setup_login_task_system(
     mut cmd: Commands,
     task_pool: Res<IoTaskPool>,
     mut events: EventReader<StartLoginEvent>,
     mut error: ResMut<LastLoginError>,
+    config: Res<ServerConfig>,
 ) {
-    for StartLoginEvent { username, password } in events.iter() {
-        let handle = task_pool.spawn(login(username.clone(), password.clone()));
-        cmd.spawn().insert(handle);
+    for StartLoginEvent {
+        username,
+        password,
+        remember,
+    } in events.iter()
+    {
+        let handle = task_pool.spawn(login(
+            config.current().api_base_url.clone(),
+            username.clone(),
+            password.clone(),
+        ));
+        let mut task = cmd.spawn();
+        task.insert(handle);
+        if *remember {
+            task.insert(RememberToken);
+        }
 
         error.0 = None;
     }
@@ -102,8 +456,19 @@ impl Plugin for AccountPlugin {
     fn build(&self, app: &mut AppBuilder) {
         app.insert_resource(CurrentAuthToken(None))
             .insert_resource(LastLoginError(None))
+            .insert_resource(AuthSession::default())
+            .insert_resource(CurrentUser(None))
             .add_event::<StartLoginEvent>()
+            .add_event::<StartRegisterEvent>()
+            .add_event::<LogoutEvent>()
+            .add_startup_system(restore_token_system.system())
             .add_system(setup_login_task_system.system())
-            .add_system(handle_tasks_system.system());
+            .add_system(handle_tasks_system.system())
+            .add_system(refresh_token_system.system())
+            .add_system(logout_system.system())
+            .add_system(server_changed_system.system())
+            .add_system(setup_register_task_system.system())
+            .add_system(handle_register_tasks_system.system())
+            .add_system(current_user_system.system());
     }
 }
@@ -1,23 +1,28 @@
-#[derive(Default, Debug, Clone, serde::Serialize, serde::Deserialize)]
-pub struct LoginError {
-    pub detail: String,
-}
-
 /// Returns the token
 #[derive(Default, Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct LoginSuccess {
     pub access_token: super::AuthToken,
 }
 
-#[derive(Default, Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
-pub struct LoginUnprocEntity {
-    pub detail: Vec<Detail>,
+#[derive(Default, Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RegisterForm {
+    pub username: String,
+    pub email: String,
+    pub pw: String,
+    pub pw_verify: String,
 }
 
-#[derive(Default, Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
-pub struct Detail {
-    pub loc: Vec<String>,
-    pub msg: String,
-    #[serde(rename = "type")]
-    pub type_field: String,
+#[derive(Default, Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct UserInfo {
+    /// hyphenated uuid
+    pub user_id: String,
+    pub username: String,
+    #[serde(default)]
+    pub email: Option<String>,
+    /// bots owned by the user, counted by the server
+    #[serde(default)]
+    pub bot_count: Option<u64>,
+    /// structures owned by the user, counted by the server
+    #[serde(default)]
+    pub structure_count: Option<u64>,
 }
@@ -0,0 +1,144 @@
+//! On-disk storage of the auth token for the "remember me" login option
+//!
+//! The token file is `--token-file <path>`, `CAO_TOKEN_FILE` or `caolo/token.json` in the user's
+//! config directory. On unix the file is only readable by its owner.
+//! A token is only valid for the server that issued it, so the tokens are stored by api url.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+
+use super::AuthToken;
+use crate::server_config::arg_or_env;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredToken {
+    pub token: AuthToken,
+    /// unix timestamp, seconds
+    pub expires_at: Option<u64>,
+}
+
+/// Stored tokens by api url
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+struct TokenFile(HashMap<String, StoredToken>);
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Reads the `exp` claim of a JWT
+///
+/// The signature is not verified, the server is the judge of the token's validity
+pub fn decode_expiry(token: &str) -> Option<u64> {
+    #[derive(serde::Deserialize)]
+    struct Claims {
+        exp: Option<u64>,
+    }
+
+    let payload = token.split('.').nth(1)?;
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+    serde_json::from_slice::<Claims>(&payload).ok()?.exp
+}
+
+impl StoredToken {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.map(|t| t <= now_secs()).unwrap_or(false)
+    }
+}
+
+fn config_dir() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os("XDG_CONFIG_HOME") {
+        return Some(PathBuf::from(dir));
+    }
+    if cfg!(windows) {
+        return std::env::var_os("APPDATA").map(PathBuf::from);
+    }
+    std::env::var_os("HOME").map(|home| Path::new(&home).join(".config"))
+}
+
+pub fn token_path() -> Option<PathBuf> {
+    arg_or_env("token-file", "CAO_TOKEN_FILE")
+        .map(PathBuf::from)
+        .or_else(|| config_dir().map(|dir| dir.join("caolo").join("token.json")))
+}
+
+fn read_token_file(path: &Path) -> anyhow::Result<TokenFile> {
+    if !path.exists() {
+        return Ok(TokenFile::default());
+    }
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read token file {:?}", path))?;
+    serde_json::from_str(content.as_str())
+        .with_context(|| format!("Failed to parse token file {:?}", path))
+}
+
+fn write_token_file(path: &Path, tokens: &TokenFile) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create directory {:?}", dir))?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("Failed to open token file {:?}", path))?;
+    // the mode only applies to new files
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("Failed to set permissions of {:?}", path))?;
+    }
+    let content = serde_json::to_vec(tokens).with_context(|| "Failed to serialize token")?;
+    file.write_all(&content)
+        .with_context(|| format!("Failed to write token file {:?}", path))
+}
+
+/// The token stored for the server at `api_url`
+pub fn load(api_url: &str) -> anyhow::Result<Option<StoredToken>> {
+    let path = match token_path() {
+        Some(p) => p,
+        None => return Ok(None),
+    };
+    let mut tokens = read_token_file(&path)?;
+    Ok(tokens.0.remove(api_url))
+}
+
+pub fn save(api_url: &str, token: StoredToken) -> anyhow::Result<()> {
+    let path = token_path().with_context(|| "No location to store the token in")?;
+    // a broken token file is overwritten
+    let mut tokens = read_token_file(&path).unwrap_or_default();
+    tokens.0.insert(api_url.to_string(), token);
+    write_token_file(&path, &tokens)
+}
+
+/// Forget the token of the server at `api_url`
+pub fn clear(api_url: &str) -> anyhow::Result<()> {
+    let path = match token_path() {
+        Some(path) if path.exists() => path,
+        _ => return Ok(()),
+    };
+    let mut tokens = read_token_file(&path).unwrap_or_default();
+    if tokens.0.remove(api_url).is_none() {
+        return Ok(());
+    }
+    if tokens.0.is_empty() {
+        return fs::remove_file(&path)
+            .with_context(|| format!("Failed to remove token file {:?}", path));
+    }
+    write_token_file(&path, &tokens)
+}
@@ -0,0 +1,262 @@
+//! Shared plumbing of the REST API calls
+//!
+//! Every request times out after [REQUEST_TIMEOUT]. Requests sent with [Retry::Transient] are
+//! retried with exponential backoff on network errors, timeouts and 5xx responses, only use it
+//! for requests that are safe to repeat.
+//!
+//! Systems polling the request tasks report failures via [ApiErrorEvent], which are shown to the
+//! player as toasts.
+
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContext};
+use thiserror::Error;
+
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+pub const MAX_RETRIES: u32 = 3;
+const BACKOFF_BASE: Duration = Duration::from_millis(250);
+const TOAST_SECS: f32 = 6.0;
+const MAX_TOASTS: usize = 5;
+
+pub type ApiResult<T> = Result<T, CaoApiError>;
+
+#[derive(Debug, Clone, Error)]
+pub enum CaoApiError {
+    #[error("Failed to reach the server: {0}")]
+    Network(String),
+    #[error("The server did not respond in time")]
+    Timeout,
+    #[error("{0}")]
+    Unauthorized(String),
+    /// The server rejected the payload
+    #[error("{0}")]
+    Validation(String),
+    #[error("{detail} ({status})")]
+    Status { status: u16, detail: String },
+    #[error("Failed to read the response: {0}")]
+    Decode(String),
+    #[error("Failed to build the request: {0}")]
+    Request(String),
+}
+
+impl CaoApiError {
+    /// Whether sending the same request again might succeed
+    pub fn is_transient(&self) -> bool {
+        match self {
+            CaoApiError::Network(_) | CaoApiError::Timeout => true,
+            CaoApiError::Status { status, .. } => *status >= 500,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retry {
+    Never,
+    Transient,
+}
+
+/// Sent by the systems polling failed requests
+#[derive(Debug, Clone)]
+pub struct ApiErrorEvent {
+    /// What was attempted, e.g. "Fetching the schema"
+    pub context: String,
+    pub error: CaoApiError,
+}
+
+impl ApiErrorEvent {
+    pub fn new(context: impl Into<String>, error: CaoApiError) -> Self {
+        Self {
+            context: context.into(),
+            error,
+        }
+    }
+}
+
+pub struct Toast {
+    pub title: String,
+    pub text: String,
+    pub timer: Timer,
+}
+
+pub struct Toasts(pub Vec<Toast>);
+
+pub struct ApiClientPlugin;
+
+/// Send the request built by `build`
+///
+/// `build` is called once per attempt, as requests can not be cloned.
+/// Responses with non 2xx status codes are turned into errors.
+pub async fn send<F>(retry: Retry, build: F) -> ApiResult<surf::Response>
+where
+    F: Fn() -> ApiResult<surf::RequestBuilder>,
+{
+    let mut attempt = 0;
+    loop {
+        match send_once(build()?).await {
+            Err(err)
+                if retry == Retry::Transient && attempt < MAX_RETRIES && err.is_transient() =>
+            {
+                let delay = BACKOFF_BASE * 2u32.pow(attempt);
+                debug!("Request failed, retrying in {:?}: {}", delay, err);
+                async_io::Timer::after(delay).await;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// [send] then deserialize the json body of the response
+pub async fn send_json<T, F>(retry: Retry, build: F) -> ApiResult<T>
+where
+    T: serde::de::DeserializeOwned,
+    F: Fn() -> ApiResult<surf::RequestBuilder>,
+{
+    let resp = send(retry, build).await?;
+    read_json(resp).await
+}
+
+pub async fn read_json<T: serde::de::DeserializeOwned>(mut resp: surf::Response) -> ApiResult<T> {
+    resp.body_json()
+        .await
+        .map_err(|err| CaoApiError::Decode(err.to_string()))
+}
+
+pub fn with_json<T: serde::Serialize>(
+    req: surf::RequestBuilder,
+    body: &T,
+) -> ApiResult<surf::RequestBuilder> {
+    req.body_json(body)
+        .map_err(|err| CaoApiError::Request(err.to_string()))
+}
+
+async fn send_once(req: surf::RequestBuilder) -> ApiResult<surf::Response> {
+    let request = async {
+        req.await
+            .map_err(|err| CaoApiError::Network(err.to_string()))
+    };
+    let timeout = async {
+        async_io::Timer::after(REQUEST_TIMEOUT).await;
+        Err(CaoApiError::Timeout)
+    };
+    let mut resp = futures_lite::future::or(request, timeout).await?;
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+    let body = resp.body_string().await.unwrap_or_default();
+    let detail = error_detail(body.as_str());
+    debug!("Request failed with status {}: {}", status, detail);
+    let err = match status {
+        surf::StatusCode::Unauthorized | surf::StatusCode::Forbidden => {
+            CaoApiError::Unauthorized(detail)
+        }
+        surf::StatusCode::UnprocessableEntity => CaoApiError::Validation(detail),
+        _ => CaoApiError::Status {
+            status: status as u16,
+            detail,
+        },
+    };
+    Err(err)
+}
+
+/// The backend reports errors as `{"detail": "<message>"}`, validation errors as
+/// `{"detail": [{"loc": [..], "msg": "<message>"}]}`
+fn error_detail(body: &str) -> String {
+    #[derive(serde::Deserialize)]
+    struct FieldError {
+        loc: Vec<serde_json::Value>,
+        msg: String,
+    }
+
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum Detail {
+        Message(String),
+        Fields(Vec<FieldError>),
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Body {
+        detail: Detail,
+    }
+
+    match serde_json::from_str::<Body>(body) {
+        Ok(Body {
+            detail: Detail::Message(msg),
+        }) => msg,
+        Ok(Body {
+            detail: Detail::Fields(fields),
+        }) if !fields.is_empty() => fields
+            .iter()
+            .map(|field| {
+                let loc = match field.loc.last() {
+                    Some(serde_json::Value::String(loc)) => loc.clone(),
+                    Some(loc) => loc.to_string(),
+                    None => String::new(),
+                };
+                format!("{}: {}", loc, field.msg)
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ if body.trim().is_empty() => "No details".to_string(),
+        _ => body.trim().to_string(),
+    }
+}
+
+fn collect_errors_system(mut events: EventReader<ApiErrorEvent>, mut toasts: ResMut<Toasts>) {
+    for event in events.iter() {
+        warn!("{}: {}", event.context, event.error);
+        toasts.0.push(Toast {
+            title: event.context.clone(),
+            text: event.error.to_string(),
+            timer: Timer::from_seconds(TOAST_SECS, false),
+        });
+    }
+    let excess = toasts.0.len().saturating_sub(MAX_TOASTS);
+    toasts.0.drain(..excess);
+}
+
+fn toasts_ui_system(
+    time: Res<Time>,
+    egui_ctx: ResMut<EguiContext>, // exclusive ownership
+    mut toasts: ResMut<Toasts>,
+) {
+    for toast in toasts.0.iter_mut() {
+        toast.timer.tick(time.delta());
+    }
+    toasts.0.retain(|toast| !toast.timer.finished());
+    if toasts.0.is_empty() {
+        return;
+    }
+    let mut dismissed = None;
+    egui::Area::new("api-error-toasts")
+        .anchor(egui::Align2::RIGHT_BOTTOM, [-10.0, -10.0])
+        .show(egui_ctx.ctx(), |ui| {
+            for (i, toast) in toasts.0.iter().enumerate() {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.colored_label(egui::color::Rgba::RED, toast.title.as_str());
+                        if ui.small_button("✖").clicked() {
+                            dismissed = Some(i);
+                        }
+                    });
+                    ui.label(toast.text.as_str());
+                });
+            }
+        });
+    if let Some(i) = dismissed {
+        toasts.0.remove(i);
+    }
+}
+
+impl Plugin for ApiClientPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(Toasts(Vec::new()))
+            .add_event::<ApiErrorEvent>()
+            .add_system(collect_errors_system.system())
+            .add_system(toasts_ui_system.system());
+    }
+}
@@ -0,0 +1,692 @@
+//! Local stand-in for the Caolo backend, for developing the client without running the simulation
+//!
+//! Serves the REST API on `--api-port` (default 8000) and the object-stream on `--ws-port`
+//! (default 8080), matching the `local` server profile of the client.
+//! Entity deltas are sent to clients requesting them, unless `--no-deltas` is given.
+//! Expired or malformed tokens are rejected by the object-stream, anonymous sessions are only
+//! rejected with `--require-auth`.
+//!
+//! Built only with the `mock-server` feature: `cargo run --features mock-server --bin mock_server`
+
+#[allow(dead_code)]
+#[path = "../../cao_lang_client/cao_lang_model.rs"]
+mod cao_lang_model;
+#[allow(dead_code)]
+#[path = "../../cao_sim_client/cao_sim_model.rs"]
+mod cao_sim_model;
+mod programs;
+#[allow(dead_code)]
+#[path = "../../cao_sim_client/wire_format.rs"]
+mod wire_format;
+mod world;
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use cao_sim_model::{
+    AxialPos, EntitiesDeltaPayload, EntitiesPayload, EntityDelta, GetLayoutQuery, Message,
+};
+use futures::prelude::*;
+use warp::{http::StatusCode, ws::WebSocket, Filter, Reply};
+use wire_format::WireFormat;
+
+const TICK_INTERVAL: Duration = Duration::from_millis(1000);
+/// `--token-lifetime <seconds>`, short lifetimes help testing the token refresh
+static TOKEN_LIFETIME_SECS: AtomicU64 = AtomicU64::new(60 * 60);
+
+type SharedWorld = Arc<Mutex<world::World>>;
+
+/// A handler panicking while holding the lock poisons it, the state is still served afterwards
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// required by [cao_sim_model]
+pub fn hex_axial_to_pixel(q: f32, r: f32) -> bevy::math::Vec2 {
+    const SQRT3: f32 = 1.732_050_8;
+    const SQRT3_2: f32 = 0.866_025_4;
+    const THREE_OVER_TWO: f32 = 1.5;
+
+    bevy::math::Vec2::new(q * SQRT3 + r * SQRT3_2, r * THREE_OVER_TWO)
+}
+
+/// Room subscription messages sent by `CaoClient`
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "ty", rename_all = "snake_case")]
+enum ClientMessage {
+    RoomId { room_id: AxialPos },
+    RoomIds { room_ids: Vec<AxialPos> },
+    UnsubscribeRoomId { room_id: AxialPos },
+    UnsubscribeRoomIds { room_ids: Vec<AxialPos> },
+    ClearRoomIds,
+}
+
+fn cli_arg(name: &str) -> Option<String> {
+    let flag = format!("--{}", name);
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next();
+        }
+    }
+    None
+}
+
+fn has_flag(name: &str) -> bool {
+    let flag = format!("--{}", name);
+    std::env::args().skip(1).any(|arg| arg == flag)
+}
+
+fn port_arg(name: &str, default: u16) -> u16 {
+    cli_arg(name)
+        .map(|p| p.parse().expect("Invalid port"))
+        .unwrap_or(default)
+}
+
+fn error_detail(status: StatusCode, detail: impl Into<String>) -> warp::reply::Response {
+    let body = warp::reply::json(&serde_json::json!({ "detail": detail.into() }));
+    warp::reply::with_status(body, status).into_response()
+}
+
+/// Unsigned JWT, so the client can read the expiry
+fn mock_token(username: &str) -> String {
+    let exp = now_secs() + TOKEN_LIFETIME_SECS.load(Ordering::Relaxed);
+    let encode =
+        |v: serde_json::Value| base64::encode_config(v.to_string(), base64::URL_SAFE_NO_PAD);
+    format!(
+        "{}.{}.mock",
+        encode(serde_json::json!({"alg": "none", "typ": "JWT"})),
+        encode(serde_json::json!({"sub": username, "exp": exp}))
+    )
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Checks the `Authorization` header value issued by [login]
+fn check_token(header: &str) -> Result<String, String> {
+    let token = header
+        .strip_prefix("Bearer ")
+        .ok_or_else(|| "Not a bearer token".to_string())?;
+    let payload = token
+        .split('.')
+        .nth(1)
+        .ok_or_else(|| "Malformed token".to_string())?;
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+        .map_err(|err| format!("Malformed token: {}", err))?;
+    let claims: serde_json::Value =
+        serde_json::from_slice(&payload).map_err(|err| format!("Malformed token: {}", err))?;
+    let exp = claims["exp"].as_u64().unwrap_or(0);
+    if exp <= now_secs() {
+        return Err("Token expired".to_string());
+    }
+    Ok(claims["sub"].as_str().unwrap_or_default().to_string())
+}
+
+fn login(form: HashMap<String, String>) -> warp::reply::Response {
+    match form.get("username").filter(|u| !u.is_empty()) {
+        Some(username) => {
+            tracing::info!("Login {}", username);
+            warp::reply::json(&serde_json::json!({
+                "access_token": mock_token(username),
+                "token_type": "bearer",
+            }))
+            .into_response()
+        }
+        None => {
+            let body = warp::reply::json(&serde_json::json!({
+                "detail": [{
+                    "loc": ["body", "username"],
+                    "msg": "field required",
+                    "type": "value_error.missing",
+                }]
+            }));
+            warp::reply::with_status(body, StatusCode::UNPROCESSABLE_ENTITY).into_response()
+        }
+    }
+}
+
+fn refresh_token(auth: Option<String>) -> warp::reply::Response {
+    match auth.as_deref().map(check_token) {
+        Some(Ok(user)) => warp::reply::json(&serde_json::json!({
+            "access_token": mock_token(user.as_str()),
+            "token_type": "bearer",
+        }))
+        .into_response(),
+        Some(Err(err)) => error_detail(StatusCode::UNAUTHORIZED, err),
+        None => error_detail(StatusCode::UNAUTHORIZED, "Not authenticated"),
+    }
+}
+
+fn register(form: HashMap<String, String>) -> warp::reply::Response {
+    let field = |name: &str| form.get(name).map(|x| x.as_str()).unwrap_or_default();
+    let invalid = |name: &str, msg: &str| {
+        let body = warp::reply::json(&serde_json::json!({
+            "detail": [{
+                "loc": ["body", name],
+                "msg": msg,
+                "type": "value_error",
+            }]
+        }));
+        warp::reply::with_status(body, StatusCode::UNPROCESSABLE_ENTITY).into_response()
+    };
+    if field("username").is_empty() {
+        return invalid("username", "field required");
+    }
+    if !field("email").contains('@') {
+        return invalid("email", "value is not a valid email address");
+    }
+    if field("pw") != field("pw_verify") {
+        return invalid("pw_verify", "passwords do not match");
+    }
+    tracing::info!("Register {}", field("username"));
+    warp::reply::with_status(warp::reply(), StatusCode::CREATED).into_response()
+}
+
+/// Call `f` with the user the `Authorization` header belongs to
+fn authenticated(
+    auth: Option<String>,
+    f: impl FnOnce(String) -> warp::reply::Response,
+) -> warp::reply::Response {
+    match auth.as_deref().map(check_token) {
+        Some(Ok(user)) => f(user),
+        Some(Err(err)) => error_detail(StatusCode::UNAUTHORIZED, err),
+        None => error_detail(StatusCode::UNAUTHORIZED, "Not authenticated"),
+    }
+}
+
+/// Every user of the mock server owns the bots of [world::MOCK_USER_ID]
+fn current_user(world: &SharedWorld, auth: Option<String>) -> warp::reply::Response {
+    authenticated(auth, |user| {
+        let (bots, structures) = lock(world).owned_counts(&world::MOCK_USER_ID);
+        warp::reply::json(&serde_json::json!({
+            "user_id": uuid::Uuid::from_bytes(world::MOCK_USER_ID).to_string(),
+            "username": user,
+            "email": format!("{}@example.com", user),
+            "bot_count": bots,
+            "structure_count": structures,
+        }))
+        .into_response()
+    })
+}
+
+#[derive(serde::Deserialize)]
+struct AssignScriptPayload {
+    entity_id: u64,
+    program_id: String,
+}
+
+fn assign_script(
+    world: &SharedWorld,
+    store: &programs::SharedPrograms,
+    user: &str,
+    payload: AssignScriptPayload,
+) -> warp::reply::Response {
+    if !lock(store).exists(user, payload.program_id.as_str()) {
+        return error_detail(StatusCode::NOT_FOUND, "Program not found");
+    }
+    let script = match uuid::Uuid::parse_str(payload.program_id.as_str()) {
+        Ok(id) => *id.as_bytes(),
+        Err(err) => return error_detail(StatusCode::BAD_REQUEST, err.to_string()),
+    };
+    if !lock(world).assign_script(payload.entity_id, &script) {
+        return error_detail(StatusCode::NOT_FOUND, "Entity not found");
+    }
+    tracing::info!(
+        "Assigned {} to entity {}",
+        payload.program_id,
+        payload.entity_id
+    );
+    warp::reply::json(&serde_json::json!({})).into_response()
+}
+
+fn compile(body: warp::hyper::body::Bytes) -> warp::reply::Response {
+    let ir: cao_lang::compiler::CaoIr = match serde_json::from_slice(&body) {
+        Ok(ir) => ir,
+        Err(err) => {
+            return error_detail(StatusCode::UNPROCESSABLE_ENTITY, err.to_string());
+        }
+    };
+    match cao_lang::compiler::compile(&ir, None) {
+        Ok(_) => warp::reply::json(&serde_json::json!({})).into_response(),
+        Err(err) => {
+            // the client maps `lane <lane> card <card>` back to the editor
+            let detail = match err.loc {
+                Some((cao_lang::compiler::LaneNode::LaneId(lane), card)) => {
+                    format!("Lane {} card {}: {}", lane, card, err.payload)
+                }
+                Some((cao_lang::compiler::LaneNode::LaneName(lane), card)) => {
+                    format!("Lane {:?} card {}: {}", lane, card, err.payload)
+                }
+                None => err.payload.to_string(),
+            };
+            error_detail(StatusCode::BAD_REQUEST, detail)
+        }
+    }
+}
+
+/// Changes whenever [schema] does
+const SCHEMA_ETAG: &str = "\"mock-schema-1\"";
+
+fn get_schema(if_none_match: Option<String>) -> warp::reply::Response {
+    if if_none_match.as_deref() == Some(SCHEMA_ETAG) {
+        return warp::reply::with_status(warp::reply(), StatusCode::NOT_MODIFIED).into_response();
+    }
+    warp::reply::with_header(warp::reply::json(&schema()), "ETag", SCHEMA_ETAG).into_response()
+}
+
+/// Natives the mock simulation pretends to provide
+fn schema() -> Vec<cao_lang_model::SchemaNode> {
+    let call = |name: &str, description: &str, input: &[&str], output: &[&str]| {
+        cao_lang_model::SchemaNode {
+            name: name.to_string(),
+            description: description.to_string(),
+            ty: "Call".to_string(),
+            input: input.iter().map(|x| x.to_string()).collect(),
+            output: output.iter().map(|x| x.to_string()).collect(),
+            properties: vec![],
+        }
+    };
+    vec![
+        call("console_log", "Log a message", &["Value"], &[]),
+        call(
+            "mine_resource",
+            "Mine the given resource",
+            &["EntityId"],
+            &["OperationResult"],
+        ),
+        call(
+            "approach_entity",
+            "Move towards the given entity",
+            &["EntityId"],
+            &["OperationResult"],
+        ),
+        call(
+            "move_bot_to_position",
+            "Move towards the given position",
+            &["WorldPosition"],
+            &["OperationResult"],
+        ),
+        call(
+            "find_closest_resource",
+            "Find the closest resource",
+            &[],
+            &["EntityId"],
+        ),
+        call(
+            "unload",
+            "Unload the carried resources to the given structure",
+            &["Integer", "ResourceType", "EntityId"],
+            &["OperationResult"],
+        ),
+    ]
+}
+
+fn send_message(format: WireFormat, msg: &Message) -> warp::ws::Message {
+    let payload = format.encode(msg).expect("Failed to serialize message");
+    match format {
+        WireFormat::Json => {
+            warp::ws::Message::text(String::from_utf8(payload).expect("Json is not utf8"))
+        }
+        WireFormat::MessagePack => warp::ws::Message::binary(payload),
+    }
+}
+
+fn diff<T: Clone + serde::Serialize>(
+    old: &[T],
+    new: &[T],
+    id: impl Fn(&T) -> u64,
+) -> EntityDelta<T> {
+    let mut delta = EntityDelta::default();
+    for e in new {
+        match old.iter().find(|x| id(x) == id(e)) {
+            Some(x) => {
+                if serde_json::to_value(x).ok() != serde_json::to_value(e).ok() {
+                    delta.updated.push(e.clone());
+                }
+            }
+            None => delta.spawned.push(e.clone()),
+        }
+    }
+    delta.removed = old
+        .iter()
+        .map(&id)
+        .filter(|x| !new.iter().any(|e| id(e) == *x))
+        .collect();
+    delta
+}
+
+fn entities_delta(old: &EntitiesPayload, new: &EntitiesPayload) -> EntitiesDeltaPayload {
+    EntitiesDeltaPayload {
+        time: new.time,
+        room_id: new.room_id,
+        bots: diff(&old.bots, &new.bots, |b| b.id),
+        structures: diff(&old.structures, &new.structures, |s| s.id),
+        resources: diff(&old.resources, &new.resources, |r| r.id),
+    }
+}
+
+async fn object_stream(
+    socket: WebSocket,
+    format: WireFormat,
+    deltas: bool,
+    world: SharedWorld,
+    mut ticks: tokio::sync::watch::Receiver<i64>,
+) {
+    tracing::info!("Client connected, using {}, deltas: {}", format, deltas);
+    let (mut tx, mut rx) = socket.split();
+    // last entities payload sent per subscribed room
+    let mut rooms: HashMap<AxialPos, Option<EntitiesPayload>> = HashMap::new();
+    loop {
+        let mut outgoing = Vec::new();
+        futures::select! {
+            msg = rx.next().fuse() => {
+                let msg = match msg {
+                    Some(Ok(msg)) => msg,
+                    Some(Err(err)) => {
+                        tracing::debug!("Connection error {:?}", err);
+                        break;
+                    }
+                    None => break,
+                };
+                if msg.is_close() {
+                    break;
+                }
+                if !(msg.is_binary() || msg.is_text()) {
+                    continue;
+                }
+                let msg: ClientMessage = match serde_json::from_slice(msg.as_bytes()) {
+                    Ok(m) => m,
+                    Err(err) => {
+                        tracing::warn!("Failed to parse client message {:?}", err);
+                        continue;
+                    }
+                };
+                tracing::debug!("Client message {:?}", msg);
+                let mut subscribed = Vec::new();
+                match msg {
+                    ClientMessage::RoomId { room_id } => subscribed.push(room_id),
+                    ClientMessage::RoomIds { room_ids } => subscribed.extend(room_ids),
+                    ClientMessage::UnsubscribeRoomId { room_id } => {
+                        rooms.remove(&room_id);
+                    }
+                    ClientMessage::UnsubscribeRoomIds { room_ids } => {
+                        for room_id in room_ids {
+                            rooms.remove(&room_id);
+                        }
+                    }
+                    ClientMessage::ClearRoomIds => rooms.clear(),
+                }
+                let mut world = lock(&world);
+                for room_id in subscribed {
+                    // the first update after (re)subscribing is always a snapshot
+                    rooms.insert(room_id, None);
+                    let terrain = world.room(room_id).terrain_payload();
+                    outgoing.push(send_message(format, &Message::Terrain(Some(terrain))));
+                }
+            }
+            res = ticks.changed().fuse() => {
+                if res.is_err() {
+                    break;
+                }
+                let world = lock(&world);
+                for (room_id, last) in rooms.iter_mut() {
+                    let pl = match world.entities(*room_id) {
+                        Some(pl) => pl,
+                        None => continue,
+                    };
+                    let msg = match last.as_ref().filter(|_| deltas) {
+                        Some(last) => Message::EntitiesDelta(entities_delta(last, &pl)),
+                        None => Message::Entities(pl.clone()),
+                    };
+                    outgoing.push(send_message(format, &msg));
+                    *last = Some(pl);
+                }
+            }
+        }
+        for msg in outgoing {
+            if let Err(err) = tx.send(msg).await {
+                tracing::debug!("Send failed {:?}", err);
+                return;
+            }
+        }
+    }
+    tracing::info!("Client disconnected");
+}
+
+async fn run_simulation(world: SharedWorld, ticks: tokio::sync::watch::Sender<i64>) {
+    let mut interval = tokio::time::interval(TICK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let time = {
+            let mut world = lock(&world);
+            world.tick();
+            world.time
+        };
+        ticks.send(time).unwrap_or_default();
+    }
+}
+
+fn main() {
+    tracing_subscriber::fmt::init();
+
+    let api_port = port_arg("api-port", 8000);
+    let ws_port = port_arg("ws-port", 8080);
+    let no_deltas = has_flag("no-deltas");
+    let require_auth = has_flag("require-auth");
+    if let Some(lifetime) = cli_arg("token-lifetime") {
+        let lifetime = lifetime.parse().expect("Invalid token lifetime");
+        TOKEN_LIFETIME_SECS.store(lifetime, Ordering::Relaxed);
+    }
+
+    let world: SharedWorld = Default::default();
+    let (tick_tx, tick_rx) = tokio::sync::watch::channel(0);
+
+    let layout = warp::path!("v1" / "world" / "room-terrain-layout")
+        .and(warp::get())
+        .and(warp::query::<GetLayoutQuery>())
+        .map(|q: GetLayoutQuery| warp::reply::json(&world::layout(q.radius)));
+    let token = warp::path!("v1" / "token")
+        .and(warp::post())
+        .and(warp::body::form())
+        .map(login);
+    let refresh = warp::path!("v1" / "token" / "refresh")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .map(refresh_token);
+    let schema = warp::path!("v1" / "scripting" / "schema")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .map(get_schema);
+    let compile = warp::path!("v1" / "scripting" / "compile")
+        .and(warp::post())
+        .and(warp::body::bytes())
+        .map(compile);
+    let register = warp::path!("v1" / "users" / "register")
+        .and(warp::post())
+        .and(warp::body::json())
+        .map(register);
+    let me_world = world.clone();
+    let me = warp::path!("v1" / "users" / "me")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .map(move |auth: Option<String>| current_user(&me_world, auth));
+    let store: programs::SharedPrograms = Default::default();
+    let with_store = warp::any().map(move || store.clone());
+    let auth = || warp::header::optional::<String>("authorization");
+    let my_programs = warp::path!("v1" / "scripting" / "my-programs")
+        .and(warp::get())
+        .and(auth())
+        .and(with_store.clone())
+        .map(|auth: Option<String>, store: programs::SharedPrograms| {
+            authenticated(auth, |user| lock(&store).list(user.as_str()))
+        });
+    let create_program = warp::path!("v1" / "scripting" / "create-program")
+        .and(warp::post())
+        .and(auth())
+        .and(with_store.clone())
+        .and(warp::body::json())
+        .map(
+            |auth: Option<String>,
+             store: programs::SharedPrograms,
+             payload: programs::NamePayload| {
+                authenticated(auth, |user| lock(&store).create(user.as_str(), payload))
+            },
+        );
+    let fetch_program = warp::path!("v1" / "scripting" / "programs" / String)
+        .and(warp::get())
+        .and(auth())
+        .and(with_store.clone())
+        .map(
+            |id: String, auth: Option<String>, store: programs::SharedPrograms| {
+                authenticated(auth, |user| lock(&store).fetch(user.as_str(), id.as_str()))
+            },
+        );
+    let save_program = warp::path!("v1" / "scripting" / "programs" / String)
+        .and(warp::put())
+        .and(auth())
+        .and(with_store.clone())
+        .and(warp::body::json())
+        .map(
+            |id: String,
+             auth: Option<String>,
+             store: programs::SharedPrograms,
+             payload: programs::SavePayload| {
+                authenticated(auth, |user| {
+                    lock(&store).save(user.as_str(), id.as_str(), payload)
+                })
+            },
+        );
+    let rename_program = warp::path!("v1" / "scripting" / "programs" / String)
+        .and(warp::patch())
+        .and(auth())
+        .and(with_store.clone())
+        .and(warp::body::json())
+        .map(
+            |id: String,
+             auth: Option<String>,
+             store: programs::SharedPrograms,
+             payload: programs::NamePayload| {
+                authenticated(auth, |user| {
+                    lock(&store).rename(user.as_str(), id.as_str(), payload)
+                })
+            },
+        );
+    let delete_program = warp::path!("v1" / "scripting" / "programs" / String)
+        .and(warp::delete())
+        .and(auth())
+        .and(with_store.clone())
+        .map(
+            |id: String, auth: Option<String>, store: programs::SharedPrograms| {
+                authenticated(auth, |user| lock(&store).delete(user.as_str(), id.as_str()))
+            },
+        );
+    let assign_world = world.clone();
+    let assign_script = warp::path!("v1" / "scripting" / "assign-script")
+        .and(warp::post())
+        .and(auth())
+        .and(with_store.clone())
+        .and(warp::body::json())
+        .map(
+            move |auth: Option<String>,
+                  store: programs::SharedPrograms,
+                  payload: AssignScriptPayload| {
+                authenticated(auth, |user| {
+                    assign_script(&assign_world, &store, user.as_str(), payload)
+                })
+            },
+        );
+    let api = layout
+        .or(token)
+        .or(refresh)
+        .or(register)
+        .or(me)
+        .or(schema)
+        .or(compile)
+        .or(my_programs)
+        .or(create_program)
+        .or(fetch_program)
+        .or(save_program)
+        .or(rename_program)
+        .or(delete_program)
+        .or(assign_script)
+        .with(warp::log("mock_server::api"));
+
+    let stream_world = world.clone();
+    let object_stream = warp::path("object-stream")
+        .and(warp::ws())
+        .and(warp::header::optional::<String>(
+            wire_format::SUBPROTOCOL_HEADER,
+        ))
+        .and(warp::header::optional::<String>(
+            wire_format::ENTITY_DELTAS_HEADER,
+        ))
+        .and(warp::header::optional::<String>("authorization"))
+        .map(
+            move |ws: warp::ws::Ws,
+                  offer: Option<String>,
+                  deltas: Option<String>,
+                  auth: Option<String>| {
+                match auth.as_deref().map(check_token) {
+                    Some(Ok(user)) => tracing::info!("Object-stream session of {}", user),
+                    Some(Err(err)) => {
+                        tracing::info!("Rejecting object-stream session: {}", err);
+                        return error_detail(StatusCode::UNAUTHORIZED, err);
+                    }
+                    None if require_auth => {
+                        return error_detail(StatusCode::UNAUTHORIZED, "Not authenticated");
+                    }
+                    None => tracing::debug!("Anonymous object-stream session"),
+                }
+                let deltas = deltas.is_some() && !no_deltas;
+                let world = stream_world.clone();
+                let ticks = tick_rx.clone();
+                let format = offer
+                    .as_deref()
+                    .and_then(|offer| WireFormat::negotiate(offer, &WireFormat::ALL));
+                let reply = ws.on_upgrade(move |socket| {
+                    object_stream(socket, format.unwrap_or_default(), deltas, world, ticks)
+                });
+                match format {
+                    Some(format) => warp::reply::with_header(
+                        reply,
+                        wire_format::SUBPROTOCOL_HEADER,
+                        format.subprotocol(),
+                    )
+                    .into_response(),
+                    // legacy clients get json without the header
+                    None => reply.into_response(),
+                }
+            },
+        );
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to init tokio runtime");
+    runtime.block_on(async move {
+        let api_addr: SocketAddr = ([127, 0, 0, 1], api_port).into();
+        let ws_addr: SocketAddr = ([127, 0, 0, 1], ws_port).into();
+        tracing::info!(
+            "Serving the api on {} and the object-stream on {}",
+            api_addr,
+            ws_addr
+        );
+        futures::join!(
+            warp::serve(api).run(api_addr),
+            warp::serve(object_stream).run(ws_addr),
+            run_simulation(world, tick_tx),
+        );
+    });
+}
@@ -0,0 +1,173 @@
+//! In-memory store of the users' CaoLang programs, lost when the server exits
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use warp::{http::StatusCode, Reply};
+
+use crate::{error_detail, now_secs};
+
+pub type SharedPrograms = Arc<Mutex<Programs>>;
+
+struct StoredProgram {
+    owner: String,
+    name: String,
+    version: i64,
+    created: String,
+    updated: String,
+    program: serde_json::Value,
+}
+
+#[derive(Default)]
+pub struct Programs {
+    next_id: u64,
+    programs: HashMap<String, StoredProgram>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct NamePayload {
+    name: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct SavePayload {
+    program: serde_json::Value,
+    expected_version: Option<i64>,
+}
+
+/// RFC 3339 UTC timestamp of the current time
+fn timestamp() -> String {
+    let secs = now_secs();
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+    // days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+fn summary(id: &str, p: &StoredProgram) -> serde_json::Value {
+    serde_json::json!({
+        "program_id": id,
+        "name": p.name,
+        "version": p.version,
+        "created": p.created,
+        "updated": p.updated,
+    })
+}
+
+fn not_found() -> warp::reply::Response {
+    error_detail(StatusCode::NOT_FOUND, "Program not found")
+}
+
+impl Programs {
+    fn get_mut(&mut self, user: &str, id: &str) -> Option<&mut StoredProgram> {
+        self.programs.get_mut(id).filter(|p| p.owner == user)
+    }
+
+    pub fn exists(&self, user: &str, id: &str) -> bool {
+        self.programs
+            .get(id)
+            .map(|p| p.owner == user)
+            .unwrap_or(false)
+    }
+
+    pub fn list(&self, user: &str) -> warp::reply::Response {
+        let mut list: Vec<_> = self
+            .programs
+            .iter()
+            .filter(|(_, p)| p.owner == user)
+            .collect();
+        list.sort_by(|(_, a), (_, b)| a.created.cmp(&b.created).then(a.name.cmp(&b.name)));
+        let list: Vec<_> = list.into_iter().map(|(id, p)| summary(id, p)).collect();
+        warp::reply::json(&list).into_response()
+    }
+
+    pub fn create(&mut self, user: &str, payload: NamePayload) -> warp::reply::Response {
+        if payload.name.trim().is_empty() {
+            return error_detail(StatusCode::BAD_REQUEST, "Name must not be empty");
+        }
+        self.next_id += 1;
+        let id = format!("00000000-0000-0000-0000-{:012x}", self.next_id);
+        let now = timestamp();
+        let program = StoredProgram {
+            owner: user.to_string(),
+            name: payload.name,
+            version: 0,
+            created: now.clone(),
+            updated: now,
+            program: serde_json::json!({ "lanes": [] }),
+        };
+        let reply = warp::reply::json(&summary(id.as_str(), &program));
+        self.programs.insert(id, program);
+        warp::reply::with_status(reply, StatusCode::CREATED).into_response()
+    }
+
+    pub fn fetch(&mut self, user: &str, id: &str) -> warp::reply::Response {
+        match self.get_mut(user, id) {
+            Some(p) => {
+                let mut body = summary(id, p);
+                body["program"] = p.program.clone();
+                warp::reply::json(&body).into_response()
+            }
+            None => not_found(),
+        }
+    }
+
+    pub fn save(&mut self, user: &str, id: &str, payload: SavePayload) -> warp::reply::Response {
+        let p = match self.get_mut(user, id) {
+            Some(p) => p,
+            None => return not_found(),
+        };
+        if let Some(expected) = payload.expected_version {
+            if expected != p.version {
+                return error_detail(
+                    StatusCode::CONFLICT,
+                    format!("Expected version {}, found {}", expected, p.version),
+                );
+            }
+        }
+        p.program = payload.program;
+        p.version += 1;
+        p.updated = timestamp();
+        warp::reply::json(&summary(id, p)).into_response()
+    }
+
+    pub fn rename(&mut self, user: &str, id: &str, payload: NamePayload) -> warp::reply::Response {
+        if payload.name.trim().is_empty() {
+            return error_detail(StatusCode::BAD_REQUEST, "Name must not be empty");
+        }
+        match self.get_mut(user, id) {
+            Some(p) => {
+                p.name = payload.name;
+                p.updated = timestamp();
+                warp::reply::json(&summary(id, p)).into_response()
+            }
+            None => not_found(),
+        }
+    }
+
+    pub fn delete(&mut self, user: &str, id: &str) -> warp::reply::Response {
+        if self.get_mut(user, id).is_none() {
+            return not_found();
+        }
+        self.programs.remove(id);
+        warp::reply::with_status(warp::reply(), StatusCode::NO_CONTENT).into_response()
+    }
+}
@@ -0,0 +1,492 @@
+//! Procedurally generated rooms with a crude simulation of bots mining resources
+
+use std::collections::HashMap;
+
+use crate::cao_sim_model::{
+    AxialPos, Bot, BoundedValue, Decay, DropoffIntent, EntitiesPayload, EntityPosition, MineIntent,
+    Owner, Resource, ResourceType, Script, Spawn, Structure, StructureBody, TerrainPayload,
+};
+
+/// Must match the radius the client requests the layout with
+pub const ROOM_RADIUS: i32 = 30;
+
+const EMPTY: i64 = 0;
+const PLAIN: i64 = 1;
+const WALL: i64 = 2;
+const BRIDGE: i64 = 3;
+
+const MAX_BOTS: usize = 8;
+const SPAWN_TIME: i64 = 10;
+const CARRY_MAX: i64 = 50;
+const MINE_AMOUNT: i64 = 10;
+const RESOURCE_MAX: i64 = 500;
+const SPAWN_ENERGY_MAX: i64 = 1000;
+const BOT_HP: i64 = 100;
+
+const NEIGHBOURS: [AxialPos; 6] = [
+    AxialPos { q: 1, r: 0 },
+    AxialPos { q: 1, r: -1 },
+    AxialPos { q: 0, r: -1 },
+    AxialPos { q: -1, r: 0 },
+    AxialPos { q: -1, r: 1 },
+    AxialPos { q: 0, r: 1 },
+];
+
+/// Owner id of every mock entity
+pub const MOCK_USER_ID: [u8; 16] = [
+    0x6d, 0x6f, 0x63, 0x6b, 0x2d, 0x75, 0x73, 0x65, 0x72, 0x2d, 0x30, 0x30, 0x30, 0x30, 0x30, 0x31,
+];
+const MOCK_SCRIPT_ID: [u8; 16] = [
+    0x6d, 0x6f, 0x63, 0x6b, 0x2d, 0x73, 0x63, 0x72, 0x69, 0x70, 0x74, 0x2d, 0x30, 0x30, 0x30, 0x31,
+];
+
+fn add(a: AxialPos, b: AxialPos) -> AxialPos {
+    AxialPos {
+        q: a.q + b.q,
+        r: a.r + b.r,
+    }
+}
+
+fn scale(a: AxialPos, s: i32) -> AxialPos {
+    AxialPos {
+        q: a.q * s,
+        r: a.r * s,
+    }
+}
+
+pub fn hex_distance(a: AxialPos, b: AxialPos) -> i32 {
+    let dq = a.q - b.q;
+    let dr = a.r - b.r;
+    (dq.abs() + dr.abs() + (dq + dr).abs()) / 2
+}
+
+fn room_center(radius: i32) -> AxialPos {
+    AxialPos {
+        q: radius,
+        r: radius,
+    }
+}
+
+/// Hexagon of the given radius, centered at (radius, radius)
+pub fn layout(radius: i32) -> Vec<AxialPos> {
+    let center = room_center(radius);
+    let mut result = Vec::with_capacity((3 * radius * (radius + 1) + 1) as usize);
+    for q in 0..=2 * radius {
+        for r in 0..=2 * radius {
+            let p = AxialPos { q, r };
+            if hex_distance(p, center) <= radius {
+                result.push(p);
+            }
+        }
+    }
+    result
+}
+
+/// Hexagonal rooms tile the plane along these two vectors
+fn room_offset(room: AxialPos, radius: i32) -> AxialPos {
+    add(
+        scale(
+            AxialPos {
+                q: 2 * radius + 1,
+                r: -radius,
+            },
+            room.q,
+        ),
+        scale(
+            AxialPos {
+                q: radius,
+                r: radius + 1,
+            },
+            room.r,
+        ),
+    )
+}
+
+fn encode_id(id: &[u8; 16]) -> String {
+    base64::encode(id)
+}
+
+pub struct Room {
+    id: AxialPos,
+    offset: AxialPos,
+    layout: Vec<AxialPos>,
+    terrain: HashMap<AxialPos, i64>,
+    bots: Vec<Bot>,
+    structures: Vec<Structure>,
+    resources: Vec<Resource>,
+    rng: fastrand::Rng,
+}
+
+impl Room {
+    fn generate(id: AxialPos, next_id: &mut u64) -> Self {
+        let seed = ((id.q as u32 as u64) << 32) | id.r as u32 as u64;
+        let rng = fastrand::Rng::with_seed(seed ^ 0x5eed_cafe_f00d_beef);
+        let layout = layout(ROOM_RADIUS);
+        let center = room_center(ROOM_RADIUS);
+
+        // bridges in the middle of the sides, connecting the neighbouring rooms
+        let side_midpoints: Vec<AxialPos> = (0..6)
+            .map(|i| {
+                let a = scale(NEIGHBOURS[i], ROOM_RADIUS);
+                let b = scale(NEIGHBOURS[(i + 1) % 6], ROOM_RADIUS);
+                add(
+                    center,
+                    AxialPos {
+                        q: (a.q + b.q) / 2,
+                        r: (a.r + b.r) / 2,
+                    },
+                )
+            })
+            .collect();
+        let blobs: Vec<(AxialPos, i32)> = (0..rng.usize(4..10))
+            .map(|_| (layout[rng.usize(..layout.len())], rng.i32(1..5)))
+            .collect();
+
+        let terrain = layout
+            .iter()
+            .map(|p| {
+                let d = hex_distance(*p, center);
+                let ty = if d == ROOM_RADIUS {
+                    if side_midpoints.iter().any(|m| hex_distance(*m, *p) <= 2) {
+                        BRIDGE
+                    } else {
+                        WALL
+                    }
+                } else if d > 3 && blobs.iter().any(|(b, r)| hex_distance(*b, *p) <= *r) {
+                    WALL
+                } else if d > 3 && rng.u8(..100) < 2 {
+                    EMPTY
+                } else {
+                    PLAIN
+                };
+                (*p, ty)
+            })
+            .collect();
+
+        let mut room = Self {
+            id,
+            offset: room_offset(id, ROOM_RADIUS),
+            layout,
+            terrain,
+            bots: Vec::new(),
+            structures: Vec::new(),
+            resources: Vec::new(),
+            rng,
+        };
+
+        let spawn_pos = room.position(center);
+        room.structures.push(Structure {
+            id: take_id(next_id),
+            pos: spawn_pos,
+            hp: BoundedValue {
+                value: 1000,
+                value_max: 1000,
+            },
+            energy: Some(BoundedValue {
+                value: 0,
+                value_max: SPAWN_ENERGY_MAX,
+            }),
+            energy_regen: Some(1),
+            owner: Some(Owner {
+                data: encode_id(&MOCK_USER_ID),
+            }),
+            script: Some(Script {
+                data: encode_id(&MOCK_SCRIPT_ID),
+            }),
+            structure_body: StructureBody::Spawn(Spawn::default()),
+        });
+        for _ in 0..room.rng.usize(2..5) {
+            let pos = room.random_walkable();
+            let pos = room.position(pos);
+            room.resources.push(Resource {
+                id: take_id(next_id),
+                pos,
+                resource_type: ResourceType {
+                    energy: BoundedValue {
+                        value: RESOURCE_MAX,
+                        value_max: RESOURCE_MAX,
+                    },
+                },
+            });
+        }
+        for _ in 0..3 {
+            let pos = room.random_walkable();
+            let bot = room.new_bot(take_id(next_id), pos, encode_id(&MOCK_SCRIPT_ID));
+            room.bots.push(bot);
+        }
+        room
+    }
+
+    fn position(&self, pos: AxialPos) -> EntityPosition {
+        EntityPosition {
+            room: self.id,
+            pos,
+            offset: self.offset,
+        }
+    }
+
+    fn is_walkable(&self, pos: AxialPos) -> bool {
+        matches!(self.terrain.get(&pos), Some(&PLAIN) | Some(&BRIDGE))
+    }
+
+    fn random_walkable(&mut self) -> AxialPos {
+        loop {
+            let pos = self.layout[self.rng.usize(..self.layout.len())];
+            if self.is_walkable(pos) {
+                return pos;
+            }
+        }
+    }
+
+    fn new_bot(&mut self, id: u64, pos: AxialPos, script: String) -> Bot {
+        Bot {
+            id,
+            pos: self.position(pos),
+            carry: Some(BoundedValue {
+                value: 0,
+                value_max: CARRY_MAX,
+            }),
+            hp: Some(BoundedValue {
+                value: BOT_HP,
+                value_max: BOT_HP,
+            }),
+            script: Some(Script { data: script }),
+            owner: Some(Owner {
+                data: encode_id(&MOCK_USER_ID),
+            }),
+            decay: Some(Decay {
+                hp_amount: 10,
+                interval: 10,
+                time_remaining: 10,
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// Walkable neighbour closest to the target, with some random wandering
+    fn step_towards(&mut self, from: AxialPos, target: AxialPos) -> AxialPos {
+        let walkable: Vec<AxialPos> = NEIGHBOURS
+            .iter()
+            .map(|n| add(from, *n))
+            .filter(|p| self.is_walkable(*p))
+            .collect();
+        if walkable.is_empty() {
+            return from;
+        }
+        if self.rng.u8(..100) < 15 {
+            return walkable[self.rng.usize(..walkable.len())];
+        }
+        walkable
+            .into_iter()
+            .min_by_key(|p| hex_distance(*p, target))
+            .filter(|p| hex_distance(*p, target) < hex_distance(from, target))
+            .unwrap_or(from)
+    }
+
+    fn tick(&mut self, next_id: &mut u64) {
+        self.update_spawns(next_id);
+        self.update_decay();
+        for i in 0..self.bots.len() {
+            self.update_bot(i);
+        }
+        for res in self.resources.iter_mut() {
+            let energy = &mut res.resource_type.energy;
+            energy.value = (energy.value + 1).min(energy.value_max);
+        }
+    }
+
+    fn update_spawns(&mut self, next_id: &mut u64) {
+        let mut spawned = Vec::new();
+        let bot_count = self.bots.len();
+        for structure in self.structures.iter_mut() {
+            if let Some(energy) = structure.energy.as_mut() {
+                energy.value =
+                    (energy.value + structure.energy_regen.unwrap_or(0)).min(energy.value_max);
+            }
+            let StructureBody::Spawn(spawn) = &mut structure.structure_body;
+            if spawn.time_to_spawn > 0 {
+                spawn.time_to_spawn -= 1;
+                if spawn.time_to_spawn == 0 {
+                    let script = structure.script.as_ref().map(|s| s.data.clone());
+                    spawned.push((spawn.spawning, structure.pos.pos, script));
+                    spawn.spawning = 0;
+                }
+            } else if bot_count + spawned.len() < MAX_BOTS {
+                spawn.spawning = take_id(next_id);
+                spawn.time_to_spawn = SPAWN_TIME;
+            }
+        }
+        for (id, pos, script) in spawned {
+            let pos = NEIGHBOURS
+                .iter()
+                .map(|n| add(pos, *n))
+                .find(|p| self.is_walkable(*p))
+                .unwrap_or(pos);
+            let script = script.unwrap_or_else(|| encode_id(&MOCK_SCRIPT_ID));
+            let bot = self.new_bot(id, pos, script);
+            self.bots.push(bot);
+        }
+    }
+
+    fn update_decay(&mut self) {
+        for bot in self.bots.iter_mut() {
+            if let (Some(decay), Some(hp)) = (bot.decay.as_mut(), bot.hp.as_mut()) {
+                decay.time_remaining -= 1;
+                if decay.time_remaining <= 0 {
+                    decay.time_remaining = decay.interval;
+                    hp.value -= decay.hp_amount;
+                }
+            }
+        }
+        self.bots
+            .retain(|bot| bot.hp.as_ref().map(|hp| hp.value > 0).unwrap_or(true));
+    }
+
+    fn update_bot(&mut self, i: usize) {
+        let from = self.bots[i].pos.pos;
+        self.bots[i].mine_intent = None;
+        self.bots[i].dropoff_intent = None;
+        self.bots[i].say = None;
+        // bots that can not carry anything have nothing to do
+        let carry = match self.bots[i].carry.clone() {
+            Some(carry) => carry,
+            None => return,
+        };
+
+        let target;
+        if carry.value >= carry.value_max {
+            let spawn = match self.structures.first() {
+                Some(s) => s,
+                None => return,
+            };
+            if hex_distance(spawn.pos.pos, from) <= 1 {
+                let spawn_id = spawn.id;
+                if let Some(energy) = self.structures[0].energy.as_mut() {
+                    energy.value = (energy.value + carry.value).min(energy.value_max);
+                }
+                let bot = &mut self.bots[i];
+                bot.carry = Some(BoundedValue { value: 0, ..carry });
+                bot.dropoff_intent = Some(DropoffIntent {
+                    target_id: spawn_id,
+                });
+                bot.say = Some("dropoff".to_string());
+                return;
+            }
+            target = spawn.pos.pos;
+        } else {
+            let resource = self
+                .resources
+                .iter_mut()
+                .filter(|r| r.resource_type.energy.value > 0)
+                .min_by_key(|r| hex_distance(r.pos.pos, from));
+            let resource = match resource {
+                Some(r) => r,
+                None => return,
+            };
+            if hex_distance(resource.pos.pos, from) <= 1 {
+                let energy = &mut resource.resource_type.energy;
+                let amount = MINE_AMOUNT
+                    .min(energy.value)
+                    .min(carry.value_max - carry.value);
+                energy.value -= amount;
+                let bot = &mut self.bots[i];
+                bot.carry = Some(BoundedValue {
+                    value: carry.value + amount,
+                    ..carry
+                });
+                bot.mine_intent = Some(MineIntent {
+                    target_id: resource.id,
+                });
+                return;
+            }
+            target = resource.pos.pos;
+        }
+        let next = self.step_towards(from, target);
+        self.bots[i].pos.pos = next;
+    }
+
+    pub fn terrain_payload(&self) -> TerrainPayload {
+        TerrainPayload {
+            room_id: self.id,
+            offset: self.offset,
+            tiles: self.layout.iter().map(|p| self.terrain[p]).collect(),
+        }
+    }
+
+    pub fn entities_payload(&self, time: i64) -> EntitiesPayload {
+        EntitiesPayload {
+            time,
+            room_id: self.id,
+            bots: self.bots.clone(),
+            structures: self.structures.clone(),
+            resources: self.resources.clone(),
+        }
+    }
+}
+
+fn take_id(next_id: &mut u64) -> u64 {
+    let id = *next_id;
+    *next_id += 1;
+    id
+}
+
+#[derive(Default)]
+pub struct World {
+    pub time: i64,
+    next_id: u64,
+    rooms: HashMap<AxialPos, Room>,
+}
+
+impl World {
+    /// Rooms are generated when they're first requested
+    pub fn room(&mut self, id: AxialPos) -> &Room {
+        let next_id = &mut self.next_id;
+        self.rooms
+            .entry(id)
+            .or_insert_with(|| Room::generate(id, next_id))
+    }
+
+    pub fn tick(&mut self) {
+        self.time += 1;
+        for room in self.rooms.values_mut() {
+            room.tick(&mut self.next_id);
+        }
+    }
+
+    /// Returns false if no bot or structure has the id
+    pub fn assign_script(&mut self, entity_id: u64, script: &[u8; 16]) -> bool {
+        let script = Some(Script {
+            data: encode_id(script),
+        });
+        for room in self.rooms.values_mut() {
+            if let Some(bot) = room.bots.iter_mut().find(|b| b.id == entity_id) {
+                bot.script = script;
+                return true;
+            }
+            if let Some(s) = room.structures.iter_mut().find(|s| s.id == entity_id) {
+                s.script = script;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// `(bots, structures)` owned by `owner` in the generated rooms
+    pub fn owned_counts(&self, owner: &[u8; 16]) -> (usize, usize) {
+        let owner = Some(Owner {
+            data: encode_id(owner),
+        });
+        self.rooms
+            .values()
+            .fold((0, 0), |(bots, structures), room| {
+                (
+                    bots + room.bots.iter().filter(|b| b.owner == owner).count(),
+                    structures + room.structures.iter().filter(|s| s.owner == owner).count(),
+                )
+            })
+    }
+
+    pub fn entities(&self, room: AxialPos) -> Option<EntitiesPayload> {
+        self.rooms.get(&room).map(|r| r.entities_payload(self.time))
+    }
+}
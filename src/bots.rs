@@ -10,9 +10,12 @@ use bevy::{
 };
 
 use crate::{
-    cao_entities::{self, pos_2d_to_3d, EntityMetadata, EntityMovedEvent, NewEntityEvent},
+    cao_entities::{
+        self, pos_2d_to_3d, timeline::Timeline, DespawnReason, Despawning, EntityDespawnedEvent,
+        EntityMetadata, EntityMovedEvent, NewEntityEvent,
+    },
     cao_sim_client::{
-        cao_sim_model::{self, EntityPosition},
+        cao_sim_model::{self, AxialPos, EntityPosition},
         SimEntityId,
     },
     mining::MiningEvent,
@@ -32,9 +35,20 @@ pub struct CurrentRotation(pub Quat);
 #[derive(Debug, Clone, Default)]
 struct WalkTimer(Timer);
 
+/// Marks the place where a bot decayed
+pub struct DeathMarker {
+    pub bot_id: SimEntityId,
+    /// absolute position
+    pub pos: AxialPos,
+    pub decay: Option<cao_sim_model::Decay>,
+    timer: Timer,
+}
+
 pub struct BotsPlugin;
 
 pub const STEP_TIME: f32 = 0.8;
+/// Seconds the death markers are displayed for
+pub const DEATH_MARKER_TIME: f32 = 10.0;
 
 fn build_bot(
     cmd: &mut EntityCommands,
@@ -115,8 +129,9 @@ fn update_transform_rot(
     }
 }
 
-fn update_walkies_system(time: Res<Time>, mut q: Query<&mut WalkTimer>) {
-    let delta = time.delta();
+/// walk animations follow the timeline's playback speed
+fn update_walkies_system(time: Res<Time>, timeline: Res<Timeline>, mut q: Query<&mut WalkTimer>) {
+    let delta = time.delta().mul_f32(timeline.time_scale());
     for mut t in q.iter_mut() {
         t.0.tick(delta);
     }
@@ -218,6 +233,78 @@ fn on_new_entities_system(
     }
 }
 
+/// dying bots shrink into the ground
+fn despawn_animation_system(mut q: Query<(&Despawning, &mut Transform), With<Bot>>) {
+    for (despawning, mut tr) in q.iter_mut() {
+        let t = ezing::quad_in(despawning.timer.percent());
+        tr.scale = Vec3::splat(1.0 - t);
+    }
+}
+
+fn on_bot_despawned_system(
+    mut cmd: Commands,
+    bot_assets: Res<bot_assets::BotRenderingAssets>,
+    mut bot_materials: ResMut<Assets<bot_assets::BotMaterial>>,
+    mut despawned: EventReader<EntityDespawnedEvent>,
+    bots: Query<(&cao_sim_model::Bot, &CurrentPos)>,
+) {
+    for event in despawned
+        .iter()
+        .filter(|e| e.ty == cao_entities::EntityType::Bot && e.reason == DespawnReason::Decayed)
+    {
+        let (bot, pos) = match bots.get(event.id) {
+            Ok(b) => b,
+            Err(err) => {
+                trace!("Decayed bot can't be queried {:?}", err);
+                continue;
+            }
+        };
+        debug!(
+            "Bot {:?} decayed at {}",
+            event.cao_id,
+            bot.pos.absolute_axial()
+        );
+        let material = bot_materials.add(bot_assets::BotMaterial {
+            color: Color::rgb(0.4, 0.4, 0.4),
+            time: 0.0,
+            selected: 0,
+        });
+        cmd.spawn_bundle((
+            DeathMarker {
+                bot_id: event.cao_id,
+                pos: bot.pos.absolute_axial(),
+                decay: bot.decay.clone(),
+                timer: Timer::from_seconds(DEATH_MARKER_TIME, false),
+            },
+            Transform::from_translation(pos_2d_to_3d(pos.0)),
+            GlobalTransform::default(),
+        ))
+        .with_children(|c| {
+            c.spawn_bundle(MeshBundle {
+                mesh: bot_assets.mesh.clone_weak(),
+                render_pipelines: RenderPipelines::from_pipelines(vec![RenderPipeline::new(
+                    bot_assets.pipeline.clone_weak(),
+                )]),
+                transform: Transform::from_scale(Vec3::new(0.6, 0.1, 0.6)),
+                ..Default::default()
+            })
+            .insert(material);
+        });
+    }
+}
+
+fn update_death_markers_system(
+    mut cmd: Commands,
+    time: Res<Time>,
+    mut q: Query<(Entity, &mut DeathMarker)>,
+) {
+    for (e, mut marker) in q.iter_mut() {
+        if marker.timer.tick(time.delta()).finished() {
+            cmd.entity(e).despawn_recursive();
+        }
+    }
+}
+
 fn update_from_to(bot_id: Entity, bot: &cao_sim_model::Bot, bot_q: &mut BotPosQuery) {
     let (mut last_pos, mut next_pos, mut last_rot, mut next_rot, mut t) =
         match bot_q.get_mut(bot_id) {
@@ -280,7 +367,10 @@ impl Plugin for BotsPlugin {
                     .with_system(update_bot_materials.system())
                     .with_system(update_walkies_system.system())
                     .with_system(on_payload_change_system.system())
-                    .with_system(update_orient_system.system()),
+                    .with_system(update_orient_system.system())
+                    .with_system(despawn_animation_system.system())
+                    .with_system(on_bot_despawned_system.system())
+                    .with_system(update_death_markers_system.system()),
             )
             .init_resource::<bot_assets::BotRenderingAssets>()
             .add_asset::<bot_assets::BotMaterial>();
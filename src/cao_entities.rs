@@ -1,21 +1,29 @@
+pub mod timeline;
+
 use std::collections::HashMap;
 
 use bevy::{ecs::system::EntityCommands, prelude::*};
 
 use crate::{
     cao_sim_client::{
-        cao_sim_model::{AxialPos, EntityPosition},
-        SimEntityId,
+        cao_sim_model::{AxialPos, Bot, EntityDelta, EntityPosition, Resource, Structure},
+        EntitiesUpdate, SimEntityId,
     },
     terrain::{is_room_visible, CurrentRoom, Room},
 };
 use lru::LruCache;
+use timeline::{PlaybackEntities, Timeline, TimelineCommand};
 
 /// maps absolute coordinates to entity ids
 pub struct EntityPositionMap(pub HashMap<AxialPos, smallvec::SmallVec<[Entity; 4]>>);
 pub struct SimToBevyId(pub LruCache<SimEntityId, Entity>);
-/// Latest time sent by the entities payload
+/// Time of the entities payload on display
 pub struct LatestTime(pub i64);
+/// Time of the last full snapshot of each room
+///
+/// Entities not present in the latest snapshot of their room are garbage collected, entities
+/// updated by deltas live until they are explicitly removed.
+pub struct SnapshotTimes(pub HashMap<AxialPos, i64>);
 
 #[derive(Debug, Clone, Copy)]
 pub struct NewEntityEvent {
@@ -31,6 +39,46 @@ pub struct EntityMovedEvent {
     pub ty: EntityType,
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct EntityDespawnedEvent {
+    pub id: Entity,
+    pub cao_id: SimEntityId,
+    pub ty: EntityType,
+    pub reason: DespawnReason,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DespawnReason {
+    /// Removed from the simulation
+    Died,
+    /// Bot removed from the simulation after its decay ran out
+    Decayed,
+    /// Moved out of the visible rooms, or is no longer tracked
+    LeftView,
+    /// The simulation reused the id for an entity of another type
+    IdRecycled,
+    /// The timeline was rewound to before the entity was spawned
+    Rewound,
+}
+
+impl DespawnReason {
+    /// The entity is removed from the world, as opposed to just the view
+    pub fn is_death(self) -> bool {
+        matches!(self, DespawnReason::Died | DespawnReason::Decayed)
+    }
+}
+
+/// Seconds the despawn animations take
+pub const DESPAWN_TIME: f32 = 1.0;
+
+/// Entity playing its despawn animation
+///
+/// The simulation data has been detached from it already, it is despawned when the timer finishes
+pub struct Despawning {
+    pub reason: DespawnReason,
+    pub timer: Timer,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum EntityType {
     Bot,
@@ -55,24 +103,88 @@ pub fn pos_2d_to_3d(p: Vec2) -> Vec3 {
 /// deletes old entities
 ///
 /// entities not in the meta-map are not deleted by this system
-// TODO: once entity death events  are available use those + check the visible rooms maybe
 fn entity_gc_system(
     mut cmd: Commands,
     latest: Res<LatestTime>,
+    snapshot_times: Res<SnapshotTimes>,
     sim2bevy: Res<SimToBevyId>,
     current_room: Res<CurrentRoom>,
-    q: Query<(Entity, &SimEntityId, &EntityPosition, &EntityMetadata)>,
+    mut despawned_event: EventWriter<EntityDespawnedEvent>,
+    q: Query<(
+        Entity,
+        &SimEntityId,
+        &EntityPosition,
+        &EntityMetadata,
+        Option<&Bot>,
+    )>,
 ) {
     let current_time = latest.0;
-    for (e, se, wp, meta) in q.iter() {
-        if current_time - meta.ts > 3 {
+    for (e, se, wp, meta, bot) in q.iter() {
+        let snapshot_time = snapshot_times.0.get(&wp.room).copied();
+        let reason = if meta.ts > current_time {
+            trace!("Deleting entity from the future {:?}", se);
+            DespawnReason::Rewound
+        } else if snapshot_time.map(|t| t - meta.ts > 3).unwrap_or(false) {
             trace!("Deleting expired entity {:?}", se);
-            cmd.entity(e).despawn_recursive();
+            death_reason(bot)
         } else if !sim2bevy.0.contains(se) {
-            trace!("Deleting dead entity {:?}", se);
-            cmd.entity(e).despawn_recursive();
+            trace!("Deleting untracked entity {:?}", se);
+            DespawnReason::LeftView
         } else if !is_room_visible(&*current_room, &Room(wp.room)) {
             trace!("Deleting out of range entity {:?}", se);
+            DespawnReason::LeftView
+        } else {
+            continue;
+        };
+        despawn_entity(&mut cmd, e, *se, meta.ty, reason, &mut despawned_event);
+    }
+}
+
+/// Bots killed by their decay are reported as [DespawnReason::Decayed]
+fn death_reason(bot: Option<&Bot>) -> DespawnReason {
+    let decayed = bot
+        .and_then(|bot| Some((bot.decay.as_ref()?, bot.hp.as_ref()?)))
+        .map(|(decay, hp)| decay.time_remaining <= 1 && hp.value <= decay.hp_amount)
+        .unwrap_or(false);
+    if decayed {
+        DespawnReason::Decayed
+    } else {
+        DespawnReason::Died
+    }
+}
+
+/// Detach the entity from the simulation and despawn it
+///
+/// Dead entities are kept around until their despawn animation finishes
+fn despawn_entity(
+    cmd: &mut Commands,
+    id: Entity,
+    cao_id: SimEntityId,
+    ty: EntityType,
+    reason: DespawnReason,
+    despawned_event: &mut EventWriter<EntityDespawnedEvent>,
+) {
+    if reason.is_death() {
+        cmd.entity(id)
+            .remove_bundle::<(SimEntityId, EntityMetadata, EntityPosition)>()
+            .insert(Despawning {
+                reason,
+                timer: Timer::from_seconds(DESPAWN_TIME, false),
+            });
+    } else {
+        cmd.entity(id).despawn_recursive();
+    }
+    despawned_event.send(EntityDespawnedEvent {
+        id,
+        cao_id,
+        ty,
+        reason,
+    });
+}
+
+fn despawning_system(mut cmd: Commands, time: Res<Time>, mut q: Query<(Entity, &mut Despawning)>) {
+    for (e, mut despawning) in q.iter_mut() {
+        if despawning.timer.tick(time.delta()).finished() {
             cmd.entity(e).despawn_recursive();
         }
     }
@@ -86,15 +198,34 @@ fn handle_new_entity<'a, 'b>(
     wp: EntityPosition,
     moved_event: &mut EventWriter<EntityMovedEvent>,
     spawned_event: &mut EventWriter<NewEntityEvent>,
+    despawned_event: &mut EventWriter<EntityDespawnedEvent>,
     meta_map: &mut Query<(&mut EntityMetadata, &mut EntityPosition)>,
     sim2bevy: &mut SimToBevyId,
 ) -> EntityCommands<'a, 'b> {
+    // if the simulation recycled this ID, we treat it as a new entity
+    let recycled = sim2bevy
+        .0
+        .peek(&cao_id)
+        .and_then(|id| meta_map.get_mut(*id).ok())
+        .filter(|(meta, _)| meta.ty != ty)
+        .map(|(meta, _)| (meta.id, meta.ty));
+    if let Some((id, old_ty)) = recycled {
+        trace!("Entity id {:?} recycled", cao_id);
+        despawn_entity(
+            cmd,
+            id,
+            cao_id,
+            old_ty,
+            DespawnReason::IdRecycled,
+            despawned_event,
+        );
+    }
+
     let entity_id;
     if let Some((id, (mut metadata, mut world_pos))) = sim2bevy
         .0
         .get(&cao_id) // moves this entity to the top of the LRU
         .and_then(|id| meta_map.get_mut(*id).ok().map(|x| (id, x)))
-        // if the simulation recycled this ID, we treat it as a new entity
         .and_then(|(id, m)| (m.0.ty == ty).then(|| (id, m)))
     {
         debug_assert_eq!(metadata.cao_id, cao_id);
@@ -165,68 +296,184 @@ fn update_positions_system(
     }
 }
 
+/// Spawns or updates the given entities
+fn insert_entities<'a>(
+    time: i64,
+    cmd: &mut Commands,
+    structures: impl Iterator<Item = &'a Structure>,
+    resources: impl Iterator<Item = &'a Resource>,
+    bots: impl Iterator<Item = &'a Bot>,
+    moved_event: &mut EventWriter<EntityMovedEvent>,
+    spawned_event: &mut EventWriter<NewEntityEvent>,
+    despawned_event: &mut EventWriter<EntityDespawnedEvent>,
+    meta_map: &mut Query<(&mut EntityMetadata, &mut EntityPosition)>,
+    sim2bevy: &mut SimToBevyId,
+) {
+    for structure in structures {
+        let cao_id = SimEntityId(structure.id);
+        handle_new_entity(
+            time,
+            cmd,
+            cao_id,
+            EntityType::Structure,
+            structure.pos.clone(),
+            moved_event,
+            spawned_event,
+            despawned_event,
+            meta_map,
+            sim2bevy,
+        )
+        .insert(structure.clone());
+    }
+    for resource in resources {
+        let cao_id = SimEntityId(resource.id);
+        handle_new_entity(
+            time,
+            cmd,
+            cao_id,
+            EntityType::Resource,
+            resource.pos.clone(),
+            moved_event,
+            spawned_event,
+            despawned_event,
+            meta_map,
+            sim2bevy,
+        )
+        .insert(resource.clone());
+    }
+    for bot in bots {
+        let cao_id = SimEntityId(bot.id);
+        handle_new_entity(
+            time,
+            cmd,
+            cao_id,
+            EntityType::Bot,
+            bot.pos.clone(),
+            moved_event,
+            spawned_event,
+            despawned_event,
+            meta_map,
+            sim2bevy,
+        )
+        .insert(bot.clone());
+    }
+}
+
+fn remove_entities<T>(
+    cmd: &mut Commands,
+    delta: &EntityDelta<T>,
+    ty: EntityType,
+    despawned_event: &mut EventWriter<EntityDespawnedEvent>,
+    meta_map: &mut Query<(&mut EntityMetadata, &mut EntityPosition)>,
+    bots: &Query<&Bot>,
+    sim2bevy: &mut SimToBevyId,
+) {
+    for cao_id in delta.removed.iter().copied().map(SimEntityId) {
+        let id = match sim2bevy.0.pop(&cao_id) {
+            Some(id) => id,
+            None => {
+                trace!("Removed entity {:?} is not tracked", cao_id);
+                continue;
+            }
+        };
+        match meta_map.get_mut(id) {
+            Ok((meta, _)) if meta.ty == ty => {
+                trace!("Despawning removed entity {:?}", cao_id);
+                let reason = death_reason(bots.get(id).ok());
+                despawn_entity(cmd, id, cao_id, ty, reason, despawned_event);
+            }
+            // the id has been recycled for an entity of another type
+            Ok(_) => {
+                sim2bevy.0.put(cao_id, id);
+            }
+            Err(_) => {}
+        }
+    }
+}
+
 fn on_new_entities_system(
     mut cmd: Commands,
-    mut new_entities: EventReader<crate::cao_sim_client::NewEntities>,
+    mut new_entities: EventReader<PlaybackEntities>,
     mut moved_event: EventWriter<EntityMovedEvent>,
     mut spawned_event: EventWriter<NewEntityEvent>,
+    mut despawned_event: EventWriter<EntityDespawnedEvent>,
     mut latest_ts: ResMut<LatestTime>,
+    mut snapshot_times: ResMut<SnapshotTimes>,
     mut sim2bevy: ResMut<SimToBevyId>,
     mut meta_map: Query<(&mut EntityMetadata, &mut EntityPosition)>,
+    bots: Query<&Bot>,
 ) {
-    for new_entities in new_entities.iter() {
-        let time = new_entities.0.time;
+    for PlaybackEntities(update) in new_entities.iter() {
+        let time = update.time();
 
         latest_ts.0 = latest_ts.0.max(time);
 
-        let cmd = &mut cmd;
-        let moved_event = &mut moved_event;
-        let spawned_event = &mut spawned_event;
-
-        for structure in new_entities.0.structures.iter() {
-            let cao_id = SimEntityId(structure.id);
-            handle_new_entity(
-                time,
-                cmd,
-                cao_id,
-                EntityType::Structure,
-                structure.pos.clone(),
-                moved_event,
-                spawned_event,
-                &mut meta_map,
-                &mut *sim2bevy,
-            )
-            .insert(structure.clone());
-        }
-        for resource in new_entities.0.resources.iter() {
-            let cao_id = SimEntityId(resource.id);
-            handle_new_entity(
-                time,
-                cmd,
-                cao_id,
-                EntityType::Resource,
-                resource.pos.clone(),
-                moved_event,
-                spawned_event,
-                &mut meta_map,
-                &mut *sim2bevy,
-            )
-            .insert(resource.clone());
-        }
-        for bot in new_entities.0.bots.iter() {
-            let cao_id = SimEntityId(bot.id);
-            handle_new_entity(
-                time,
-                cmd,
-                cao_id,
-                EntityType::Bot,
-                bot.pos.clone(),
-                moved_event,
-                spawned_event,
-                &mut meta_map,
-                &mut *sim2bevy,
-            )
-            .insert(bot.clone());
+        match update {
+            EntitiesUpdate::Snapshot(pl) => {
+                // assign instead of max, rewinding the timeline sends older snapshots
+                snapshot_times.0.insert(pl.room_id, time);
+                insert_entities(
+                    time,
+                    &mut cmd,
+                    pl.structures.iter(),
+                    pl.resources.iter(),
+                    pl.bots.iter(),
+                    &mut moved_event,
+                    &mut spawned_event,
+                    &mut despawned_event,
+                    &mut meta_map,
+                    &mut *sim2bevy,
+                );
+            }
+            EntitiesUpdate::Delta(delta) => {
+                remove_entities(
+                    &mut cmd,
+                    &delta.structures,
+                    EntityType::Structure,
+                    &mut despawned_event,
+                    &mut meta_map,
+                    &bots,
+                    &mut *sim2bevy,
+                );
+                remove_entities(
+                    &mut cmd,
+                    &delta.resources,
+                    EntityType::Resource,
+                    &mut despawned_event,
+                    &mut meta_map,
+                    &bots,
+                    &mut *sim2bevy,
+                );
+                remove_entities(
+                    &mut cmd,
+                    &delta.bots,
+                    EntityType::Bot,
+                    &mut despawned_event,
+                    &mut meta_map,
+                    &bots,
+                    &mut *sim2bevy,
+                );
+                insert_entities(
+                    time,
+                    &mut cmd,
+                    delta
+                        .structures
+                        .spawned
+                        .iter()
+                        .chain(delta.structures.updated.iter()),
+                    delta
+                        .resources
+                        .spawned
+                        .iter()
+                        .chain(delta.resources.updated.iter()),
+                    delta.bots.spawned.iter().chain(delta.bots.updated.iter()),
+                    &mut moved_event,
+                    &mut spawned_event,
+                    &mut despawned_event,
+                    &mut meta_map,
+                    &mut *sim2bevy,
+                );
+            }
         }
     }
 }
@@ -238,16 +485,29 @@ impl Plugin for CaoEntityPlugin {
         app.insert_resource(EntityPositionMap(HashMap::with_capacity(2048)))
             .insert_resource(SimToBevyId(LruCache::new(4096)))
             .insert_resource(LatestTime(-1))
+            .insert_resource(SnapshotTimes(HashMap::with_capacity(64)))
+            .insert_resource(Timeline::default())
             .add_event::<NewEntityEvent>()
             .add_event::<EntityMovedEvent>()
+            .add_event::<EntityDespawnedEvent>()
+            .add_event::<PlaybackEntities>()
+            .add_event::<TimelineCommand>()
             .add_system(update_positions_system.system())
+            .add_system(despawning_system.system())
             .add_stage_before(
                 CoreStage::PreUpdate,
                 "remote_input",
                 SystemStage::parallel(),
             )
             .add_stage_after(CoreStage::PostUpdate, "gc", SystemStage::parallel())
-            .add_system_to_stage("remote_input", on_new_entities_system.system())
+            .add_system_to_stage(
+                "remote_input",
+                timeline::timeline_system.system().label("timeline"),
+            )
+            .add_system_to_stage(
+                "remote_input",
+                on_new_entities_system.system().after("timeline"),
+            )
             .add_system_to_stage("gc", entity_gc_system.system());
     }
 }
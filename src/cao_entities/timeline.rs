@@ -0,0 +1,275 @@
+//! Buffered history of the entity payloads, allowing to pause, step and scrub through the recent
+//! ticks
+//!
+//! Incoming [NewEntities] are buffered here and forwarded as [PlaybackEntities] to the entity
+//! systems, either immediately (live) or when the playback reaches their tick.
+//!
+//! Deltas are forwarded as-is while live, but the history stores the snapshots they produce, so
+//! any tick can be displayed on its own.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+use bevy::prelude::*;
+
+use crate::cao_sim_client::{
+    cao_sim_model::{AxialPos, EntitiesPayload},
+    EntitiesUpdate, NewEntities,
+};
+
+use super::LatestTime;
+
+/// Number of ticks kept in the history
+pub const HISTORY_LEN: usize = 512;
+
+/// Payloads to be displayed
+pub struct PlaybackEntities(pub EntitiesUpdate);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimelineCommand {
+    Pause,
+    Play,
+    GoLive,
+    /// Step the given number of ticks forward (or backward if negative) and pause
+    Step(i64),
+    /// Jump to the given tick and pause
+    Seek(i64),
+    SetSpeed(f32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaybackState {
+    /// Display updates as they arrive
+    Live,
+    Paused,
+    /// Replaying the buffered history
+    Playing,
+}
+
+#[derive(Debug)]
+struct TickPayloads {
+    /// seconds since startup
+    received: f64,
+    /// one payload per room
+    payloads: Vec<Arc<EntitiesPayload>>,
+}
+
+#[derive(Debug)]
+pub struct Timeline {
+    ticks: BTreeMap<i64, TickPayloads>,
+    /// latest snapshot of each room, deltas are applied to these
+    rooms: HashMap<AxialPos, Arc<EntitiesPayload>>,
+    pub state: PlaybackState,
+    /// The tick on display
+    pub cursor: i64,
+    pub speed: f32,
+    /// Time accumulated towards the next tick while playing
+    progress: f64,
+}
+
+impl Default for Timeline {
+    fn default() -> Self {
+        Self {
+            ticks: BTreeMap::new(),
+            rooms: HashMap::new(),
+            state: PlaybackState::Live,
+            cursor: -1,
+            speed: 1.0,
+            progress: 0.0,
+        }
+    }
+}
+
+impl Timeline {
+    pub fn first_tick(&self) -> Option<i64> {
+        self.ticks.keys().next().copied()
+    }
+
+    pub fn last_tick(&self) -> Option<i64> {
+        self.ticks.keys().next_back().copied()
+    }
+
+    /// Multiplier of the animation speeds
+    ///
+    /// Steps taken while paused are animated at normal speed
+    pub fn time_scale(&self) -> f32 {
+        match self.state {
+            PlaybackState::Live | PlaybackState::Paused => 1.0,
+            PlaybackState::Playing => self.speed,
+        }
+    }
+
+    /// Materialize the update into a snapshot of its room
+    fn snapshot(&mut self, update: &EntitiesUpdate) -> Arc<EntitiesPayload> {
+        let snapshot = match update {
+            EntitiesUpdate::Snapshot(pl) => Arc::clone(pl),
+            EntitiesUpdate::Delta(delta) => {
+                let mut snapshot = match self.rooms.get(&delta.room_id) {
+                    Some(pl) => EntitiesPayload::clone(pl),
+                    None => {
+                        warn!(
+                            "Received delta for room {} without a snapshot",
+                            delta.room_id
+                        );
+                        EntitiesPayload {
+                            room_id: delta.room_id,
+                            ..Default::default()
+                        }
+                    }
+                };
+                snapshot.apply_delta(delta);
+                Arc::new(snapshot)
+            }
+        };
+        self.rooms.insert(snapshot.room_id, Arc::clone(&snapshot));
+        snapshot
+    }
+
+    fn insert(&mut self, received: f64, payload: Arc<EntitiesPayload>) {
+        let tick = self
+            .ticks
+            .entry(payload.time)
+            .or_insert_with(|| TickPayloads {
+                received,
+                payloads: Vec::with_capacity(8),
+            });
+        match tick
+            .payloads
+            .iter_mut()
+            .find(|pl| pl.room_id == payload.room_id)
+        {
+            Some(pl) => *pl = payload,
+            None => tick.payloads.push(payload),
+        }
+        while self.ticks.len() > HISTORY_LEN {
+            let first = self.first_tick().unwrap();
+            self.ticks.remove(&first);
+        }
+    }
+
+    fn next_tick(&self, tick: i64) -> Option<i64> {
+        self.ticks.range(tick + 1..).next().map(|(t, _)| *t)
+    }
+
+    fn prev_tick(&self, tick: i64) -> Option<i64> {
+        self.ticks.range(..tick).next_back().map(|(t, _)| *t)
+    }
+
+    /// Last buffered tick at or before `tick`
+    fn floor_tick(&self, tick: i64) -> Option<i64> {
+        self.ticks
+            .range(..=tick)
+            .next_back()
+            .map(|(t, _)| *t)
+            .or_else(|| self.first_tick())
+    }
+
+    fn step(&self, from: i64, n: i64) -> i64 {
+        let mut tick = from;
+        for _ in 0..n.abs() {
+            let next = if n > 0 {
+                self.next_tick(tick)
+            } else {
+                self.prev_tick(tick)
+            };
+            match next {
+                Some(t) => tick = t,
+                None => break,
+            }
+        }
+        tick
+    }
+
+    /// Seconds between the receipt of the two ticks
+    fn interval(&self, from: i64, to: i64) -> f64 {
+        match (self.ticks.get(&from), self.ticks.get(&to)) {
+            (Some(a), Some(b)) => b.received - a.received,
+            _ => 0.0,
+        }
+    }
+}
+
+pub fn timeline_system(
+    time: Res<Time>,
+    mut timeline: ResMut<Timeline>,
+    mut latest: ResMut<LatestTime>,
+    mut commands: EventReader<TimelineCommand>,
+    mut new_entities: EventReader<NewEntities>,
+    mut playback: EventWriter<PlaybackEntities>,
+) {
+    let now = time.seconds_since_startup();
+    for NewEntities(update) in new_entities.iter() {
+        let snapshot = timeline.snapshot(update);
+        timeline.insert(now, snapshot);
+        if timeline.state == PlaybackState::Live {
+            timeline.cursor = timeline.cursor.max(update.time());
+            playback.send(PlaybackEntities(update.clone()));
+        }
+    }
+
+    let mut seek = None;
+    for command in commands.iter().copied() {
+        debug!("Timeline command {:?}", command);
+        match command {
+            TimelineCommand::Pause => timeline.state = PlaybackState::Paused,
+            TimelineCommand::Play => {
+                timeline.state = PlaybackState::Playing;
+                timeline.progress = 0.0;
+            }
+            TimelineCommand::GoLive => {
+                timeline.state = PlaybackState::Live;
+                seek = timeline.last_tick();
+            }
+            TimelineCommand::Step(n) => {
+                timeline.state = PlaybackState::Paused;
+                let from = seek.unwrap_or(timeline.cursor);
+                seek = Some(timeline.step(from, n));
+            }
+            TimelineCommand::Seek(tick) => {
+                timeline.state = PlaybackState::Paused;
+                seek = timeline.floor_tick(tick);
+            }
+            TimelineCommand::SetSpeed(speed) => timeline.speed = speed.max(0.0),
+        }
+    }
+
+    if timeline.state == PlaybackState::Playing {
+        if timeline
+            .first_tick()
+            .map(|t| t > timeline.cursor)
+            .unwrap_or(false)
+        {
+            // the tick on display has been evicted from the history
+            seek = timeline.first_tick();
+        }
+        timeline.progress += time.delta_seconds_f64() * timeline.speed as f64;
+        let mut cursor = seek.unwrap_or(timeline.cursor);
+        while let Some(next) = timeline.next_tick(cursor) {
+            let interval = timeline.interval(cursor, next);
+            if timeline.progress < interval {
+                break;
+            }
+            timeline.progress -= interval;
+            cursor = next;
+            seek = Some(next);
+        }
+        if timeline.next_tick(cursor).is_none() {
+            debug!("Playback caught up with the live updates");
+            timeline.state = PlaybackState::Live;
+        }
+    }
+
+    if let Some(tick) = seek {
+        timeline.cursor = tick;
+        latest.0 = tick;
+        if let Some(tick) = timeline.ticks.get(&tick) {
+            for payload in tick.payloads.iter() {
+                playback.send(PlaybackEntities(EntitiesUpdate::Snapshot(Arc::clone(
+                    payload,
+                ))));
+            }
+        }
+    }
+}
@@ -1,4 +1,5 @@
 pub mod cao_lang_model;
+pub mod schema_cache;
 
 use bevy::{
     prelude::*,
@@ -6,122 +7,522 @@ use bevy::{
 };
 use cao_lang::compiler::CaoIr;
 use futures_lite::future;
+use thiserror::Error;
 
-use crate::{account::AuthToken, cao_lang_client::cao_lang_model::SchemaNode};
+use crate::{
+    account::{AuthToken, CurrentAuthToken},
+    api_client::{self, ApiErrorEvent, ApiResult, CaoApiError, Retry},
+    cao_lang_client::{
+        cao_lang_model::{
+            Program, ProgramSummary, RemoteCompileError, SaveProgramPayload, SchemaNode,
+        },
+        schema_cache::CachedSchema,
+    },
+    server_config::{ServerConfig, ServerProfileChanged},
+};
+
+/// Seconds between checking the server for a new schema
+const SCHEMA_REFRESH_SECS: f32 = 300.0;
 
 pub struct CaoLangSchema(pub Vec<cao_lang_model::SchemaNode>);
+/// Differences between the server's schema and the cards this client knows
+#[derive(Default)]
+pub struct SchemaWarnings(pub Vec<String>);
+/// Sent when the schema is replaced by a different one
+pub struct SchemaChanged {
+    /// cards of the previous schema that are no longer available
+    pub removed: Vec<SchemaNode>,
+}
+/// Server and version of the current schema
+#[derive(Default)]
+pub struct SchemaSource {
+    pub api_url: String,
+    pub etag: Option<String>,
+    /// whether the schema holds the server's cards, from the cache or fetched
+    pub loaded: bool,
+}
+struct SchemaRefreshTimer(Timer);
+/// Programs of the logged in user
+pub struct MyPrograms(pub Vec<ProgramSummary>);
+/// Refetch [MyPrograms]
+pub struct RefreshMyPrograms;
+
+type MyProgramsResult = ApiResult<Vec<ProgramSummary>>;
+/// `None` if the schema did not change since the last fetch
+type SchemaTask = Task<ApiResult<Option<CachedSchema>>>;
 
 pub struct CaoLangPlugin;
 
+/// Replace the schema with the server's `payload` and the bundled cards
+fn replace_schema(
+    payload: Vec<SchemaNode>,
+    schema: &mut CaoLangSchema,
+    warnings: &mut SchemaWarnings,
+    changed: &mut EventWriter<SchemaChanged>,
+) {
+    let (w, missing) = check_schema_version(&payload, bundled_schema());
+    let mut nodes = payload;
+    nodes.extend(missing);
+    if nodes == schema.0 {
+        return;
+    }
+    let removed = schema
+        .0
+        .iter()
+        .filter(|old| !nodes.iter().any(|n| n.ty == old.ty && n.name == old.name))
+        .cloned()
+        .collect();
+    schema.0 = nodes;
+    *warnings = w;
+    changed.send(SchemaChanged { removed });
+}
+
 fn handle_tasks_system(
     mut commands: Commands,
     mut layout: ResMut<CaoLangSchema>,
-    q: Query<(Entity, &mut Task<CaoLangSchema>)>,
+    mut warnings: ResMut<SchemaWarnings>,
+    mut source: ResMut<SchemaSource>,
+    mut changed: EventWriter<SchemaChanged>,
+    mut api_errors: EventWriter<ApiErrorEvent>,
+    q: Query<(Entity, &mut SchemaTask)>,
 ) {
     q.for_each_mut(|(e, mut t)| {
-        if let Some(stuff) = future::block_on(future::poll_once(&mut *t)) {
-            *layout = stuff;
-            commands.entity(e).remove::<Task<CaoLangSchema>>();
+        if let Some(res) = future::block_on(future::poll_once(&mut *t)) {
+            match res {
+                Ok(Some(fetched)) => {
+                    if let Err(err) = schema_cache::save(source.api_url.as_str(), fetched.clone()) {
+                        warn!("Failed to cache the schema: {:?}", err);
+                    }
+                    source.etag = fetched.etag;
+                    source.loaded = true;
+                    replace_schema(fetched.schema, &mut layout, &mut warnings, &mut changed);
+                }
+                Ok(None) => debug!("The schema is up to date"),
+                // the cached schema is still usable
+                Err(err) if source.loaded => warn!("Failed to refresh the schema: {}", err),
+                Err(err) => api_errors.send(ApiErrorEvent::new("Fetching the schema", err)),
+            }
+            commands.entity(e).remove::<SchemaTask>();
         }
     });
 }
 
-// TODO handle errors...
-pub async fn fetch_my_programs(token: AuthToken) -> Result<(), ()> {
-    let resp = surf::get(format!("{}/scripting/my-programs", crate::API_BASE_URL))
-        .header("Authorization", token)
-        .await;
-    todo!()
+pub async fn fetch_my_programs(api_url: String, token: AuthToken) -> MyProgramsResult {
+    api_client::send_json(Retry::Transient, || {
+        Ok(surf::get(format!("{}/scripting/my-programs", api_url))
+            .header("Authorization", token.as_str()))
+    })
+    .await
 }
 
-pub type CreateNewProgramResult = Result<(), cao_lang_model::CreateProgramError>;
-pub async fn create_new_program(name: String, token: AuthToken) -> CreateNewProgramResult {
+pub type CreateNewProgramResult = ApiResult<ProgramSummary>;
+pub async fn create_new_program(
+    api_url: String,
+    name: String,
+    token: AuthToken,
+) -> CreateNewProgramResult {
     #[derive(serde::Serialize)]
-    struct Payload {
-        name: String,
+    struct Payload<'a> {
+        name: &'a str,
     }
-    let resp = surf::post(format!("{}/scripting/create-program", crate::API_BASE_URL))
-        .header("Authorization", token)
-        .body_json(&Payload { name })
-        .unwrap()
-        .await;
-
-    dbg!(resp);
-    todo!()
-}
-
-// TODO handle errors...
-pub type CompileProgramResult = Result<(), cao_lang_model::RemoteCompileError>;
-pub async fn compile_program(program: CaoIr) -> CompileProgramResult {
-    let mut resp = loop {
-        let resp = surf::post(format!("{}/scripting/compile", crate::API_BASE_URL))
-            .body_json(&program)
-            .expect("failed to serialize program");
-        match resp.await {
-            Ok(resp) => break resp,
-            Err(err) => {
-                error!("Request send failed, retrying, err: {:?}", err);
-            }
-        }
+    api_client::send_json(Retry::Never, || {
+        api_client::with_json(
+            surf::post(format!("{}/scripting/create-program", api_url))
+                .header("Authorization", token.as_str()),
+            &Payload {
+                name: name.as_str(),
+            },
+        )
+    })
+    .await
+}
+
+pub async fn fetch_program(
+    api_url: String,
+    token: AuthToken,
+    program_id: String,
+) -> ApiResult<Program> {
+    api_client::send_json(Retry::Transient, || {
+        Ok(
+            surf::get(format!("{}/scripting/programs/{}", api_url, program_id))
+                .header("Authorization", token.as_str()),
+        )
+    })
+    .await
+}
+
+/// Returns the summary of the new version
+pub async fn save_program(
+    api_url: String,
+    token: AuthToken,
+    program_id: String,
+    expected_version: Option<i64>,
+    program: CaoIr,
+) -> ApiResult<ProgramSummary> {
+    let payload = SaveProgramPayload {
+        program: &program,
+        expected_version,
     };
-    match resp.status() {
-        surf::StatusCode::Ok => Ok(()),
-        surf::StatusCode::BadRequest => {
-            let body = resp
-                .body_json()
-                .await
-                .expect("Failed to get compilation error");
-
-            Err(body)
-        }
-        surf::StatusCode::UnprocessableEntity => todo!(),
-        _ => {
-            error!("Unexpected response {:?}", resp);
-            todo!()
+    // a retry of a save the server did apply would be rejected as a conflict
+    api_client::send_json(Retry::Never, || {
+        api_client::with_json(
+            surf::put(format!("{}/scripting/programs/{}", api_url, program_id))
+                .header("Authorization", token.as_str()),
+            &payload,
+        )
+    })
+    .await
+}
+
+/// Create a new program holding `program`
+pub async fn save_new_program(
+    api_url: String,
+    token: AuthToken,
+    name: String,
+    program: CaoIr,
+) -> ApiResult<ProgramSummary> {
+    let created = create_new_program(api_url.clone(), name, token.clone()).await?;
+    save_program(
+        api_url,
+        token,
+        created.program_id,
+        Some(created.version),
+        program,
+    )
+    .await
+}
+
+pub async fn rename_program(
+    api_url: String,
+    token: AuthToken,
+    program_id: String,
+    name: String,
+) -> ApiResult<ProgramSummary> {
+    #[derive(serde::Serialize)]
+    struct Payload<'a> {
+        name: &'a str,
+    }
+    api_client::send_json(Retry::Transient, || {
+        api_client::with_json(
+            surf::patch(format!("{}/scripting/programs/{}", api_url, program_id))
+                .header("Authorization", token.as_str()),
+            &Payload {
+                name: name.as_str(),
+            },
+        )
+    })
+    .await
+}
+
+pub async fn delete_program(
+    api_url: String,
+    token: AuthToken,
+    program_id: String,
+) -> ApiResult<()> {
+    let res = api_client::send(Retry::Transient, || {
+        Ok(
+            surf::delete(format!("{}/scripting/programs/{}", api_url, program_id))
+                .header("Authorization", token.as_str()),
+        )
+    })
+    .await;
+    match res {
+        // a retried delete might not find the program anymore
+        Ok(_) | Err(CaoApiError::Status { status: 404, .. }) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Run the program on a bot, or on the bots a spawn spawns
+pub async fn assign_script(
+    api_url: String,
+    token: AuthToken,
+    entity_id: u64,
+    program_id: String,
+) -> ApiResult<()> {
+    #[derive(serde::Serialize)]
+    struct Payload<'a> {
+        entity_id: u64,
+        program_id: &'a str,
+    }
+    api_client::send(Retry::Transient, || {
+        api_client::with_json(
+            surf::post(format!("{}/scripting/assign-script", api_url))
+                .header("Authorization", token.as_str()),
+            &Payload {
+                entity_id,
+                program_id: program_id.as_str(),
+            },
+        )
+    })
+    .await?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Error)]
+pub enum CompileProgramError {
+    #[error("{}", .0.detail)]
+    Compile(RemoteCompileError),
+    #[error(transparent)]
+    Api(CaoApiError),
+}
+
+pub type CompileProgramResult = Result<(), CompileProgramError>;
+pub async fn compile_program(api_url: String, program: CaoIr) -> CompileProgramResult {
+    let res = api_client::send(Retry::Transient, || {
+        api_client::with_json(
+            surf::post(format!("{}/scripting/compile", api_url)),
+            &program,
+        )
+    })
+    .await;
+    match res {
+        Ok(_) => Ok(()),
+        // the program was delivered, but it does not compile
+        Err(CaoApiError::Status {
+            status: 400,
+            detail,
+        })
+        | Err(CaoApiError::Validation(detail)) => {
+            Err(CompileProgramError::Compile(RemoteCompileError { detail }))
         }
+        Err(err) => Err(CompileProgramError::Api(err)),
     }
 }
 
-async fn get_schema() -> CaoLangSchema {
-    let payload = surf::get(format!("{}/scripting/schema", crate::API_BASE_URL))
-        .recv_json()
-        .await
-        .expect("Failed to get schema");
-    trace!("Got schema payload {:#?}", payload);
-    let mut result = CaoLangSchema(payload);
-    let default_cards = cao_lang::compiler::card_description::get_instruction_descriptions();
-    result.0.extend(default_cards.iter().map(
-        |cao_lang::SubProgram {
-             name,
-             description,
-             ty,
-             output,
-             input,
-             properties,
-         }| {
-            SchemaNode {
+fn bundled_schema() -> Vec<SchemaNode> {
+    cao_lang::compiler::card_description::get_instruction_descriptions()
+        .iter()
+        .map(
+            |cao_lang::SubProgram {
+                 name,
+                 description,
+                 ty,
+                 output,
+                 input,
+                 properties,
+             }| SchemaNode {
                 name: name.to_string(),
-                ty: format!("{}", ty.as_str()),
+                ty: ty.as_str().to_string(),
                 description: description.to_string(),
                 input: input.iter().map(ToString::to_string).collect(),
                 output: output.iter().map(ToString::to_string).collect(),
                 properties: properties.iter().map(ToString::to_string).collect(),
+            },
+        )
+        .collect()
+}
+
+/// Compare the server's cards to the bundled `cao-lang` descriptions
+///
+/// Returns the warnings and the bundled cards missing from the server's schema
+fn check_schema_version(
+    remote: &[SchemaNode],
+    bundled: Vec<SchemaNode>,
+) -> (SchemaWarnings, Vec<SchemaNode>) {
+    let mut warnings = Vec::new();
+    for node in remote.iter() {
+        if let Err(err) = cao_lang_model::schema_to_card(node) {
+            warnings.push(format!(
+                "Server card {} is not supported: {}",
+                node.name, err
+            ));
+            continue;
+        }
+        if node.ty == "Call" {
+            continue;
+        }
+        match bundled.iter().find(|b| b.name == node.name) {
+            Some(b) if b.input != node.input || b.output != node.output => {
+                warnings.push(format!(
+                    "Server card {} has different inputs or outputs than the bundled cao-lang version",
+                    node.name
+                ));
             }
-        },
-    ));
+            Some(_) => {}
+            None => warnings.push(format!(
+                "Server card {} is not in the bundled cao-lang version",
+                node.name
+            )),
+        }
+    }
+    let missing = bundled
+        .into_iter()
+        .filter(|b| !remote.iter().any(|n| n.ty != "Call" && n.name == b.name))
+        .collect();
+    for w in warnings.iter() {
+        warn!("{}", w);
+    }
+    (SchemaWarnings(warnings), missing)
+}
+
+async fn fetch_schema(api_url: String, etag: Option<String>) -> ApiResult<Option<CachedSchema>> {
+    let res = api_client::send(Retry::Transient, || {
+        let req = surf::get(format!("{}/scripting/schema", api_url));
+        Ok(match etag.as_ref() {
+            Some(etag) => req.header("If-None-Match", etag.as_str()),
+            None => req,
+        })
+    })
+    .await;
+    let resp = match res {
+        Ok(resp) => resp,
+        Err(CaoApiError::Status { status: 304, .. }) => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let etag = resp.header("ETag").map(|v| v.last().as_str().to_string());
+    let schema = api_client::read_json(resp).await?;
+    trace!("Got schema payload {:#?}", schema);
+    Ok(Some(CachedSchema { etag, schema }))
+}
+
+/// Use the cached schema of the current server until the fresh one arrives
+fn load_schema(
+    commands: &mut Commands,
+    task_pool: &IoTaskPool,
+    config: &ServerConfig,
+    schema: &mut CaoLangSchema,
+    warnings: &mut SchemaWarnings,
+    source: &mut SchemaSource,
+    changed: &mut EventWriter<SchemaChanged>,
+) {
+    let api_url = config.current().api_base_url.clone();
+    let cached = match schema_cache::load(api_url.as_str()) {
+        Ok(cached) => cached,
+        Err(err) => {
+            warn!("Failed to load the cached schema: {:?}", err);
+            None
+        }
+    };
+    *source = SchemaSource {
+        api_url: api_url.clone(),
+        etag: cached.as_ref().and_then(|c| c.etag.clone()),
+        loaded: cached.is_some(),
+    };
+    // without a cache only the bundled cards are available
+    let payload = cached.map(|c| c.schema).unwrap_or_default();
+    replace_schema(payload, schema, warnings, changed);
+
+    let handle = task_pool.spawn(fetch_schema(api_url, source.etag.clone()));
+    commands.spawn().insert(handle);
+}
+
+fn setup_schema_task_system(
+    mut commands: Commands,
+    task_pool: Res<IoTaskPool>,
+    config: Res<ServerConfig>,
+    mut schema: ResMut<CaoLangSchema>,
+    mut warnings: ResMut<SchemaWarnings>,
+    mut source: ResMut<SchemaSource>,
+    mut changed: EventWriter<SchemaChanged>,
+) {
+    load_schema(
+        &mut commands,
+        &*task_pool,
+        &*config,
+        &mut *schema,
+        &mut *warnings,
+        &mut *source,
+        &mut changed,
+    );
+}
 
-    result
+/// load the schema of the newly selected server
+fn on_server_changed_system(
+    mut commands: Commands,
+    mut events: EventReader<ServerProfileChanged>,
+    task_pool: Res<IoTaskPool>,
+    config: Res<ServerConfig>,
+    mut schema: ResMut<CaoLangSchema>,
+    mut warnings: ResMut<SchemaWarnings>,
+    mut source: ResMut<SchemaSource>,
+    mut changed: EventWriter<SchemaChanged>,
+    tasks: Query<Entity, With<SchemaTask>>,
+) {
+    if events.iter().last().is_none() {
+        return;
+    }
+    for e in tasks.iter() {
+        commands.entity(e).remove::<SchemaTask>();
+    }
+    load_schema(
+        &mut commands,
+        &*task_pool,
+        &*config,
+        &mut *schema,
+        &mut *warnings,
+        &mut *source,
+        &mut changed,
+    );
 }
 
-fn setup_schema_task_system(mut commands: Commands, task_pool: Res<IoTaskPool>) {
-    let handle = task_pool.spawn(get_schema());
+/// check for a new schema every [SCHEMA_REFRESH_SECS]
+fn refresh_schema_system(
+    mut commands: Commands,
+    time: Res<Time>,
+    task_pool: Res<IoTaskPool>,
+    source: Res<SchemaSource>,
+    mut timer: ResMut<SchemaRefreshTimer>,
+    tasks: Query<Entity, With<SchemaTask>>,
+) {
+    if !timer.0.tick(time.delta()).just_finished() || tasks.iter().next().is_some() {
+        return;
+    }
+    let handle = task_pool.spawn(fetch_schema(source.api_url.clone(), source.etag.clone()));
     commands.spawn().insert(handle);
 }
 
+fn my_programs_system(
+    mut commands: Commands,
+    task_pool: Res<IoTaskPool>,
+    token: Res<CurrentAuthToken>,
+    config: Res<ServerConfig>,
+    mut refresh: EventReader<RefreshMyPrograms>,
+    mut programs: ResMut<MyPrograms>,
+    mut api_errors: EventWriter<ApiErrorEvent>,
+    mut tasks: Query<(Entity, &mut Task<MyProgramsResult>)>,
+) {
+    for (e, mut t) in tasks.iter_mut() {
+        if let Some(res) = future::block_on(future::poll_once(&mut *t)) {
+            match res {
+                Ok(p) => programs.0 = p,
+                Err(err) => api_errors.send(ApiErrorEvent::new("Fetching your programs", err)),
+            }
+            commands.entity(e).despawn_recursive();
+        }
+    }
+    let refresh = refresh.iter().last().is_some();
+    if !(refresh || token.is_changed()) {
+        return;
+    }
+    match token.0.as_ref() {
+        Some(token) => {
+            let handle = task_pool.spawn(fetch_my_programs(
+                config.current().api_base_url.clone(),
+                token.clone(),
+            ));
+            commands.spawn().insert(handle);
+        }
+        None => programs.0.clear(),
+    }
+}
+
 impl Plugin for CaoLangPlugin {
     fn build(&self, app: &mut AppBuilder) {
         app.insert_resource(CaoLangSchema(Vec::new()))
+            .init_resource::<SchemaWarnings>()
+            .init_resource::<SchemaSource>()
+            .insert_resource(SchemaRefreshTimer(Timer::from_seconds(
+                SCHEMA_REFRESH_SECS,
+                true,
+            )))
+            .add_event::<SchemaChanged>()
+            .insert_resource(MyPrograms(Vec::new()))
+            .add_event::<RefreshMyPrograms>()
+            .add_system(my_programs_system.system())
             .add_startup_system(setup_schema_task_system.system())
-            .add_system(handle_tasks_system.system());
+            .add_system(handle_tasks_system.system())
+            .add_system(on_server_changed_system.system())
+            .add_system(refresh_schema_system.system());
     }
 }
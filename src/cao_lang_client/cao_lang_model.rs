@@ -1,5 +1,5 @@
 use cao_lang::{
-    compiler::{CallNode, Card},
+    compiler::{CallNode, CaoIr, Card},
     InputString,
 };
 use thiserror::Error;
@@ -23,15 +23,53 @@ pub struct SchemaNode {
     pub properties: Vec<String>,
 }
 
+#[derive(Default, Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ProgramSummary {
+    pub program_id: String,
+    pub name: String,
+    /// incremented by the server on every save
+    #[serde(default)]
+    pub version: i64,
+    #[serde(default)]
+    pub created: Option<String>,
+    #[serde(default)]
+    pub updated: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Program {
+    #[serde(flatten)]
+    pub summary: ProgramSummary,
+    pub program: CaoIr,
+}
 
-#[derive(serde::Deserialize, Debug, Error)]
-pub enum CreateProgramError {
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct SaveProgramPayload<'a> {
+    pub program: &'a CaoIr,
+    /// the version the edits are based on, the server rejects the save with `409 Conflict` if
+    /// the program has been saved since. `None` overwrites unconditionally.
+    pub expected_version: Option<i64>,
 }
 
-pub fn schema_to_card(node: &SchemaNode) -> Card {
-    match node.ty.as_str() {
+/// Schema nodes this client can not turn into cards
+#[derive(Debug, Clone, Error)]
+pub enum SchemaError {
+    #[error("{name} has an undefined type")]
+    Undefined { name: String },
+    #[error("{ty} card {name} is not supported by this client")]
+    UnknownName { name: String, ty: String },
+    #[error("{name} has unknown card type {ty}")]
+    UnknownType { name: String, ty: String },
+    #[error("invalid function name {name}: {reason}")]
+    InvalidName { name: String, reason: String },
+}
+
+pub fn schema_to_card(node: &SchemaNode) -> Result<Card, SchemaError> {
+    let card = match node.ty.as_str() {
         "Undefined" => {
-            panic!("Undefined card type");
+            return Err(SchemaError::Undefined {
+                name: node.name.clone(),
+            })
         }
         "Branch" | "Object" | "Instruction" => match node.name.as_str() {
             "Pass" => Card::Pass,
@@ -76,11 +114,27 @@ pub fn schema_to_card(node: &SchemaNode) -> Card {
                 variable: Default::default(),
                 lane: Default::default(),
             },
-            _ => todo!("Schema name {} is not implemented", node.name),
+            _ => {
+                return Err(SchemaError::UnknownName {
+                    name: node.name.clone(),
+                    ty: node.ty.clone(),
+                })
+            }
         },
-        "Call" => Card::CallNative(Box::new(CallNode(
-            InputString::from(node.name.as_str()).expect("function name is too long"),
-        ))),
-        _ => todo!("Unknown card type {}", node.ty),
-    }
+        "Call" => {
+            let name =
+                InputString::from(node.name.as_str()).map_err(|err| SchemaError::InvalidName {
+                    name: node.name.clone(),
+                    reason: format!("{:?}", err),
+                })?;
+            Card::CallNative(Box::new(CallNode(name)))
+        }
+        _ => {
+            return Err(SchemaError::UnknownType {
+                name: node.name.clone(),
+                ty: node.ty.clone(),
+            })
+        }
+    };
+    Ok(card)
 }
@@ -0,0 +1,75 @@
+//! On-disk cache of the last schema fetched from each server
+//!
+//! The cache file is `--schema-cache <path>`, `CAO_SCHEMA_CACHE` or `caolo/schema.json` in the
+//! user's cache directory. Only the server's payload is stored, the bundled cards are added on
+//! load.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use super::cao_lang_model::SchemaNode;
+use crate::server_config::arg_or_env;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedSchema {
+    /// sent as `If-None-Match` when refreshing the schema
+    pub etag: Option<String>,
+    pub schema: Vec<SchemaNode>,
+}
+
+/// Cached schemas by api url
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+struct CacheFile(HashMap<String, CachedSchema>);
+
+fn cache_dir() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os("XDG_CACHE_HOME") {
+        return Some(PathBuf::from(dir));
+    }
+    if cfg!(windows) {
+        return std::env::var_os("LOCALAPPDATA").map(PathBuf::from);
+    }
+    std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache"))
+}
+
+pub fn cache_path() -> Option<PathBuf> {
+    arg_or_env("schema-cache", "CAO_SCHEMA_CACHE")
+        .map(PathBuf::from)
+        .or_else(|| cache_dir().map(|dir| dir.join("caolo").join("schema.json")))
+}
+
+fn read_cache_file(path: &Path) -> anyhow::Result<CacheFile> {
+    if !path.exists() {
+        return Ok(CacheFile::default());
+    }
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read schema cache {:?}", path))?;
+    serde_json::from_str(content.as_str())
+        .with_context(|| format!("Failed to parse schema cache {:?}", path))
+}
+
+pub fn load(api_url: &str) -> anyhow::Result<Option<CachedSchema>> {
+    let path = match cache_path() {
+        Some(p) => p,
+        None => return Ok(None),
+    };
+    let mut file = read_cache_file(&path)?;
+    Ok(file.0.remove(api_url))
+}
+
+pub fn save(api_url: &str, schema: CachedSchema) -> anyhow::Result<()> {
+    let path = cache_path().with_context(|| "No location to cache the schema in")?;
+    // a broken cache is overwritten
+    let mut file = read_cache_file(&path).unwrap_or_default();
+    file.0.insert(api_url.to_string(), schema);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create directory {:?}", dir))?;
+    }
+    let content = serde_json::to_vec(&file).with_context(|| "Failed to serialize the schema")?;
+    fs::write(&path, content).with_context(|| format!("Failed to write schema cache {:?}", path))
+}
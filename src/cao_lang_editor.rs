@@ -1,28 +1,48 @@
 mod card_widget;
+pub mod clipboard;
+pub mod diagnostics;
+pub mod history;
 mod lane_widget;
+pub mod program_browser;
+pub mod program_diff;
+pub mod program_files;
+pub mod sandbox;
+pub mod schema_palette;
+pub mod source_view;
+pub mod text_format;
+pub mod type_check;
 
 use crate::{
-    account::CurrentAuthToken,
+    api_client::ApiErrorEvent,
     cao_lang_client::{
         cao_lang_model::{schema_to_card, RemoteCompileError},
-        CaoLangSchema,
+        CaoLangSchema, CompileProgramError, CompileProgramResult, SchemaWarnings,
     },
+    server_config::ServerConfig,
 };
 use bevy::{prelude::*, tasks::Task};
 use bevy_egui::{
     egui::{self, color, CursorIcon, Id, InnerResponse, LayerId, Order, Sense, Shape, Ui},
-    EguiContext,
+    EguiClipboard, EguiContext,
 };
-use cao_lang::compiler::{CaoIr, Card, CompilationError};
+use cao_lang::compiler::{CaoIr, Card, CompilationError, LaneNode};
+use clipboard::CardSelection;
 use futures_lite::future;
+use history::{EditCommand, EditHistory};
+use schema_palette::SchemaPalette;
+use source_view::EditorMode;
+use type_check::StackTypeErrors;
 
 pub struct CurrentProgram(pub CaoIr);
 pub struct LaneNames(pub Vec<String>);
 pub struct CurrentLocalCompileError(pub Option<CompilationError>);
 pub struct CurrentRemoteCompileError(pub Option<RemoteCompileError>);
+/// `(lane, card)` the editor should bring into view
+#[derive(Default)]
+pub struct EditorFocus(pub Option<(usize, Option<usize>)>);
 
 type LocalCompileResult = Result<CaoIr, CompilationError>;
-type RemoteCompileResult = Result<(), RemoteCompileError>;
+type RemoteCompileResult = CompileProgramResult;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum LaneIndex {
@@ -40,6 +60,27 @@ pub struct OnCardDrop {
 
 pub struct CaoLangEditorPlugin;
 
+/// Program the editor starts with
+pub fn scratch_program() -> CaoIr {
+    CaoIr {
+        lanes: vec![cao_lang::compiler::Lane::default().with_name("Main")],
+    }
+}
+
+/// Lane references of the card
+pub fn lane_refs_mut(card: &mut Card) -> Vec<&mut LaneNode> {
+    match card {
+        Card::IfTrue(lane)
+        | Card::IfFalse(lane)
+        | Card::Jump(lane)
+        | Card::Repeat(lane)
+        | Card::While(lane)
+        | Card::ForEach { lane, .. } => vec![lane],
+        Card::IfElse { then, r#else } => vec![then, r#else],
+        _ => vec![],
+    }
+}
+
 fn drag_src<R>(ui: &mut Ui, id: Id, mut body: impl FnMut(&mut Ui) -> R) {
     let is_being_dragged = ui.memory().is_being_dragged(id);
 
@@ -116,10 +157,10 @@ fn drop_target<R>(
 
 fn on_card_drop_system(
     mut ir: ResMut<CurrentProgram>,
+    mut history: ResMut<EditHistory>,
     schema: Res<CaoLangSchema>,
     mut on_drop: EventReader<OnCardDrop>,
 ) {
-    let lanes = &mut ir.0.lanes;
     for drop in on_drop.iter().copied() {
         debug!("Drop event {:?}", drop);
 
@@ -130,28 +171,67 @@ fn on_card_drop_system(
             dst_card,
         } = drop;
 
-        let card: Card = match src_lane {
-            LaneIndex::LaneId(id) if lanes[id].cards.len() > src_card => {
-                lanes[id].cards.remove(src_card)
+        let dst_lane = match (src_lane, dst_lane) {
+            (_, LaneIndex::LaneId(id)) if id < ir.0.lanes.len() => id,
+            // dropping a card on the schema deletes it
+            (LaneIndex::LaneId(id), LaneIndex::SchemaLane)
+                if ir.0.lanes.get(id).map(|l| l.cards.len() > src_card) == Some(true) =>
+            {
+                let card = ir.0.lanes[id].cards[src_card].clone();
+                history.apply(
+                    &mut ir.0,
+                    EditCommand::RemoveCard {
+                        lane: id,
+                        index: src_card,
+                        card,
+                    },
+                );
+                continue;
             }
-            LaneIndex::SchemaLane => schema_to_card(&schema.0[src_card]),
+            _ => continue,
+        };
+        let dst_len = ir.0.lanes[dst_lane].cards.len();
+        let command = match src_lane {
+            LaneIndex::LaneId(id) if ir.0.lanes[id].cards.len() > src_card => {
+                // the card is removed before inserting
+                let dst_len = if id == dst_lane { dst_len - 1 } else { dst_len };
+                let dst = (dst_lane, dst_card.min(dst_len));
+                if (id, src_card) == dst {
+                    continue;
+                }
+                EditCommand::MoveCard {
+                    src: (id, src_card),
+                    dst,
+                }
+            }
+            LaneIndex::SchemaLane => match schema.0.get(src_card).map(schema_to_card) {
+                Some(Ok(card)) => EditCommand::InsertCard {
+                    lane: dst_lane,
+                    index: dst_card.min(dst_len),
+                    card,
+                },
+                Some(Err(err)) => {
+                    warn!("Can not add the card: {}", err);
+                    continue;
+                }
+                None => {
+                    warn!("Dropped card {} is no longer in the schema", src_card);
+                    continue;
+                }
+            },
             _ => {
                 continue;
             }
         };
-
-        match dst_lane {
-            LaneIndex::LaneId(id) => {
-                lanes[id].cards.insert(dst_card, card);
-            }
-            LaneIndex::SchemaLane => { /*noop*/ }
-        }
+        history.apply(&mut ir.0, command);
     }
 }
 
 fn schema_ui(
     egui_ctx: &mut EguiContext,
     schema: &CaoLangSchema,
+    warnings: &SchemaWarnings,
+    palette: &mut SchemaPalette,
     src_col_row: &mut Option<(LaneIndex, usize)>,
     dst_col_row: &mut Option<(LaneIndex, usize)>,
     dropped: &mut bool,
@@ -160,21 +240,75 @@ fn schema_ui(
         .scroll(true)
         .id(egui::Id::new("cao-lang-schema"))
         .show(egui_ctx.ctx(), |ui| {
+            if !warnings.0.is_empty() {
+                egui::CollapsingHeader::new(format!("⚠ {} schema warnings", warnings.0.len()))
+                    .id_source("cao-lang-schema-warnings")
+                    .show(ui, |ui| {
+                        for w in warnings.0.iter() {
+                            ui.label(w);
+                        }
+                    });
+            }
+            ui.horizontal(|ui| {
+                ui.label("🔍");
+                ui.add(egui::TextEdit::singleline(&mut palette.query).hint_text("Search"));
+                if ui.small_button("✖").clicked() {
+                    palette.query.clear();
+                }
+            });
+            let groups = palette.groups(schema);
+            let mut toggled_favorite = None;
             ui.columns(1, |uis| {
                 let ui = &mut uis[0];
                 let resp = drop_target(
                     ui,
                     true,
                     |ui| {
-                        for (card_index, card) in schema.0.iter().enumerate() {
-                            let id = Id::new("cao-lang-schema-item").with(card_index);
-                            drag_src(ui, id, |ui| {
-                                card_widget::schema_card_ui(ui, card);
-                            });
-
-                            if ui.memory().is_being_dragged(id) {
-                                *src_col_row = Some((LaneIndex::SchemaLane, card_index));
-                            }
+                        if groups.is_empty() {
+                            ui.label("No matching cards");
+                        }
+                        for (group, nodes) in groups.iter() {
+                            egui::CollapsingHeader::new(*group)
+                                .id_source(("cao-lang-schema-group", *group))
+                                .default_open(true)
+                                .show(ui, |ui| {
+                                    for card_index in nodes.iter().copied() {
+                                        let card = &schema.0[card_index];
+                                        // keyed by name so a dragged card stays the same card
+                                        // if the schema is refreshed mid-drag
+                                        let id = Id::new("cao-lang-schema-item")
+                                            .with(*group)
+                                            .with(card.name.as_str());
+                                        ui.horizontal(|ui| {
+                                            let star = if palette.is_favorite(card) {
+                                                "★"
+                                            } else {
+                                                "☆"
+                                            };
+                                            if ui.small_button(star).clicked() {
+                                                toggled_favorite = Some(card_index);
+                                            }
+                                            match schema_to_card(card) {
+                                                Ok(_) => drag_src(ui, id, |ui| {
+                                                    card_widget::schema_card_ui(ui, card, None);
+                                                }),
+                                                Err(err) => {
+                                                    let reason = err.to_string();
+                                                    card_widget::schema_card_ui(
+                                                        ui,
+                                                        card,
+                                                        Some(reason.as_str()),
+                                                    );
+                                                }
+                                            }
+                                        });
+
+                                        if ui.memory().is_being_dragged(id) {
+                                            *src_col_row =
+                                                Some((LaneIndex::SchemaLane, card_index));
+                                        }
+                                    }
+                                });
                         }
                     },
                     None,
@@ -186,18 +320,102 @@ fn schema_ui(
                     *dst_col_row = Some((LaneIndex::SchemaLane, 0));
                 }
             });
+            if let Some(i) = toggled_favorite {
+                palette.toggle_favorite(&schema.0[i]);
+            }
         });
 }
 
+fn selection_ui(
+    ui: &mut Ui,
+    selection: &mut CardSelection,
+    egui_clipboard: &mut EguiClipboard,
+    ir: &mut CurrentProgram,
+    history: &mut EditHistory,
+) {
+    ui.heading("Selection");
+    let range = selection.valid(&ir.0);
+    match range {
+        Some(range) => {
+            let cards = range.cards();
+            ui.label(format!(
+                "Lane {} cards {}..={}",
+                range.lane,
+                cards.start(),
+                cards.end()
+            ));
+        }
+        None => {
+            ui.label("Click ▣ on a card to select it, shift+click to select a range");
+        }
+    }
+    ui.horizontal_wrapped(|ui| {
+        let has_selection = range.is_some();
+        let copy = ui
+            .add(egui::Button::new("Copy").enabled(has_selection))
+            .on_hover_text("Ctrl+C")
+            .clicked();
+        let cut = ui
+            .add(egui::Button::new("Cut").enabled(has_selection))
+            .on_hover_text("Ctrl+X")
+            .clicked();
+        if let Some(range) = range.filter(|_| copy || cut) {
+            if let Some(text) = clipboard::copy_text(&ir.0, &range) {
+                egui_clipboard.set_contents(text.as_str());
+            }
+            if cut {
+                let command = clipboard::cut_command(&ir.0, &range);
+                history.apply(&mut ir.0, command);
+                selection.0 = None;
+            }
+        }
+        if ui
+            .add(egui::Button::new("Paste"))
+            .on_hover_text("Ctrl+V")
+            .clicked()
+        {
+            let cards = egui_clipboard
+                .get_contents()
+                .and_then(|text| clipboard::parse_cards(text.as_str()));
+            if let Some((command, pasted)) =
+                cards.and_then(|cards| clipboard::paste_command(&ir.0, range, cards))
+            {
+                history.apply(&mut ir.0, command);
+                selection.0 = Some(pasted);
+            }
+        }
+        if ui
+            .add(egui::Button::new("Extract to lane").enabled(has_selection))
+            .on_hover_text("Move the selected cards into a new lane and jump to it")
+            .clicked()
+        {
+            if let Some(range) = range {
+                let command = clipboard::extract_command(&ir.0, &range);
+                history.apply(&mut ir.0, command);
+                selection.0 = None;
+            }
+        }
+        if ui
+            .add(egui::Button::new("Clear").enabled(has_selection))
+            .on_hover_text("Escape")
+            .clicked()
+        {
+            selection.0 = None;
+        }
+    });
+}
+
 fn left_ui_system(
     egui_ctx: ResMut<EguiContext>, // exclusive ownership
     compile_error: Res<CurrentLocalCompileError>,
     remote_compile_error: Res<CurrentRemoteCompileError>,
     mut ir: ResMut<CurrentProgram>,
-    mut new_name: Local<String>,
-    token: Res<CurrentAuthToken>,
-    pool: Res<bevy::tasks::IoTaskPool>,
-    mut cmd: Commands,
+    mut files: ResMut<program_files::ProgramFiles>,
+    mut history: ResMut<EditHistory>,
+    mut mode: ResMut<EditorMode>,
+    mut selection: ResMut<CardSelection>,
+    mut egui_clipboard: ResMut<EguiClipboard>,
+    mut diff: ResMut<program_diff::ProgramDiff>,
 ) {
     egui::SidePanel::left("cao-lang-control").show(egui_ctx.ctx(), |ui| {
         ui.heading("Compilation result");
@@ -221,24 +439,47 @@ fn left_ui_system(
         }
         ui.separator();
 
-        if ui.small_button("Add Lane").clicked() {
-            ir.0.lanes.push(Default::default());
-        }
-
-        if let Some(token) = token.0.as_ref() {
-            ui.separator();
-            ui.text_edit_singleline(&mut *new_name);
-            if ui.small_button("New program").clicked() {
-                let name = std::mem::take(&mut *new_name);
-
-                // TODO: handle result
-                cmd.spawn()
-                    .insert(pool.spawn(crate::cao_lang_client::create_new_program(
-                        name,
-                        token.clone(),
-                    )));
+        ui.horizontal(|ui| {
+            ui.label("Editor:");
+            ui.radio_value(&mut *mode, EditorMode::Cards, "Cards");
+            ui.radio_value(&mut *mode, EditorMode::Text, "Text");
+        });
+        ui.horizontal(|ui| {
+            if ui
+                .add(egui::Button::new("⟲ Undo").enabled(history.can_undo()))
+                .on_hover_text("Ctrl+Z")
+                .clicked()
+            {
+                history.undo(&mut ir.0);
+            }
+            if ui
+                .add(egui::Button::new("⟳ Redo").enabled(history.can_redo()))
+                .on_hover_text("Ctrl+Shift+Z")
+                .clicked()
+            {
+                history.redo(&mut ir.0);
             }
+        });
+        if ui.small_button("Add Lane").clicked() {
+            let index = ir.0.lanes.len();
+            history.apply(
+                &mut ir.0,
+                EditCommand::AddLane {
+                    index,
+                    lane: Default::default(),
+                },
+            );
         }
+        ui.separator();
+        selection_ui(
+            ui,
+            &mut *selection,
+            &mut *egui_clipboard,
+            &mut *ir,
+            &mut *history,
+        );
+        ui.separator();
+        program_files::program_files_ui(ui, &mut *files, &mut *ir, &mut *history, &mut *diff);
     });
 }
 
@@ -246,12 +487,17 @@ fn remote_compile_result_system(
     mut cmd: Commands,
     tasks: Query<(Entity, &mut Task<RemoteCompileResult>)>,
     mut compile_error: ResMut<CurrentRemoteCompileError>,
+    mut api_errors: EventWriter<ApiErrorEvent>,
 ) {
     tasks.for_each_mut(|(e, mut task)| {
         if let Some(res) = future::block_on(future::poll_once(&mut *task)) {
             match res {
                 Ok(_) => compile_error.0 = None,
-                Err(err) => compile_error.0 = Some(err),
+                Err(CompileProgramError::Compile(err)) => compile_error.0 = Some(err),
+                // the program was not checked, keep the last verdict
+                Err(CompileProgramError::Api(err)) => {
+                    api_errors.send(ApiErrorEvent::new("Compiling on the server", err))
+                }
             }
             cmd.entity(e).despawn_recursive();
         }
@@ -263,6 +509,7 @@ fn compiler_result_system(
     tasks: Query<(Entity, &mut Task<LocalCompileResult>)>,
     mut compile_error: ResMut<CurrentLocalCompileError>,
     pool: Res<bevy::tasks::IoTaskPool>,
+    config: Res<ServerConfig>,
 ) {
     tasks.for_each_mut(|(e, mut task)| {
         if let Some(res) = future::block_on(future::poll_once(&mut *task)) {
@@ -270,7 +517,10 @@ fn compiler_result_system(
                 Ok(ir) => {
                     debug!("Sending IR to server");
                     cmd.spawn()
-                        .insert(pool.spawn(crate::cao_lang_client::compile_program(ir)));
+                        .insert(pool.spawn(crate::cao_lang_client::compile_program(
+                            config.current().api_base_url.clone(),
+                            ir,
+                        )));
 
                     compile_error.0 = None;
                 }
@@ -327,11 +577,32 @@ fn update_lane_names_system(ir: Res<CurrentProgram>, mut names: ResMut<LaneNames
 fn editor_ui_system(
     mut egui_ctx: ResMut<EguiContext>, // exclusive ownership
     schema: Res<CaoLangSchema>,
+    schema_warnings: Res<SchemaWarnings>,
+    mut palette: ResMut<SchemaPalette>,
     mut ir: ResMut<CurrentProgram>,
+    mut history: ResMut<EditHistory>,
     lane_names: Res<LaneNames>,
     mut on_drop: EventWriter<OnCardDrop>,
     compile_error: Res<CurrentLocalCompileError>,
+    type_errors: Res<StackTypeErrors>,
+    mut focus: ResMut<EditorFocus>,
+    mode: Res<EditorMode>,
+    mut selection: ResMut<CardSelection>,
+    mut name_draft: Local<Option<(usize, String)>>,
+    sandbox: Res<sandbox::Sandbox>,
 ) {
+    if *mode != EditorMode::Cards {
+        return;
+    }
+    let pending_focus = focus.0.take();
+    if let Some((lane, _)) = pending_focus {
+        let id = egui::Id::new("cao-lang-lane").with(LaneIndex::LaneId(lane));
+        egui_ctx
+            .ctx()
+            .memory()
+            .areas
+            .move_to_top(LayerId::new(Order::Middle, id));
+    }
     let mut src_col_row = None;
     let mut dst_col_row = None;
     let mut dropped = false;
@@ -339,11 +610,16 @@ fn editor_ui_system(
     schema_ui(
         &mut *egui_ctx,
         &*schema,
+        &*schema_warnings,
+        &mut *palette,
         &mut src_col_row,
         &mut dst_col_row,
         &mut dropped,
     );
     let mut closed_lane_idx = None;
+    let mut edits = Vec::new();
+    let mut actions = lane_widget::LaneActions::default();
+    let running_card = sandbox.current_card();
     for (lane_index, lane) in ir.0.lanes.iter_mut().enumerate() {
         let mut open = true;
         lane_widget::lane_ui(
@@ -355,7 +631,18 @@ fn editor_ui_system(
             &mut dst_col_row,
             &mut dropped,
             &*compile_error,
+            &*type_errors,
+            pending_focus
+                .filter(|(lane, _)| *lane == lane_index)
+                .and_then(|(_, card)| card),
+            running_card
+                .filter(|(lane, _)| *lane == lane_index)
+                .map(|(_, card)| card),
             &mut open,
+            &mut edits,
+            &*selection,
+            &mut actions,
+            &mut *name_draft,
         );
         if !open {
             closed_lane_idx = Some(lane_index);
@@ -373,8 +660,36 @@ fn editor_ui_system(
             });
         }
     }
-    if let Some(i) = closed_lane_idx {
-        ir.0.lanes.remove(i);
+    if let Some(lane) = actions.goto_lane {
+        focus.0 = Some((lane, None));
+    }
+    if let Some((lane, card, shift)) = actions.select {
+        selection.click(lane, card, shift);
+    }
+    if let Some(range) = actions.box_select {
+        selection.0 = Some(range);
+    }
+    if let Some((index, after)) = actions.rename {
+        let before = ir.0.lanes[index].name.clone();
+        history.apply(
+            &mut ir.0,
+            EditCommand::RenameLane {
+                index,
+                before,
+                after,
+            },
+        );
+    }
+    if let Some(lane) = actions.duplicate {
+        let command = clipboard::duplicate_lane_command(&ir.0, lane);
+        history.apply(&mut ir.0, command);
+    }
+    for edit in edits {
+        history.apply(&mut ir.0, edit);
+    }
+    if let Some(index) = closed_lane_idx {
+        let lane = ir.0.lanes[index].clone();
+        history.apply(&mut ir.0, EditCommand::RemoveLane { index, lane });
     }
 }
 
@@ -384,15 +699,38 @@ impl Plugin for CaoLangEditorPlugin {
             .insert_resource(LaneNames(Vec::with_capacity(4)))
             .insert_resource(CurrentLocalCompileError(None))
             .insert_resource(CurrentRemoteCompileError(None))
-            .insert_resource(CurrentProgram(CaoIr {
-                lanes: vec![cao_lang::compiler::Lane::default().with_name("Main")],
-            }))
+            .insert_resource(CurrentProgram(scratch_program()))
+            .insert_resource(program_files::ProgramFiles::default())
+            .insert_resource(EditHistory::default())
+            .insert_resource(SchemaPalette::default())
+            .insert_resource(StackTypeErrors::default())
+            .insert_resource(diagnostics::Diagnostics::default())
+            .insert_resource(diagnostics::RemovedSchemaCards::default())
+            .insert_resource(EditorFocus::default())
+            .insert_resource(sandbox::Sandbox::default())
+            .insert_resource(EditorMode::default())
+            .insert_resource(CardSelection::default())
+            .insert_resource(source_view::SourceView::default())
+            .insert_resource(program_diff::ProgramDiff::default())
+            .add_plugin(program_browser::ProgramBrowserPlugin)
+            // the schema may change while the editor is closed
+            .add_system(diagnostics::schema_changed_system.system())
             .add_system_set(
                 SystemSet::on_update(crate::AppState::CaoLangEditor)
-                    .with_system(left_ui_system.system())
+                    .with_system(left_ui_system.system().label("left-panel"))
+                    .with_system(program_files::file_drop_system.system())
+                    .with_system(history::undo_redo_keys_system.system())
+                    .with_system(clipboard::clipboard_keys_system.system())
                     .with_system(on_card_drop_system.system())
                     .with_system(update_lane_names_system.system())
                     .with_system(compiler_system.system())
+                    .with_system(type_check::type_check_system.system())
+                    .with_system(diagnostics::collect_diagnostics_system.system())
+                    .with_system(diagnostics::problems_ui_system.system())
+                    .with_system(sandbox::sandbox_ui_system.system())
+                    .with_system(program_diff::program_diff_ui_system.system())
+                    // central panels go after the side panels
+                    .with_system(source_view::source_view_system.system().after("left-panel"))
                     .with_system(compiler_result_system.system())
                     .with_system(remote_compile_result_system.system())
                     .with_system(editor_ui_system.system()),
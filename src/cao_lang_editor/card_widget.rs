@@ -8,27 +8,56 @@ use crate::cao_lang_client::cao_lang_model::SchemaNode;
 
 use super::LaneNames;
 
-fn lane_node_ui(ui: &mut Ui, node: &mut LaneNode, names: &LaneNames) {
-    match node {
-        LaneNode::LaneName(ref mut ln) => {
-            egui::ComboBox::from_label("Lane")
-                .selected_text(ln.as_str())
-                .show_ui(ui, |ui| {
-                    for name in names.0.iter() {
-                        ui.selectable_value(ln, name.clone(), name);
-                    }
-                });
+/// Returns the index of the referenced lane if the user wants to go to it
+fn lane_node_ui(ui: &mut Ui, node: &mut LaneNode, names: &LaneNames) -> Option<usize> {
+    let mut goto = None;
+    ui.horizontal(|ui| {
+        match node {
+            LaneNode::LaneName(ref mut ln) => {
+                egui::ComboBox::from_label("Lane")
+                    .selected_text(ln.as_str())
+                    .show_ui(ui, |ui| {
+                        for name in names.0.iter() {
+                            ui.selectable_value(ln, name.clone(), name);
+                        }
+                    });
+            }
+            LaneNode::LaneId(i) => {
+                egui::ComboBox::from_label("Lane")
+                    .selected_text(names.0.get(*i).map(|x| x.as_str()).unwrap_or(""))
+                    .show_ui(ui, |ui| {
+                        for (j, name) in names.0.iter().enumerate() {
+                            ui.selectable_value(i, j, name);
+                        }
+                    });
+            }
         }
-        LaneNode::LaneId(i) => {
-            egui::ComboBox::from_label("Lane")
-                .selected_text(names.0.get(*i).map(|x| x.as_str()).unwrap_or(""))
-                .show_ui(ui, |ui| {
-                    for (j, name) in names.0.iter().enumerate() {
-                        ui.selectable_value(i, j, name);
-                    }
-                });
+        let target = match node {
+            LaneNode::LaneName(name) => names.0.iter().position(|x| x == name),
+            LaneNode::LaneId(i) => Some(*i).filter(|i| *i < names.0.len()),
+        };
+        if ui
+            .add(egui::Button::new("➡").enabled(target.is_some()))
+            .on_hover_text("Go to lane")
+            .clicked()
+        {
+            goto = target;
         }
-    }
+        let mut by_name = matches!(node, LaneNode::LaneName(_));
+        if ui
+            .checkbox(&mut by_name, "by name")
+            .on_hover_text("Reference the lane by its name instead of its position")
+            .changed()
+        {
+            *node = match node {
+                LaneNode::LaneName(_) => LaneNode::LaneId(target.unwrap_or_default()),
+                LaneNode::LaneId(i) => {
+                    LaneNode::LaneName(names.0.get(*i).cloned().unwrap_or_default())
+                }
+            };
+        }
+    });
+    goto
 }
 
 fn variable_widget(ui: &mut Ui, label: impl Into<Label>, var: &mut VarNode) {
@@ -43,16 +72,43 @@ fn variable_widget(ui: &mut Ui, label: impl Into<Label>, var: &mut VarNode) {
     });
 }
 
-pub fn schema_card_ui(ui: &mut Ui, card: &SchemaNode) {
+fn schema_node_tooltip(ui: &mut Ui, card: &SchemaNode) {
+    ui.label(egui::Label::new(format!("{} ({})", card.name, card.ty)).strong());
+    egui::Grid::new("schema-node-tooltip").show(ui, |ui| {
+        for (label, list) in [
+            ("Inputs", &card.input),
+            ("Outputs", &card.output),
+            ("Properties", &card.properties),
+        ]
+        .iter()
+        {
+            ui.label(*label);
+            if list.is_empty() {
+                ui.label("-");
+            } else {
+                ui.label(list.join(", "));
+            }
+            ui.end_row();
+        }
+    });
+}
+
+/// `unsupported` cards are greyed out with the reason
+pub fn schema_card_ui(ui: &mut Ui, card: &SchemaNode, unsupported: Option<&str>) {
     let where_to_put_background = ui.painter().add(Shape::Noop);
     let response = ui
         .scope(|ui| {
+            ui.set_enabled(unsupported.is_none());
             ui.heading(&card.name);
             ui.horizontal_wrapped(|ui| {
                 ui.label(&card.description);
             });
+            if let Some(reason) = unsupported {
+                ui.label(egui::Label::new(reason).italics());
+            }
         })
-        .response;
+        .response
+        .on_hover_ui(|ui| schema_node_tooltip(ui, card));
 
     let style = ui.visuals().widgets.inactive;
     let rect = response.rect;
@@ -73,21 +129,35 @@ pub fn card_ui(
     card: &mut Card,
     names: &LaneNames,
     error: Option<&str>,
+    type_error: Option<&str>,
     open: &mut bool,
+    goto_lane: &mut Option<usize>,
+    selected: bool,
+    running: bool,
+    select_clicked: &mut bool,
 ) -> Response {
     let where_to_put_background = ui.painter().add(Shape::Noop);
 
     let response = ui
         .scope(|ui| {
             ui.horizontal(|ui| {
+                if ui
+                    .selectable_label(selected, "▣")
+                    .on_hover_text("Select, shift+click selects a range, alt+drag sweeps one")
+                    .clicked()
+                {
+                    *select_clicked = true;
+                }
                 let heading = egui::Label::new(card.name());
                 let heading = if error.is_some() {
                     heading.background_color(egui::Color32::RED).strong()
+                } else if type_error.is_some() {
+                    heading.background_color(egui::Color32::from_rgb(200, 120, 0))
                 } else {
                     heading
                 };
                 let heading = ui.heading(heading);
-                if let Some(error) = error {
+                if let Some(error) = error.or(type_error) {
                     heading.on_hover_text(error);
                 }
                 if ui.small_button("🗑").clicked() {
@@ -97,7 +167,7 @@ pub fn card_ui(
             match card {
                 Card::ForEach { variable, lane } => {
                     variable_widget(ui, "Variable", variable);
-                    lane_node_ui(ui, lane, names)
+                    *goto_lane = lane_node_ui(ui, lane, names).or(*goto_lane);
                 }
                 Card::SetGlobalVar(var) | Card::ReadVar(var) | Card::SetVar(var) => {
                     variable_widget(ui, "Variable", var);
@@ -122,15 +192,17 @@ pub fn card_ui(
                 }
                 Card::IfElse { then, r#else } => {
                     ui.label("then");
-                    lane_node_ui(ui, then, names);
+                    *goto_lane = lane_node_ui(ui, then, names).or(*goto_lane);
                     ui.label("else");
-                    lane_node_ui(ui, r#else, names);
+                    *goto_lane = lane_node_ui(ui, r#else, names).or(*goto_lane);
                 }
                 Card::IfTrue(node)
                 | Card::IfFalse(node)
                 | Card::Jump(node)
                 | Card::Repeat(node)
-                | Card::While(node) => lane_node_ui(ui, node, names),
+                | Card::While(node) => {
+                    *goto_lane = lane_node_ui(ui, node, names).or(*goto_lane);
+                }
                 // empty bodied items
                 Card::Pass
                 | Card::SetProperty
@@ -161,13 +233,23 @@ pub fn card_ui(
 
     let style = ui.visuals().widgets.inactive;
     let rect = response.rect;
+    let stroke = if selected {
+        ui.visuals().selection.stroke
+    } else {
+        style.bg_stroke
+    };
 
     ui.painter().set(
         where_to_put_background,
         Shape::Rect {
             corner_radius: style.corner_radius,
-            fill: style.bg_fill,
-            stroke: style.bg_stroke,
+            // the card the sandbox run stopped before
+            fill: if running {
+                ui.visuals().selection.bg_fill
+            } else {
+                style.bg_fill
+            },
+            stroke,
             rect,
         },
     );
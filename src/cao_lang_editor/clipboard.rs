@@ -0,0 +1,235 @@
+//! Card selection and clipboard operations
+//!
+//! Cards are copied to the system clipboard as a json list of [Card]s. Pasting also accepts
+//! cards in the [text format](super::text_format).
+
+use std::ops::RangeInclusive;
+
+use bevy::{input::Input, prelude::*};
+use bevy_egui::{EguiClipboard, EguiContext};
+use cao_lang::compiler::{CaoIr, Card, Lane, LaneNode};
+
+use super::{
+    history::{EditCommand, EditHistory},
+    text_format, CurrentProgram,
+};
+
+/// Cards `start..=end` of a lane, `start` is where the selection began so it may be after `end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CardRange {
+    pub lane: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Default)]
+pub struct CardSelection(pub Option<CardRange>);
+
+impl CardRange {
+    pub fn single(lane: usize, card: usize) -> Self {
+        Self {
+            lane,
+            start: card,
+            end: card,
+        }
+    }
+
+    pub fn cards(&self) -> RangeInclusive<usize> {
+        self.start.min(self.end)..=self.start.max(self.end)
+    }
+}
+
+impl CardSelection {
+    /// Select the card, shift extends the selection within the lane
+    pub fn click(&mut self, lane: usize, card: usize, shift: bool) {
+        self.0 = match self.0 {
+            Some(range) if shift && range.lane == lane => Some(CardRange { end: card, ..range }),
+            Some(range) if range == CardRange::single(lane, card) => None,
+            _ => Some(CardRange::single(lane, card)),
+        };
+    }
+
+    /// The selection, if it still fits the program
+    pub fn valid(&self, ir: &CaoIr) -> Option<CardRange> {
+        self.0.filter(|range| {
+            ir.lanes
+                .get(range.lane)
+                .map(|lane| *range.cards().end() < lane.cards.len())
+                .unwrap_or(false)
+        })
+    }
+
+    pub fn contains(&self, lane: usize, card: usize) -> bool {
+        self.0
+            .map(|range| range.lane == lane && range.cards().contains(&card))
+            .unwrap_or(false)
+    }
+}
+
+fn selected_cards<'a>(ir: &'a CaoIr, range: &CardRange) -> &'a [Card] {
+    &ir.lanes[range.lane].cards[range.cards()]
+}
+
+pub fn copy_text(ir: &CaoIr, range: &CardRange) -> Option<String> {
+    serde_json::to_string(selected_cards(ir, range))
+        .map_err(|err| error!("Failed to serialize cards {:?}", err))
+        .ok()
+}
+
+/// Cards from the clipboard's text
+pub fn parse_cards(text: &str) -> Option<Vec<Card>> {
+    if let Ok(cards) = serde_json::from_str(text) {
+        return Some(cards);
+    }
+    // cards without a lane header belong to an unnamed lane
+    let text = if text.trim_start().starts_with("lane") {
+        text.to_string()
+    } else {
+        format!("lane:\n{}", text)
+    };
+    match text_format::from_text(text.as_str()) {
+        Ok(ir) => Some(ir.lanes.into_iter().flat_map(|l| l.cards).collect()),
+        Err(err) => {
+            debug!("Clipboard does not hold cards: {}", err);
+            None
+        }
+    }
+}
+
+fn remove_range(ir: &CaoIr, range: &CardRange) -> Vec<EditCommand> {
+    // back to front so the indices stay valid
+    range
+        .cards()
+        .rev()
+        .map(|index| EditCommand::RemoveCard {
+            lane: range.lane,
+            index,
+            card: ir.lanes[range.lane].cards[index].clone(),
+        })
+        .collect()
+}
+
+pub fn cut_command(ir: &CaoIr, range: &CardRange) -> EditCommand {
+    EditCommand::Batch(remove_range(ir, range))
+}
+
+/// Insert the cards after the selection, or at the end of the first lane
+///
+/// Returns the command and the range of the pasted cards
+pub fn paste_command(
+    ir: &CaoIr,
+    selection: Option<CardRange>,
+    cards: Vec<Card>,
+) -> Option<(EditCommand, CardRange)> {
+    if cards.is_empty() || ir.lanes.is_empty() {
+        return None;
+    }
+    let (lane, at) = match selection {
+        Some(range) => (range.lane, range.cards().end() + 1),
+        None => (0, ir.lanes[0].cards.len()),
+    };
+    let pasted = CardRange {
+        lane,
+        start: at,
+        end: at + cards.len() - 1,
+    };
+    let commands = cards
+        .into_iter()
+        .enumerate()
+        .map(|(i, card)| EditCommand::InsertCard {
+            lane,
+            index: at + i,
+            card,
+        })
+        .collect();
+    Some((EditCommand::Batch(commands), pasted))
+}
+
+fn unique_lane_name(ir: &CaoIr, base: &str) -> String {
+    let taken = |name: &str| ir.lanes.iter().any(|l| l.name.as_deref() == Some(name));
+    if !taken(base) {
+        return base.to_string();
+    }
+    (2..)
+        .map(|i| format!("{} {}", base, i))
+        .find(|name| !taken(name))
+        .unwrap()
+}
+
+pub fn duplicate_lane_command(ir: &CaoIr, index: usize) -> EditCommand {
+    let mut lane = ir.lanes[index].clone();
+    let name = lane.name.as_deref().unwrap_or("Lane").to_string();
+    lane.name = Some(unique_lane_name(ir, format!("{} copy", name).as_str()));
+    // appended, inserting would shift the lanes referenced by index
+    EditCommand::AddLane {
+        index: ir.lanes.len(),
+        lane,
+    }
+}
+
+/// Move the selected cards into a new lane and jump to it in their place
+pub fn extract_command(ir: &CaoIr, range: &CardRange) -> EditCommand {
+    let name = unique_lane_name(ir, "Extracted");
+    let mut lane = Lane::default().with_name(name.as_str());
+    lane.cards = selected_cards(ir, range).to_vec();
+    let mut commands = remove_range(ir, range);
+    commands.push(EditCommand::AddLane {
+        index: ir.lanes.len(),
+        lane,
+    });
+    commands.push(EditCommand::InsertCard {
+        lane: range.lane,
+        index: *range.cards().start(),
+        card: Card::Jump(LaneNode::LaneName(name)),
+    });
+    EditCommand::Batch(commands)
+}
+
+/// Ctrl+C, Ctrl+X and Ctrl+V of the selected cards, Escape clears the selection
+pub fn clipboard_keys_system(
+    egui_ctx: ResMut<EguiContext>,
+    keys: Res<Input<KeyCode>>,
+    mut clipboard: ResMut<EguiClipboard>,
+    mut selection: ResMut<CardSelection>,
+    mut history: ResMut<EditHistory>,
+    mut ir: ResMut<CurrentProgram>,
+) {
+    if egui_ctx.ctx().wants_keyboard_input() {
+        // text fields handle their own shortcuts
+        return;
+    }
+    if keys.just_pressed(KeyCode::Escape) {
+        selection.0 = None;
+        return;
+    }
+    if !keys.pressed(KeyCode::LControl) && !keys.pressed(KeyCode::RControl) {
+        return;
+    }
+    let range = selection.valid(&ir.0);
+    if keys.just_pressed(KeyCode::C) || keys.just_pressed(KeyCode::X) {
+        let range = match range {
+            Some(range) => range,
+            None => return,
+        };
+        if let Some(text) = copy_text(&ir.0, &range) {
+            clipboard.set_contents(text.as_str());
+        }
+        if keys.just_pressed(KeyCode::X) {
+            let command = cut_command(&ir.0, &range);
+            history.apply(&mut ir.0, command);
+            selection.0 = None;
+        }
+    } else if keys.just_pressed(KeyCode::V) {
+        let cards = match clipboard
+            .get_contents()
+            .and_then(|t| parse_cards(t.as_str()))
+        {
+            Some(cards) => cards,
+            None => return,
+        };
+        if let Some((command, pasted)) = paste_command(&ir.0, range, cards) {
+            history.apply(&mut ir.0, command);
+            selection.0 = Some(pasted);
+        }
+    }
+}
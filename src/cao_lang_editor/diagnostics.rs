@@ -0,0 +1,267 @@
+//! Problems panel, collecting the local, remote and stack type diagnostics of the current program
+
+use std::convert::TryFrom;
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContext};
+use cao_lang::compiler::LaneNode;
+
+use super::{
+    type_check::{node_matches, StackTypeErrors},
+    CurrentLocalCompileError, CurrentProgram, CurrentRemoteCompileError, EditorFocus,
+};
+use crate::cao_lang_client::{cao_lang_model::SchemaNode, CaoLangSchema, SchemaChanged};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticSource {
+    Local,
+    Remote,
+    StackTypes,
+    Schema,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub source: DiagnosticSource,
+    pub message: String,
+    pub lane: Option<usize>,
+    pub card: Option<usize>,
+}
+
+#[derive(Default)]
+pub struct Diagnostics(pub Vec<Diagnostic>);
+
+/// Cards the server's schema no longer provides
+#[derive(Default)]
+pub struct RemovedSchemaCards(pub Vec<SchemaNode>);
+
+impl DiagnosticSource {
+    pub fn label(self) -> &'static str {
+        match self {
+            DiagnosticSource::Local => "local",
+            DiagnosticSource::Remote => "server",
+            DiagnosticSource::StackTypes => "stack types",
+            DiagnosticSource::Schema => "schema",
+        }
+    }
+}
+
+/// Find a `lane <index|"name"> card <index>` location in a server message
+pub fn parse_location(msg: &str) -> Option<(LaneNode, Option<usize>)> {
+    let lower = msg.to_ascii_lowercase();
+    let start = lower.find("lane ")? + "lane ".len();
+    let rest = &msg[start..];
+
+    let (lane, rest) = if let Some(quoted) = rest.strip_prefix('"') {
+        let end = quoted.find('"')?;
+        (
+            LaneNode::LaneName(quoted[..end].to_string()),
+            &quoted[end + 1..],
+        )
+    } else {
+        let digits = rest.trim_start_matches('#');
+        let len = digits
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or_else(|| digits.len());
+        (
+            LaneNode::LaneId(digits[..len].parse().ok()?),
+            &digits[len..],
+        )
+    };
+
+    let rest = rest.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+    let card = if rest.to_ascii_lowercase().starts_with("card ") {
+        let digits = rest["card ".len()..].trim_start_matches('#');
+        let len = digits
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or_else(|| digits.len());
+        digits[..len].parse().ok()
+    } else {
+        None
+    };
+    Some((lane, card))
+}
+
+fn lane_index(ir: &CurrentProgram, lane: &LaneNode) -> Option<usize> {
+    match lane {
+        LaneNode::LaneId(i) => Some(*i).filter(|i| *i < ir.0.lanes.len()),
+        LaneNode::LaneName(name) => {
+            ir.0.lanes
+                .iter()
+                .position(|l| l.name.as_deref() == Some(name.as_str()))
+        }
+    }
+}
+
+pub fn schema_changed_system(
+    mut events: EventReader<SchemaChanged>,
+    schema: Res<CaoLangSchema>,
+    mut removed: ResMut<RemovedSchemaCards>,
+) {
+    let mut changed = false;
+    for event in events.iter() {
+        changed = true;
+        for node in event.removed.iter() {
+            warn!("Card {} was removed from the schema", node.name);
+            if !removed.0.contains(node) {
+                removed.0.push(node.clone());
+            }
+        }
+    }
+    if changed {
+        // cards may come back, e.g. when switching back to the previous server
+        removed.0.retain(|node| {
+            !schema
+                .0
+                .iter()
+                .any(|n| n.ty == node.ty && n.name == node.name)
+        });
+    }
+}
+
+pub fn collect_diagnostics_system(
+    ir: Res<CurrentProgram>,
+    local: Res<CurrentLocalCompileError>,
+    remote: Res<CurrentRemoteCompileError>,
+    stack_types: Res<StackTypeErrors>,
+    removed: Res<RemovedSchemaCards>,
+    mut diagnostics: ResMut<Diagnostics>,
+) {
+    if !(ir.is_changed()
+        || local.is_changed()
+        || remote.is_changed()
+        || stack_types.is_changed()
+        || removed.is_changed())
+    {
+        return;
+    }
+    let mut result = Vec::new();
+    if let Some(err) = local.0.as_ref() {
+        let (lane, card) = match err.loc.as_ref() {
+            Some((lane, card)) => (lane_index(&ir, lane), usize::try_from(*card).ok()),
+            None => (None, None),
+        };
+        result.push(Diagnostic {
+            severity: Severity::Error,
+            source: DiagnosticSource::Local,
+            message: err.payload.to_string(),
+            lane,
+            card,
+        });
+    }
+    if let Some(err) = remote.0.as_ref() {
+        // validation errors are reported one per line
+        for line in err.detail.lines().filter(|l| !l.trim().is_empty()) {
+            let (lane, card) = match parse_location(line) {
+                Some((lane, card)) => (lane_index(&ir, &lane), card),
+                None => (None, None),
+            };
+            let severity = if line.to_ascii_lowercase().starts_with("warning") {
+                Severity::Warning
+            } else {
+                Severity::Error
+            };
+            result.push(Diagnostic {
+                severity,
+                source: DiagnosticSource::Remote,
+                message: line.trim().to_string(),
+                lane,
+                card,
+            });
+        }
+    }
+    let mut stack_errors: Vec<_> = stack_types.0.iter().collect();
+    stack_errors.sort_by_key(|(loc, _)| **loc);
+    result.extend(
+        stack_errors
+            .into_iter()
+            .map(|((lane, card), msg)| Diagnostic {
+                severity: Severity::Warning,
+                source: DiagnosticSource::StackTypes,
+                message: msg.clone(),
+                lane: Some(*lane),
+                card: Some(*card),
+            }),
+    );
+    for (lane_index, lane) in ir.0.lanes.iter().enumerate() {
+        for (card_index, card) in lane.cards.iter().enumerate() {
+            if let Some(node) = removed.0.iter().find(|node| node_matches(node, card)) {
+                result.push(Diagnostic {
+                    severity: Severity::Warning,
+                    source: DiagnosticSource::Schema,
+                    message: format!("{} was removed from the server's schema", node.name),
+                    lane: Some(lane_index),
+                    card: Some(card_index),
+                });
+            }
+        }
+    }
+    diagnostics.0 = result;
+}
+
+pub fn problems_ui_system(
+    egui_ctx: ResMut<EguiContext>, // exclusive ownership
+    ir: Res<CurrentProgram>,
+    diagnostics: Res<Diagnostics>,
+    mut focus: ResMut<EditorFocus>,
+) {
+    let errors = diagnostics
+        .0
+        .iter()
+        .filter(|d| d.severity == Severity::Error)
+        .count();
+    let title = format!(
+        "Problems ({} errors, {} warnings)",
+        errors,
+        diagnostics.0.len() - errors
+    );
+    egui::Window::new(title)
+        .id(egui::Id::new("cao-lang-problems"))
+        .default_open(false)
+        .scroll(true)
+        .show(egui_ctx.ctx(), |ui| {
+            if diagnostics.0.is_empty() {
+                ui.label("No problems");
+            }
+            for d in diagnostics.0.iter() {
+                let icon = match d.severity {
+                    Severity::Error => "❌",
+                    Severity::Warning => "⚠",
+                };
+                let location = match (d.lane, d.card) {
+                    (Some(lane), card) => {
+                        let name =
+                            ir.0.lanes
+                                .get(lane)
+                                .and_then(|l| l.name.as_deref())
+                                .unwrap_or("");
+                        match card {
+                            Some(card) => format!("{} #{} card {}", name, lane, card),
+                            None => format!("{} #{}", name, lane),
+                        }
+                    }
+                    (None, _) => "program".to_string(),
+                };
+                let text = format!(
+                    "{} [{}] {}: {}",
+                    icon,
+                    d.source.label(),
+                    location,
+                    d.message
+                );
+                let response = ui.selectable_label(false, text);
+                if let Some(lane) = d.lane {
+                    if response.on_hover_text("Show in the editor").clicked() {
+                        focus.0 = Some((lane, d.card));
+                    }
+                }
+            }
+        });
+}
@@ -0,0 +1,384 @@
+//! Undo/redo history of the edits made to the [CurrentProgram](super::CurrentProgram)
+//!
+//! Property edits happen in place in the card widgets, their commands are recorded after the fact.
+//! Applying a command is idempotent for these, so recording re-applies them harmlessly.
+
+use bevy::{input::Input, prelude::*};
+use bevy_egui::EguiContext;
+use cao_lang::compiler::{CaoIr, Card, Lane, LaneNode};
+
+use super::{lane_refs_mut, CurrentProgram};
+
+/// Maximum number of undoable commands
+const MAX_HISTORY: usize = 256;
+
+#[derive(Debug, Clone)]
+pub enum EditCommand {
+    /// Remove the card at `src` then insert it at `dst`, both `(lane, card)` indices
+    MoveCard {
+        src: (usize, usize),
+        dst: (usize, usize),
+    },
+    InsertCard {
+        lane: usize,
+        index: usize,
+        card: Card,
+    },
+    RemoveCard {
+        lane: usize,
+        index: usize,
+        card: Card,
+    },
+    EditCard {
+        lane: usize,
+        index: usize,
+        before: Card,
+        after: Card,
+    },
+    AddLane {
+        index: usize,
+        lane: Lane,
+    },
+    RemoveLane {
+        index: usize,
+        lane: Lane,
+    },
+    /// Also points the references of the lane to its new name, or to its index if it's unnamed
+    RenameLane {
+        index: usize,
+        before: Option<String>,
+        after: Option<String>,
+    },
+    /// Edits made in the source view replace the whole program
+    Replace {
+        before: CaoIr,
+        after: CaoIr,
+        source: ReplaceSource,
+    },
+    /// Applied in order, undone as one
+    Batch(Vec<EditCommand>),
+}
+
+/// Origin of a [EditCommand::Replace], only replacements of the same text session are merged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplaceSource {
+    /// Typing in the source view, a session ends when the program is changed elsewhere
+    Text { session: u32 },
+    /// Accepting every change of a compared program
+    AcceptAll,
+}
+
+#[derive(Default)]
+pub struct EditHistory {
+    undo: Vec<EditCommand>,
+    redo: Vec<EditCommand>,
+}
+
+/// Point the references of lane `index` named `from` to `to`
+///
+/// References by index are used for unnamed lanes.
+fn rename_lane_refs(ir: &mut CaoIr, index: usize, from: Option<&str>, to: Option<&str>) {
+    for card in ir.lanes.iter_mut().flat_map(|l| l.cards.iter_mut()) {
+        for node in lane_refs_mut(card) {
+            let refers = match (&*node, from) {
+                (LaneNode::LaneName(name), Some(from)) => name == from,
+                (LaneNode::LaneId(id), None) => *id == index,
+                _ => false,
+            };
+            if refers {
+                *node = match to {
+                    Some(to) => LaneNode::LaneName(to.to_string()),
+                    None => LaneNode::LaneId(index),
+                };
+            }
+        }
+    }
+}
+
+/// Point the references by index to `map(index)`
+fn renumber_lane_refs(ir: &mut CaoIr, map: impl Fn(usize) -> usize) {
+    for card in ir.lanes.iter_mut().flat_map(|l| l.cards.iter_mut()) {
+        for node in lane_refs_mut(card) {
+            if let LaneNode::LaneId(id) = node {
+                *id = map(*id);
+            }
+        }
+    }
+}
+
+impl EditCommand {
+    pub fn inverse(&self) -> Self {
+        match self.clone() {
+            EditCommand::MoveCard { src, dst } => EditCommand::MoveCard { src: dst, dst: src },
+            EditCommand::InsertCard { lane, index, card } => {
+                EditCommand::RemoveCard { lane, index, card }
+            }
+            EditCommand::RemoveCard { lane, index, card } => {
+                EditCommand::InsertCard { lane, index, card }
+            }
+            EditCommand::EditCard {
+                lane,
+                index,
+                before,
+                after,
+            } => EditCommand::EditCard {
+                lane,
+                index,
+                before: after,
+                after: before,
+            },
+            EditCommand::AddLane { index, lane } => EditCommand::RemoveLane { index, lane },
+            EditCommand::RemoveLane { index, lane } => EditCommand::AddLane { index, lane },
+            EditCommand::RenameLane {
+                index,
+                before,
+                after,
+            } => EditCommand::RenameLane {
+                index,
+                before: after,
+                after: before,
+            },
+            EditCommand::Replace {
+                before,
+                after,
+                source,
+            } => EditCommand::Replace {
+                before: after,
+                after: before,
+                source,
+            },
+            EditCommand::Batch(commands) => {
+                EditCommand::Batch(commands.iter().rev().map(|c| c.inverse()).collect())
+            }
+        }
+    }
+
+    /// Returns false if the command does not fit the program, in which case it is not applied
+    pub fn apply(&self, ir: &mut CaoIr) -> bool {
+        let lanes = &mut ir.lanes;
+        match self {
+            EditCommand::MoveCard { src, dst } => {
+                if lanes
+                    .get(src.0)
+                    .map(|l| l.cards.len() <= src.1)
+                    .unwrap_or(true)
+                    || dst.0 >= lanes.len()
+                {
+                    return false;
+                }
+                let card = lanes[src.0].cards.remove(src.1);
+                if lanes[dst.0].cards.len() < dst.1 {
+                    // undo the removal
+                    lanes[src.0].cards.insert(src.1, card);
+                    return false;
+                }
+                lanes[dst.0].cards.insert(dst.1, card);
+            }
+            EditCommand::InsertCard { lane, index, card } => {
+                match lanes.get_mut(*lane).filter(|l| l.cards.len() >= *index) {
+                    Some(l) => l.cards.insert(*index, card.clone()),
+                    None => return false,
+                }
+            }
+            EditCommand::RemoveCard { lane, index, .. } => {
+                match lanes.get_mut(*lane).filter(|l| l.cards.len() > *index) {
+                    Some(l) => {
+                        l.cards.remove(*index);
+                    }
+                    None => return false,
+                }
+            }
+            EditCommand::EditCard {
+                lane, index, after, ..
+            } => match lanes.get_mut(*lane).and_then(|l| l.cards.get_mut(*index)) {
+                Some(card) => *card = after.clone(),
+                None => return false,
+            },
+            EditCommand::AddLane { index, lane } => {
+                let (index, end) = (*index, lanes.len());
+                if index > end {
+                    return false;
+                }
+                // the inverse of the renumbering of RemoveLane, before inserting as the lane's own
+                // references are already numbered for the program it's added to
+                renumber_lane_refs(ir, |id| match id {
+                    id if id == end => index,
+                    id if id >= index && id < end => id + 1,
+                    id => id,
+                });
+                ir.lanes.insert(index, lane.clone());
+            }
+            EditCommand::RemoveLane { index, .. } => {
+                let index = *index;
+                if index >= lanes.len() {
+                    return false;
+                }
+                let last = lanes.len() - 1;
+                lanes.remove(index);
+                // references to the removed lane point past the last lane, so adding it back
+                // restores them
+                renumber_lane_refs(ir, |id| match id {
+                    id if id == index => last,
+                    id if id > index && id <= last => id - 1,
+                    id => id,
+                });
+            }
+            EditCommand::RenameLane {
+                index,
+                before,
+                after,
+            } => {
+                if *index >= lanes.len() {
+                    return false;
+                }
+                // lane names must stay unique
+                if after.is_some()
+                    && lanes
+                        .iter()
+                        .enumerate()
+                        .any(|(i, lane)| i != *index && lane.name == *after)
+                {
+                    return false;
+                }
+                lanes[*index].name = after.clone();
+                if before != after {
+                    rename_lane_refs(ir, *index, before.as_deref(), after.as_deref());
+                }
+            }
+            EditCommand::Replace { after, .. } => *ir = after.clone(),
+            EditCommand::Batch(commands) => {
+                for (i, command) in commands.iter().enumerate() {
+                    if !command.apply(ir) {
+                        // roll back the applied commands
+                        for applied in commands[..i].iter().rev() {
+                            applied.inverse().apply(ir);
+                        }
+                        return false;
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// Fold `next` into this command if both edit the same property
+    fn merge(&mut self, next: &EditCommand) -> bool {
+        match (self, next) {
+            (
+                EditCommand::EditCard {
+                    lane, index, after, ..
+                },
+                EditCommand::EditCard {
+                    lane: l,
+                    index: i,
+                    after: next,
+                    ..
+                },
+            ) if lane == l && index == i => {
+                *after = next.clone();
+                true
+            }
+            (
+                EditCommand::Replace { after, source, .. },
+                EditCommand::Replace {
+                    after: next,
+                    source: s,
+                    ..
+                },
+            ) if matches!(source, ReplaceSource::Text { .. }) && source == s => {
+                *after = next.clone();
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl EditHistory {
+    /// Apply the command and record it
+    pub fn apply(&mut self, ir: &mut CaoIr, command: EditCommand) {
+        if !command.apply(ir) {
+            warn!("Discarding edit {:?}, it does not fit the program", command);
+            return;
+        }
+        self.redo.clear();
+        if let Some(last) = self.undo.last_mut() {
+            if last.merge(&command) {
+                return;
+            }
+        }
+        self.undo.push(command);
+        if self.undo.len() > MAX_HISTORY {
+            self.undo.remove(0);
+        }
+    }
+
+    pub fn undo(&mut self, ir: &mut CaoIr) -> bool {
+        match self.undo.pop() {
+            Some(command) => {
+                if !command.inverse().apply(ir) {
+                    warn!(
+                        "Discarding undo of {:?}, it does not fit the program",
+                        command
+                    );
+                    return false;
+                }
+                self.redo.push(command);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn redo(&mut self, ir: &mut CaoIr) -> bool {
+        match self.redo.pop() {
+            Some(command) => {
+                if !command.apply(ir) {
+                    warn!(
+                        "Discarding redo of {:?}, it does not fit the program",
+                        command
+                    );
+                    return false;
+                }
+                self.undo.push(command);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Forget the history, when the program is replaced as a whole
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+}
+
+/// Ctrl+Z undoes, Ctrl+Shift+Z redoes
+pub fn undo_redo_keys_system(
+    egui_ctx: ResMut<EguiContext>,
+    keys: Res<Input<KeyCode>>,
+    mut history: ResMut<EditHistory>,
+    mut ir: ResMut<CurrentProgram>,
+) {
+    if !keys.just_pressed(KeyCode::Z) || egui_ctx.ctx().wants_keyboard_input() {
+        // text fields handle their own shortcuts
+        return;
+    }
+    if !keys.pressed(KeyCode::LControl) && !keys.pressed(KeyCode::RControl) {
+        return;
+    }
+    if keys.pressed(KeyCode::LShift) || keys.pressed(KeyCode::RShift) {
+        history.redo(&mut ir.0);
+    } else {
+        history.undo(&mut ir.0);
+    }
+}
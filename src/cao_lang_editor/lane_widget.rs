@@ -1,12 +1,30 @@
-use std::mem;
-
 use bevy_egui::{
     egui::{self, Id, Response},
     EguiContext,
 };
-use cao_lang::compiler::Lane;
+use cao_lang::compiler::{Lane, LaneNode};
+
+use super::{
+    card_widget,
+    clipboard::{CardRange, CardSelection},
+    drag_src, drop_target,
+    history::EditCommand,
+    type_check::StackTypeErrors,
+    CurrentLocalCompileError, LaneIndex, LaneNames,
+};
 
-use super::{card_widget, drag_src, drop_target, CurrentLocalCompileError, LaneIndex, LaneNames};
+/// Requests of the lane windows, handled by the editor after drawing them
+#[derive(Default)]
+pub struct LaneActions {
+    pub goto_lane: Option<usize>,
+    /// `(lane, card, shift)`
+    pub select: Option<(usize, usize, bool)>,
+    /// cards swept by an `Alt`+drag
+    pub box_select: Option<CardRange>,
+    pub duplicate: Option<usize>,
+    /// `(lane, new name)`, sent once the name edit loses focus
+    pub rename: Option<(usize, Option<String>)>,
+}
 
 pub fn lane_ui(
     lane: &mut Lane,
@@ -17,18 +35,26 @@ pub fn lane_ui(
     dst_col_row: &mut Option<(LaneIndex, usize)>,
     dropped: &mut bool,
     compile_error: &CurrentLocalCompileError,
+    type_errors: &StackTypeErrors,
+    focused_card: Option<usize>,
+    running_card: Option<usize>,
     open: &mut bool,
+    edits: &mut Vec<EditCommand>,
+    selection: &CardSelection,
+    actions: &mut LaneActions,
+    name_draft: &mut Option<(usize, String)>,
 ) -> Option<Response> {
-    let mut name = lane.name.as_mut().map(|x| mem::take(x)).unwrap_or_default();
+    let name = lane.name.clone().unwrap_or_default();
     let has_lane_error = compile_error
         .0
         .as_ref()
         .and_then(|x| x.loc.as_ref())
-        .map(|x| match x.0 {
-            cao_lang::compiler::LaneNode::LaneName(_) => todo!(),
-            cao_lang::compiler::LaneNode::LaneId(x) => x == lane_index,
+        .map(|x| match &x.0 {
+            LaneNode::LaneName(n) => *n == name,
+            LaneNode::LaneId(x) => *x == lane_index,
         })
         .unwrap_or(false);
+    let lane_id = lane_index;
     let lane_index = LaneIndex::LaneId(lane_index);
     let response = egui::Window::new(name.as_str())
         .scroll(true)
@@ -42,13 +68,46 @@ pub fn lane_ui(
                     ui,
                     true,
                     |ui| {
+                        let mut taken = false;
                         ui.horizontal(|ui| {
                             ui.label("Name: ");
-                            if ui.text_edit_singleline(&mut name).changed() {
-                                lane.name = Some(Default::default());
+                            let mut draft = match name_draft.as_ref() {
+                                Some((i, draft)) if *i == lane_id => draft.clone(),
+                                _ => name.clone(),
+                            };
+                            let response = ui.text_edit_singleline(&mut draft);
+                            // names are unique, references to the lane would be ambiguous
+                            taken = draft != name
+                                && !draft.is_empty()
+                                && lane_names.0.iter().any(|n| *n == draft);
+                            if response.lost_focus() {
+                                if matches!(name_draft, Some((i, _)) if *i == lane_id) {
+                                    *name_draft = None;
+                                }
+                                if draft != name && !taken {
+                                    actions.rename =
+                                        Some((lane_id, Some(draft).filter(|n| !n.is_empty())));
+                                }
+                            } else if response.has_focus() {
+                                *name_draft = Some((lane_id, draft));
+                            }
+                            if ui
+                                .small_button("⧉")
+                                .on_hover_text("Duplicate lane")
+                                .clicked()
+                            {
+                                actions.duplicate = Some(lane_id);
                             }
                         });
+                        if taken {
+                            ui.colored_label(egui::color::Rgba::RED, "Another lane has this name");
+                        }
 
+                        // Alt+drag sweeps a selection instead of dragging the card it starts on
+                        let box_selecting =
+                            ui.input().modifiers.alt && !ui.memory().is_anything_being_dragged();
+                        let mut box_start = None;
+                        let mut box_end = None;
                         let mut deleted_card_idx = None;
                         for (card_index, card) in lane.cards.iter_mut().enumerate() {
                             let mut is_this_errored = false;
@@ -66,28 +125,83 @@ pub fn lane_ui(
                                     .unwrap()
                             });
                             let mut open = true;
-                            drag_src(ui, id, |ui| {
+                            let mut select_clicked = false;
+                            let before = card.clone();
+                            let mut card_rect = None;
+                            let mut card_body = |ui: &mut egui::Ui| {
                                 let response = card_widget::card_ui(
                                     ui,
                                     card,
                                     lane_names,
                                     error.as_ref().map(|x| x.as_str()),
+                                    type_errors
+                                        .0
+                                        .get(&(lane_id, card_index))
+                                        .map(|x| x.as_str()),
                                     &mut open,
+                                    &mut actions.goto_lane,
+                                    selection.contains(lane_id, card_index),
+                                    running_card == Some(card_index),
+                                    &mut select_clicked,
                                 );
                                 if response.hovered() {
                                     dst_row = card_index
                                 }
-                            });
+                                if focused_card == Some(card_index) {
+                                    response.scroll_to_me(egui::Align::Center);
+                                }
+                                card_rect = Some(response.rect);
+                            };
+                            if box_selecting {
+                                ui.scope(card_body);
+                            } else {
+                                drag_src(ui, id, card_body);
+                            }
+                            if let Some(rect) = card_rect {
+                                let pointer = &ui.input().pointer;
+                                if pointer.press_origin().map_or(false, |p| rect.contains(p)) {
+                                    box_start = Some(card_index);
+                                }
+                                if pointer.hover_pos().map_or(false, |p| rect.contains(p)) {
+                                    box_end = Some(card_index);
+                                }
+                            }
+                            if select_clicked {
+                                let shift = ui.input().modifiers.shift;
+                                actions.select = Some((lane_id, card_index, shift));
+                            }
                             if !open {
                                 deleted_card_idx = Some(card_index);
+                            } else if serde_hashkey::to_key(&before).ok()
+                                != serde_hashkey::to_key(&*card).ok()
+                            {
+                                edits.push(EditCommand::EditCard {
+                                    lane: lane_id,
+                                    index: card_index,
+                                    before,
+                                    after: card.clone(),
+                                });
                             }
 
                             if ui.memory().is_being_dragged(id) {
                                 *src_col_row = Some((lane_index, card_index));
                             }
                         }
-                        if let Some(i) = deleted_card_idx {
-                            lane.cards.remove(i);
+                        if box_selecting && ui.input().pointer.primary_down() {
+                            if let (Some(start), Some(end)) = (box_start, box_end) {
+                                actions.box_select = Some(CardRange {
+                                    lane: lane_id,
+                                    start,
+                                    end,
+                                });
+                            }
+                        }
+                        if let Some(index) = deleted_card_idx {
+                            edits.push(EditCommand::RemoveCard {
+                                lane: lane_id,
+                                index,
+                                card: lane.cards[index].clone(),
+                            });
                         }
                     },
                     has_lane_error.then(|| {
@@ -110,10 +224,5 @@ pub fn lane_ui(
                 resp
             })
         });
-    if lane.name.is_some() {
-        // restore the lane name
-        lane.name = Some(name);
-    }
-
     response.map(|x| x.response)
 }
@@ -0,0 +1,578 @@
+//! Lists the programs of the user, loads them into the editor and saves the edits
+//!
+//! Saves send the version the edits are based on, if the program was saved elsewhere in the
+//! meantime the server rejects the save and the user may reload, overwrite or merge the program.
+//! Merging compares the edits with the server's version, once the user is done merging the next
+//! save is based on that version.
+
+use bevy::{
+    prelude::*,
+    tasks::{IoTaskPool, Task},
+};
+use bevy_egui::{egui, EguiContext};
+use cao_lang::compiler::CaoIr;
+use futures_lite::future;
+
+use crate::{
+    account::CurrentAuthToken,
+    api_client::{ApiErrorEvent, ApiResult, CaoApiError},
+    cao_lang_client::{
+        self,
+        cao_lang_model::{Program, ProgramSummary},
+        MyPrograms, RefreshMyPrograms,
+    },
+    server_config::ServerConfig,
+};
+
+use super::{history::EditHistory, program_diff::ProgramDiff, CurrentProgram};
+
+/// The server side program loaded into the editor
+pub struct OpenProgram {
+    pub summary: ProgramSummary,
+    /// hash of the last saved IR
+    saved: serde_hashkey::Key,
+}
+
+impl OpenProgram {
+    pub fn is_dirty(&self, ir: &CaoIr) -> bool {
+        serde_hashkey::to_key(ir)
+            .map(|key| key != self.saved)
+            .unwrap_or(true)
+    }
+}
+
+/// The user took what they wanted from the server's version, see [ProgramDiff::merge]
+pub struct MergeFinished {
+    pub summary: ProgramSummary,
+    /// hash of the server's IR
+    pub saved: serde_hashkey::Key,
+}
+
+enum Confirm {
+    /// discard the unsaved edits and open the program
+    Open(ProgramSummary),
+    /// discard the unsaved edits and start a scratch program
+    New,
+    Delete(ProgramSummary),
+}
+
+#[derive(Default)]
+pub struct ProgramBrowser {
+    /// `None` while editing a scratch program
+    pub open: Option<OpenProgram>,
+    /// the last save was rejected, because the program was saved elsewhere
+    conflict: bool,
+    confirm: Option<Confirm>,
+    new_name: String,
+    /// program_id, name
+    renaming: Option<(String, String)>,
+}
+
+struct LoadTask(Task<ApiResult<Program>>);
+/// Fetches the server's version of the open program to compare it with the edits
+struct CompareTask(Task<ApiResult<Program>>);
+struct SaveTask {
+    task: Task<ApiResult<ProgramSummary>>,
+    key: serde_hashkey::Key,
+}
+struct RenameTask(Task<ApiResult<ProgramSummary>>);
+struct DeleteTask {
+    program_id: String,
+    task: Task<ApiResult<()>>,
+}
+
+pub struct ProgramBrowserPlugin;
+
+fn program_label(summary: &ProgramSummary) -> String {
+    format!("{} (v{})", summary.name, summary.version)
+}
+
+fn spawn_save(
+    cmd: &mut Commands,
+    pool: &IoTaskPool,
+    api_url: String,
+    token: String,
+    ir: &CaoIr,
+    target: Result<(String, Option<i64>), String>,
+) {
+    let key = match serde_hashkey::to_key(ir) {
+        Ok(k) => k,
+        Err(err) => {
+            error!("Failed to hash the program {:?}", err);
+            return;
+        }
+    };
+    let ir = ir.clone();
+    let task = match target {
+        Ok((program_id, version)) => pool.spawn(cao_lang_client::save_program(
+            api_url, token, program_id, version, ir,
+        )),
+        Err(name) => pool.spawn(cao_lang_client::save_new_program(api_url, token, name, ir)),
+    };
+    cmd.spawn().insert(SaveTask { task, key });
+}
+
+fn spawn_load(cmd: &mut Commands, pool: &IoTaskPool, api_url: String, token: String, id: String) {
+    let task = pool.spawn(cao_lang_client::fetch_program(api_url, token, id));
+    cmd.spawn().insert(LoadTask(task));
+}
+
+fn spawn_compare(
+    cmd: &mut Commands,
+    pool: &IoTaskPool,
+    api_url: String,
+    token: String,
+    id: String,
+) {
+    let task = pool.spawn(cao_lang_client::fetch_program(api_url, token, id));
+    cmd.spawn().insert(CompareTask(task));
+}
+
+fn confirm_ui(ui: &mut egui::Ui, confirm: &Confirm) -> Option<bool> {
+    let text = match confirm {
+        Confirm::Open(p) => format!("Discard your unsaved changes and open {}?", p.name),
+        Confirm::New => "Discard your unsaved changes?".to_string(),
+        Confirm::Delete(p) => format!("Delete {}? This can not be undone.", p.name),
+    };
+    let mut result = None;
+    ui.colored_label(egui::color::Rgba::RED, text);
+    ui.horizontal(|ui| {
+        if ui.button("Yes").clicked() {
+            result = Some(true);
+        }
+        if ui.button("No").clicked() {
+            result = Some(false);
+        }
+    });
+    result
+}
+
+fn program_browser_ui_system(
+    mut cmd: Commands,
+    egui_ctx: ResMut<EguiContext>, // exclusive ownership
+    mut browser: ResMut<ProgramBrowser>,
+    mut ir: ResMut<CurrentProgram>,
+    mut history: ResMut<EditHistory>,
+    programs: Res<MyPrograms>,
+    token: Res<CurrentAuthToken>,
+    config: Res<ServerConfig>,
+    pool: Res<IoTaskPool>,
+    mut refresh_programs: EventWriter<RefreshMyPrograms>,
+    in_flight: Query<
+        (),
+        Or<(
+            With<LoadTask>,
+            With<CompareTask>,
+            With<SaveTask>,
+            With<RenameTask>,
+            With<DeleteTask>,
+        )>,
+    >,
+) {
+    let browser = &mut *browser;
+    let api_url = config.current().api_base_url.clone();
+    let busy = in_flight.iter().next().is_some();
+    let dirty = browser
+        .open
+        .as_ref()
+        .map(|p| p.is_dirty(&ir.0))
+        .unwrap_or(false);
+
+    egui::Window::new("Programs")
+        .id(egui::Id::new("cao-lang-programs"))
+        .scroll(true)
+        .show(egui_ctx.ctx(), |ui| {
+            let token = match token.0.as_ref() {
+                Some(t) => t.clone(),
+                None => {
+                    ui.label("Log in to save your programs");
+                    return;
+                }
+            };
+
+            match browser.open.as_ref() {
+                Some(open) => {
+                    let mut label = program_label(&open.summary);
+                    if dirty {
+                        label.push_str(" ●");
+                    }
+                    ui.label(label)
+                        .on_hover_text(if dirty { "Unsaved changes" } else { "Saved" });
+                }
+                None => {
+                    ui.label("Scratch program, not saved");
+                }
+            }
+
+            if browser.conflict {
+                ui.colored_label(
+                    egui::color::Rgba::RED,
+                    "The program was saved elsewhere since you opened it",
+                );
+                ui.horizontal(|ui| {
+                    ui.set_enabled(!busy);
+                    if let Some(open) = browser.open.as_ref() {
+                        if ui.button("Reload").clicked() {
+                            spawn_load(
+                                &mut cmd,
+                                &*pool,
+                                api_url.clone(),
+                                token.clone(),
+                                open.summary.program_id.clone(),
+                            );
+                            browser.conflict = false;
+                        }
+                        if ui
+                            .button("Merge")
+                            .on_hover_text("Compare your edits with the saved version")
+                            .clicked()
+                        {
+                            spawn_compare(
+                                &mut cmd,
+                                &*pool,
+                                api_url.clone(),
+                                token.clone(),
+                                open.summary.program_id.clone(),
+                            );
+                        }
+                        if ui.button("Overwrite").clicked() {
+                            let target = Ok((open.summary.program_id.clone(), None));
+                            spawn_save(
+                                &mut cmd,
+                                &*pool,
+                                api_url.clone(),
+                                token.clone(),
+                                &ir.0,
+                                target,
+                            );
+                            browser.conflict = false;
+                        }
+                    }
+                });
+            }
+
+            if let Some(confirm) = browser.confirm.as_ref() {
+                if let Some(accepted) = confirm_ui(ui, confirm) {
+                    let confirm = browser.confirm.take().unwrap();
+                    if accepted {
+                        match confirm {
+                            Confirm::Open(p) => {
+                                spawn_load(
+                                    &mut cmd,
+                                    &*pool,
+                                    api_url.clone(),
+                                    token.clone(),
+                                    p.program_id,
+                                );
+                            }
+                            Confirm::New => {
+                                browser.open = None;
+                                browser.conflict = false;
+                                ir.0 = super::scratch_program();
+                                history.clear();
+                            }
+                            Confirm::Delete(p) => {
+                                let task = pool.spawn(cao_lang_client::delete_program(
+                                    api_url.clone(),
+                                    token.clone(),
+                                    p.program_id.clone(),
+                                ));
+                                cmd.spawn().insert(DeleteTask {
+                                    program_id: p.program_id,
+                                    task,
+                                });
+                            }
+                        }
+                    }
+                }
+                return;
+            }
+
+            ui.horizontal(|ui| {
+                ui.set_enabled(!busy);
+                if ui.button("New").clicked() {
+                    if dirty {
+                        browser.confirm = Some(Confirm::New);
+                    } else {
+                        browser.open = None;
+                        browser.conflict = false;
+                        ir.0 = super::scratch_program();
+                        history.clear();
+                    }
+                }
+                if let Some(open) = browser.open.as_ref() {
+                    if ui
+                        .add(egui::Button::new("Save").enabled(dirty && !browser.conflict))
+                        .clicked()
+                    {
+                        let target =
+                            Ok((open.summary.program_id.clone(), Some(open.summary.version)));
+                        spawn_save(
+                            &mut cmd,
+                            &*pool,
+                            api_url.clone(),
+                            token.clone(),
+                            &ir.0,
+                            target,
+                        );
+                    }
+                    if ui
+                        .button("Compare")
+                        .on_hover_text("Compare your edits with the saved version")
+                        .clicked()
+                    {
+                        spawn_compare(
+                            &mut cmd,
+                            &*pool,
+                            api_url.clone(),
+                            token.clone(),
+                            open.summary.program_id.clone(),
+                        );
+                    }
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.set_enabled(!busy);
+                ui.text_edit_singleline(&mut browser.new_name);
+                let name = browser.new_name.trim().to_string();
+                if ui
+                    .add(egui::Button::new("Save as").enabled(!name.is_empty()))
+                    .clicked()
+                {
+                    spawn_save(
+                        &mut cmd,
+                        &*pool,
+                        api_url.clone(),
+                        token.clone(),
+                        &ir.0,
+                        Err(name),
+                    );
+                    browser.new_name.clear();
+                }
+            });
+            if busy {
+                ui.label("…");
+            }
+
+            ui.separator();
+            ui.horizontal(|ui| {
+                ui.heading("My programs");
+                if ui.small_button("⟳").on_hover_text("Refresh").clicked() {
+                    refresh_programs.send(RefreshMyPrograms);
+                }
+            });
+            let open_id = browser.open.as_ref().map(|p| p.summary.program_id.as_str());
+            let renaming = &mut browser.renaming;
+            let mut open = None;
+            let mut delete = None;
+            let mut rename = None;
+            let mut rename_done = false;
+            for program in programs.0.iter() {
+                ui.horizontal(|ui| {
+                    match renaming.as_mut() {
+                        Some((id, name)) if *id == program.program_id => {
+                            ui.text_edit_singleline(name);
+                            if ui.small_button("✔").clicked() && !name.trim().is_empty() {
+                                let task = pool.spawn(cao_lang_client::rename_program(
+                                    api_url.clone(),
+                                    token.clone(),
+                                    id.clone(),
+                                    name.trim().to_string(),
+                                ));
+                                cmd.spawn().insert(RenameTask(task));
+                                rename_done = true;
+                            }
+                            if ui.small_button("✖").clicked() {
+                                rename_done = true;
+                            }
+                            return;
+                        }
+                        _ => {}
+                    }
+                    let selected = open_id == Some(program.program_id.as_str());
+                    let resp = ui
+                        .selectable_label(selected, program_label(program))
+                        .on_hover_text(format!(
+                            "created: {}\nupdated: {}",
+                            program.created.as_deref().unwrap_or("-"),
+                            program.updated.as_deref().unwrap_or("-"),
+                        ));
+                    if resp.clicked() && !busy {
+                        open = Some(program.clone());
+                    }
+                    if ui.small_button("✏").on_hover_text("Rename").clicked() {
+                        rename = Some((program.program_id.clone(), program.name.clone()));
+                    }
+                    if ui.small_button("🗑").on_hover_text("Delete").clicked() {
+                        delete = Some(program.clone());
+                    }
+                });
+            }
+            if rename_done {
+                *renaming = None;
+            }
+            if rename.is_some() {
+                *renaming = rename;
+            }
+            if let Some(p) = delete {
+                browser.confirm = Some(Confirm::Delete(p));
+            }
+            if let Some(p) = open {
+                if dirty {
+                    browser.confirm = Some(Confirm::Open(p));
+                } else {
+                    spawn_load(&mut cmd, &*pool, api_url.clone(), token, p.program_id);
+                }
+            }
+        });
+}
+
+fn program_tasks_system(
+    mut cmd: Commands,
+    mut browser: ResMut<ProgramBrowser>,
+    mut ir: ResMut<CurrentProgram>,
+    mut history: ResMut<EditHistory>,
+    mut diff: ResMut<ProgramDiff>,
+    mut refresh_programs: EventWriter<RefreshMyPrograms>,
+    mut api_errors: EventWriter<ApiErrorEvent>,
+    mut loads: Query<(Entity, &mut LoadTask)>,
+    mut compares: Query<(Entity, &mut CompareTask)>,
+    mut saves: Query<(Entity, &mut SaveTask)>,
+    mut renames: Query<(Entity, &mut RenameTask)>,
+    mut deletes: Query<(Entity, &mut DeleteTask)>,
+) {
+    for (e, mut t) in loads.iter_mut() {
+        if let Some(res) = future::block_on(future::poll_once(&mut t.0)) {
+            cmd.entity(e).despawn_recursive();
+            match res.and_then(|program| {
+                serde_hashkey::to_key(&program.program)
+                    .map(|key| (program, key))
+                    .map_err(|err| CaoApiError::Decode(err.to_string()))
+            }) {
+                Ok((program, saved)) => {
+                    info!("Opened program {}", program.summary.name);
+                    ir.0 = program.program;
+                    history.clear();
+                    diff.close();
+                    browser.open = Some(OpenProgram {
+                        summary: program.summary,
+                        saved,
+                    });
+                    browser.conflict = false;
+                }
+                Err(err) => api_errors.send(ApiErrorEvent::new("Opening the program", err)),
+            }
+        }
+    }
+    for (e, mut t) in compares.iter_mut() {
+        if let Some(res) = future::block_on(future::poll_once(&mut t.0)) {
+            cmd.entity(e).despawn_recursive();
+            match res {
+                Ok(program) => {
+                    let label = program_label(&program.summary);
+                    diff.merge(label, program.summary, program.program);
+                }
+                Err(err) => api_errors.send(ApiErrorEvent::new("Comparing the program", err)),
+            }
+        }
+    }
+    for (e, mut t) in saves.iter_mut() {
+        if let Some(res) = future::block_on(future::poll_once(&mut t.task)) {
+            cmd.entity(e).despawn_recursive();
+            match res {
+                Ok(summary) => {
+                    info!("Saved {} version {}", summary.name, summary.version);
+                    browser.open = Some(OpenProgram {
+                        summary,
+                        saved: t.key.clone(),
+                    });
+                    refresh_programs.send(RefreshMyPrograms);
+                }
+                Err(CaoApiError::Status { status: 409, .. }) => browser.conflict = true,
+                Err(err) => api_errors.send(ApiErrorEvent::new("Saving the program", err)),
+            }
+        }
+    }
+    for (e, mut t) in renames.iter_mut() {
+        if let Some(res) = future::block_on(future::poll_once(&mut t.0)) {
+            cmd.entity(e).despawn_recursive();
+            match res {
+                Ok(summary) => {
+                    if let Some(open) = browser.open.as_mut() {
+                        if open.summary.program_id == summary.program_id {
+                            open.summary.name = summary.name;
+                        }
+                    }
+                    refresh_programs.send(RefreshMyPrograms);
+                }
+                Err(err) => api_errors.send(ApiErrorEvent::new("Renaming the program", err)),
+            }
+        }
+    }
+    for (e, mut t) in deletes.iter_mut() {
+        if let Some(res) = future::block_on(future::poll_once(&mut t.task)) {
+            cmd.entity(e).despawn_recursive();
+            match res {
+                Ok(()) => {
+                    // keep the IR, so the program can still be saved as a new one
+                    if browser
+                        .open
+                        .as_ref()
+                        .map(|p| p.summary.program_id == t.program_id)
+                        .unwrap_or(false)
+                    {
+                        browser.open = None;
+                    }
+                    refresh_programs.send(RefreshMyPrograms);
+                }
+                Err(err) => api_errors.send(ApiErrorEvent::new("Deleting the program", err)),
+            }
+        }
+    }
+}
+
+/// Base the next save on the merged version, until then saves are checked against the old one
+fn merge_finished_system(
+    mut browser: ResMut<ProgramBrowser>,
+    mut merge_finished: EventReader<MergeFinished>,
+) {
+    for MergeFinished { summary, saved } in merge_finished.iter() {
+        let open = match browser.open.as_ref() {
+            Some(open) if open.summary.program_id == summary.program_id => open,
+            _ => continue,
+        };
+        if open.summary.version > summary.version {
+            // saved since the comparison
+            continue;
+        }
+        browser.open = Some(OpenProgram {
+            summary: summary.clone(),
+            saved: saved.clone(),
+        });
+        browser.conflict = false;
+    }
+}
+
+/// Programs of other users must not be saved over
+fn on_logout_system(token: Res<CurrentAuthToken>, mut browser: ResMut<ProgramBrowser>) {
+    if token.is_changed() && token.0.is_none() {
+        browser.open = None;
+        browser.conflict = false;
+        browser.confirm = None;
+        browser.renaming = None;
+    }
+}
+
+impl Plugin for ProgramBrowserPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_event::<MergeFinished>()
+            .insert_resource(ProgramBrowser::default())
+            .add_system(program_tasks_system.system())
+            .add_system(merge_finished_system.system())
+            .add_system(on_logout_system.system())
+            .add_system_set(
+                SystemSet::on_update(crate::AppState::CaoLangEditor)
+                    .with_system(program_browser_ui_system.system()),
+            );
+    }
+}
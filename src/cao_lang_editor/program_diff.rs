@@ -0,0 +1,465 @@
+//! Lane by lane comparison of the current program with another version of it
+//!
+//! Lanes are matched by name, unnamed lanes by index, the first lane is always matched. Cards are
+//! compared by value, runs of differing cards form hunks. Accepting a hunk replaces the current
+//! program's cards with the other version's, going through the [EditHistory] so it can be undone.
+
+use std::ops::Range;
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContext};
+use cao_lang::compiler::{CaoIr, Card, Lane};
+
+use super::{
+    history::{EditCommand, EditHistory, ReplaceSource},
+    program_browser::MergeFinished,
+    text_format::card_to_text,
+    CurrentProgram,
+};
+use crate::cao_lang_client::cao_lang_model::ProgramSummary;
+
+const REMOVED_COLOR: egui::Color32 = egui::Color32::from_rgb(200, 60, 60);
+const ADDED_COLOR: egui::Color32 = egui::Color32::from_rgb(60, 170, 60);
+const CHANGED_COLOR: egui::Color32 = egui::Color32::from_rgb(200, 120, 0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HunkKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// Cards `local` of the current lane are `other` in the other version
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hunk {
+    pub local: Range<usize>,
+    pub other: Range<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    Same {
+        local: usize,
+        other: usize,
+        len: usize,
+    },
+    Hunk(Hunk),
+}
+
+/// `None` lane indices are missing from that version
+#[derive(Debug, Clone)]
+pub struct LaneDiff {
+    pub local: Option<usize>,
+    pub other: Option<usize>,
+    pub segments: Vec<Segment>,
+}
+
+/// The version the current program is compared with
+pub struct DiffTarget {
+    /// shown in the title, e.g. the file or the server version
+    pub label: String,
+    pub program: CaoIr,
+    /// the server's version being merged, the next save is based on it once the merge is done
+    pub saved: Option<ProgramSummary>,
+}
+
+#[derive(Default)]
+pub struct ProgramDiff {
+    pub target: Option<DiffTarget>,
+    /// key of the compared programs and their diff
+    cache: Option<(serde_hashkey::Key, Vec<LaneDiff>)>,
+}
+
+impl Hunk {
+    pub fn kind(&self) -> HunkKind {
+        match (self.local.is_empty(), self.other.is_empty()) {
+            (true, _) => HunkKind::Added,
+            (_, true) => HunkKind::Removed,
+            _ => HunkKind::Changed,
+        }
+    }
+}
+
+impl LaneDiff {
+    pub fn is_same(&self, local: &CaoIr, other: &CaoIr) -> bool {
+        match (self.local, self.other) {
+            (Some(l), Some(o)) => {
+                local.lanes[l].name == other.lanes[o].name
+                    && self
+                        .segments
+                        .iter()
+                        .all(|s| matches!(s, Segment::Same { .. }))
+            }
+            _ => false,
+        }
+    }
+}
+
+impl ProgramDiff {
+    pub fn compare(&mut self, label: impl Into<String>, program: CaoIr) {
+        self.target = Some(DiffTarget {
+            label: label.into(),
+            program,
+            saved: None,
+        });
+        self.cache = None;
+    }
+
+    /// Compare with the server's version of the open program
+    pub fn merge(&mut self, label: impl Into<String>, summary: ProgramSummary, program: CaoIr) {
+        self.compare(label, program);
+        if let Some(target) = self.target.as_mut() {
+            target.saved = Some(summary);
+        }
+    }
+
+    pub fn close(&mut self) {
+        self.target = None;
+        self.cache = None;
+    }
+}
+
+/// Pair up the lanes of the two programs, `(local, other)` indices
+fn match_lanes(local: &CaoIr, other: &CaoIr) -> Vec<(Option<usize>, Option<usize>)> {
+    let mut other_taken = vec![false; other.lanes.len()];
+    let mut pairs = Vec::with_capacity(local.lanes.len());
+    for (i, lane) in local.lanes.iter().enumerate() {
+        let found = match lane.name.as_deref() {
+            _ if i == 0 => Some(0).filter(|_| !other.lanes.is_empty()),
+            Some(name) => other
+                .lanes
+                .iter()
+                .position(|l| l.name.as_deref() == Some(name)),
+            None => Some(i).filter(|i| other.lanes.get(*i).map(|l| l.name.is_none()) == Some(true)),
+        };
+        let found = found.filter(|j| !other_taken[*j]);
+        if let Some(j) = found {
+            other_taken[j] = true;
+        }
+        pairs.push((Some(i), found));
+    }
+    pairs.extend(
+        other_taken
+            .iter()
+            .enumerate()
+            .filter(|(_, taken)| !**taken)
+            .map(|(j, _)| (None, Some(j))),
+    );
+    pairs
+}
+
+/// Longest common subsequence of the cards, grouped into segments
+pub fn diff_cards(local: &[Card], other: &[Card]) -> Vec<Segment> {
+    let keys = |cards: &[Card]| -> Vec<_> {
+        cards
+            .iter()
+            .map(|c| serde_hashkey::to_key(c).ok())
+            .collect()
+    };
+    let (a, b) = (keys(local), keys(other));
+    let (n, m) = (a.len(), b.len());
+    // lcs[i][j] is the length of the common subsequence of a[i..] and b[j..]
+    let mut lcs = vec![vec![0u32; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut segments = Vec::new();
+    let mut hunk: Option<Hunk> = None;
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && a[i] == b[j] {
+            if let Some(hunk) = hunk.take() {
+                segments.push(Segment::Hunk(hunk));
+            }
+            match segments.last_mut() {
+                Some(Segment::Same { len, .. }) => *len += 1,
+                _ => segments.push(Segment::Same {
+                    local: i,
+                    other: j,
+                    len: 1,
+                }),
+            }
+            i += 1;
+            j += 1;
+            continue;
+        }
+        let hunk = hunk.get_or_insert(Hunk {
+            local: i..i,
+            other: j..j,
+        });
+        if j == m || (i < n && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            i += 1;
+            hunk.local.end = i;
+        } else {
+            j += 1;
+            hunk.other.end = j;
+        }
+    }
+    segments.extend(hunk.map(Segment::Hunk));
+    segments
+}
+
+pub fn diff_programs(local: &CaoIr, other: &CaoIr) -> Vec<LaneDiff> {
+    match_lanes(local, other)
+        .into_iter()
+        .map(|(l, o)| {
+            let segments = match (l, o) {
+                (Some(l), Some(o)) => diff_cards(&local.lanes[l].cards, &other.lanes[o].cards),
+                _ => Vec::new(),
+            };
+            LaneDiff {
+                local: l,
+                other: o,
+                segments,
+            }
+        })
+        .collect()
+}
+
+/// Replace the hunk's cards in the current lane by the other version's
+pub fn accept_hunk_command(
+    local: &CaoIr,
+    other: &CaoIr,
+    (local_lane, other_lane): (usize, usize),
+    hunk: &Hunk,
+) -> EditCommand {
+    let lane = &local.lanes[local_lane];
+    // back to front so the indices stay valid
+    let mut commands: Vec<_> = hunk
+        .local
+        .clone()
+        .rev()
+        .map(|index| EditCommand::RemoveCard {
+            lane: local_lane,
+            index,
+            card: lane.cards[index].clone(),
+        })
+        .collect();
+    commands.extend(
+        other.lanes[other_lane].cards[hunk.other.clone()]
+            .iter()
+            .enumerate()
+            .map(|(i, card)| EditCommand::InsertCard {
+                lane: local_lane,
+                index: hunk.local.start + i,
+                card: card.clone(),
+            }),
+    );
+    EditCommand::Batch(commands)
+}
+
+/// Add, remove or rename the lane to match the other version
+pub fn accept_lane_command(local: &CaoIr, other: &CaoIr, diff: &LaneDiff) -> Option<EditCommand> {
+    match (diff.local, diff.other) {
+        // appended, inserting would shift the lanes referenced by index
+        (None, Some(o)) => Some(EditCommand::AddLane {
+            index: local.lanes.len(),
+            lane: other.lanes[o].clone(),
+        }),
+        (Some(l), None) => Some(EditCommand::RemoveLane {
+            index: l,
+            lane: local.lanes[l].clone(),
+        }),
+        (Some(l), Some(o)) if local.lanes[l].name != other.lanes[o].name => {
+            Some(EditCommand::RenameLane {
+                index: l,
+                before: local.lanes[l].name.clone(),
+                after: other.lanes[o].name.clone(),
+            })
+        }
+        _ => None,
+    }
+}
+
+fn lane_title(lane: Option<(usize, &Lane)>) -> String {
+    match lane {
+        Some((i, lane)) => format!("#{} {}", i, lane.name.as_deref().unwrap_or("")),
+        None => "-".to_string(),
+    }
+}
+
+fn card_cell(ui: &mut egui::Ui, card: Option<&Card>, color: Option<egui::Color32>) {
+    let text = card.map(card_to_text).unwrap_or_default();
+    let label = egui::Label::new(text).monospace();
+    match color {
+        Some(color) => ui.add(label.text_color(color)),
+        None => ui.add(label),
+    };
+}
+
+/// Show the segments side by side, returns the accepted hunk
+fn lane_diff_ui<'a>(
+    ui: &mut egui::Ui,
+    id: egui::Id,
+    local: &[Card],
+    other: &[Card],
+    segments: &'a [Segment],
+) -> Option<&'a Hunk> {
+    let mut accepted = None;
+    egui::Grid::new(id).striped(true).show(ui, |ui| {
+        ui.label(egui::Label::new("Current").strong());
+        ui.label(egui::Label::new("Other").strong());
+        ui.end_row();
+        for segment in segments {
+            match segment {
+                Segment::Same {
+                    local: l,
+                    other: o,
+                    len,
+                } => {
+                    for k in 0..*len {
+                        card_cell(ui, local.get(l + k), None);
+                        card_cell(ui, other.get(o + k), None);
+                        ui.end_row();
+                    }
+                }
+                Segment::Hunk(hunk) => {
+                    let (local_color, other_color) = match hunk.kind() {
+                        HunkKind::Added | HunkKind::Removed => (REMOVED_COLOR, ADDED_COLOR),
+                        HunkKind::Changed => (CHANGED_COLOR, CHANGED_COLOR),
+                    };
+                    let rows = hunk.local.len().max(hunk.other.len());
+                    for k in 0..rows {
+                        let l = Some(hunk.local.start + k).filter(|i| hunk.local.contains(i));
+                        let o = Some(hunk.other.start + k).filter(|i| hunk.other.contains(i));
+                        card_cell(ui, l.map(|i| &local[i]), Some(local_color));
+                        card_cell(ui, o.map(|i| &other[i]), Some(other_color));
+                        if k == 0
+                            && ui
+                                .small_button("Accept")
+                                .on_hover_text("Take the other version of these cards")
+                                .clicked()
+                        {
+                            accepted = Some(hunk);
+                        }
+                        ui.end_row();
+                    }
+                }
+            }
+        }
+    });
+    accepted
+}
+
+pub fn program_diff_ui_system(
+    egui_ctx: ResMut<EguiContext>, // exclusive ownership
+    mut diff: ResMut<ProgramDiff>,
+    mut ir: ResMut<CurrentProgram>,
+    mut history: ResMut<EditHistory>,
+    mut merge_finished: EventWriter<MergeFinished>,
+) {
+    let diff = &mut *diff;
+    let target = match diff.target.as_ref() {
+        Some(t) => t,
+        None => return,
+    };
+    let key = match serde_hashkey::to_key(&(&ir.0, &target.program)) {
+        Ok(key) => key,
+        Err(err) => {
+            error!("Failed to hash the programs {:?}", err);
+            return;
+        }
+    };
+    if diff.cache.as_ref().map(|(k, _)| k != &key).unwrap_or(true) {
+        diff.cache = Some((key, diff_programs(&ir.0, &target.program)));
+    }
+    let lanes = &diff.cache.as_ref().unwrap().1;
+    let other = &target.program;
+
+    let mut command = None;
+    let mut close = false;
+    egui::Window::new(format!("Compare with {}", target.label))
+        .id(egui::Id::new("cao-lang-program-diff"))
+        .scroll(true)
+        .show(egui_ctx.ctx(), |ui| {
+            let changed = lanes.iter().filter(|d| !d.is_same(&ir.0, other)).count();
+            ui.horizontal(|ui| {
+                ui.label(format!("{} lane(s) differ", changed));
+                if ui
+                    .add(egui::Button::new("Accept all").enabled(changed > 0))
+                    .clicked()
+                {
+                    command = Some(EditCommand::Replace {
+                        before: ir.0.clone(),
+                        after: other.clone(),
+                        source: ReplaceSource::AcceptAll,
+                    });
+                }
+                if let Some(summary) = target.saved.as_ref() {
+                    if ui
+                        .button("Done merging")
+                        .on_hover_text("Base the next save on this version")
+                        .clicked()
+                    {
+                        match serde_hashkey::to_key(other) {
+                            Ok(saved) => {
+                                merge_finished.send(MergeFinished {
+                                    summary: summary.clone(),
+                                    saved,
+                                });
+                                close = true;
+                            }
+                            Err(err) => error!("Failed to hash the program {:?}", err),
+                        }
+                    }
+                }
+                if ui.button("Close").clicked() {
+                    close = true;
+                }
+            });
+            for (i, lane_diff) in lanes.iter().enumerate() {
+                let local_lane = lane_diff.local.map(|l| (l, &ir.0.lanes[l]));
+                let other_lane = lane_diff.other.map(|o| (o, &other.lanes[o]));
+                let same = lane_diff.is_same(&ir.0, other);
+                let title = format!(
+                    "{} {} ⟷ {}",
+                    if same { " " } else { "●" },
+                    lane_title(local_lane),
+                    lane_title(other_lane)
+                );
+                egui::CollapsingHeader::new(title)
+                    .id_source(("cao-lang-diff-lane", i))
+                    .default_open(!same)
+                    .show(ui, |ui| {
+                        if let Some(cmd) = accept_lane_command(&ir.0, other, lane_diff) {
+                            let hint = match (local_lane, other_lane) {
+                                (None, _) => "Add this lane",
+                                (_, None) => "Remove this lane",
+                                _ => "Take the other version's lane name",
+                            };
+                            if ui.button(hint).clicked() {
+                                command = Some(cmd);
+                            }
+                        }
+                        let (l, o) = match (local_lane, other_lane) {
+                            (Some((l, _)), Some((o, _))) => (l, o),
+                            _ => return,
+                        };
+                        let accepted = lane_diff_ui(
+                            ui,
+                            egui::Id::new("cao-lang-diff-grid").with(i),
+                            &ir.0.lanes[l].cards,
+                            &other.lanes[o].cards,
+                            &lane_diff.segments,
+                        );
+                        if let Some(hunk) = accepted {
+                            command = Some(accept_hunk_command(&ir.0, other, (l, o), hunk));
+                        }
+                    });
+            }
+        });
+
+    if let Some(command) = command {
+        history.apply(&mut ir.0, command);
+    }
+    if close {
+        diff.close();
+    }
+}
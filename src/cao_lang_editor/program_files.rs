@@ -0,0 +1,167 @@
+//! Importing and exporting the current program as local files
+//!
+//! `.json` files hold the serialized [CaoIr], anything else is read as the
+//! [text format](super::text_format).
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use bevy::{prelude::*, window::FileDragAndDrop};
+use bevy_egui::egui::{self, Ui};
+use cao_lang::compiler::CaoIr;
+
+use super::{history::EditHistory, program_diff::ProgramDiff, text_format, CurrentProgram};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgramFormat {
+    Json,
+    Text,
+}
+
+pub struct ProgramFiles {
+    pub path: String,
+    /// Outcome of the last import/export, `Err` holds the error message
+    pub status: Option<Result<String, String>>,
+}
+
+impl Default for ProgramFiles {
+    fn default() -> Self {
+        Self {
+            path: format!("program.{}", text_format::FILE_EXTENSION),
+            status: None,
+        }
+    }
+}
+
+impl ProgramFormat {
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("json") => ProgramFormat::Json,
+            _ => ProgramFormat::Text,
+        }
+    }
+}
+
+pub fn export_program(path: &Path, ir: &CaoIr) -> anyhow::Result<()> {
+    let content = match ProgramFormat::from_path(path) {
+        ProgramFormat::Json => {
+            serde_json::to_string_pretty(ir).with_context(|| "Failed to serialize the program")?
+        }
+        ProgramFormat::Text => text_format::to_text(ir),
+    };
+    std::fs::write(path, content).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+pub fn import_program(path: &Path) -> anyhow::Result<CaoIr> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let ir = match ProgramFormat::from_path(path) {
+        ProgramFormat::Json => serde_json::from_str(content.as_str())
+            .with_context(|| format!("Failed to parse {}", path.display()))?,
+        ProgramFormat::Text => text_format::from_text(content.as_str())
+            .with_context(|| format!("Failed to parse {}", path.display()))?,
+    };
+    Ok(ir)
+}
+
+fn load_into(
+    path: &Path,
+    program: &mut CurrentProgram,
+    files: &mut ProgramFiles,
+    history: &mut EditHistory,
+) {
+    files.status = Some(match import_program(path) {
+        Ok(ir) => {
+            program.0 = ir;
+            history.clear();
+            Ok(format!("Imported {}", path.display()))
+        }
+        Err(err) => {
+            error!("{:?}", err);
+            Err(format!("{:#}", err))
+        }
+    });
+}
+
+pub fn program_files_ui(
+    ui: &mut Ui,
+    files: &mut ProgramFiles,
+    program: &mut CurrentProgram,
+    history: &mut EditHistory,
+    diff: &mut ProgramDiff,
+) {
+    ui.heading("Local file");
+    ui.add(egui::TextEdit::singleline(&mut files.path).hint_text("path/to/program.cao"));
+    ui.horizontal(|ui| {
+        let has_path = !files.path.trim().is_empty();
+        if ui
+            .add(egui::Button::new("Export").enabled(has_path))
+            .clicked()
+        {
+            let path = PathBuf::from(files.path.trim());
+            files.status = Some(match export_program(&path, &program.0) {
+                Ok(()) => Ok(format!("Exported to {}", path.display())),
+                Err(err) => {
+                    error!("{:?}", err);
+                    Err(format!("{:#}", err))
+                }
+            });
+        }
+        if ui
+            .add(egui::Button::new("Import").enabled(has_path))
+            .clicked()
+        {
+            let path = PathBuf::from(files.path.trim());
+            load_into(&path, program, files, history);
+        }
+        if ui
+            .add(egui::Button::new("Compare").enabled(has_path))
+            .on_hover_text("Compare the current program with the file")
+            .clicked()
+        {
+            let path = PathBuf::from(files.path.trim());
+            match import_program(&path) {
+                Ok(ir) => {
+                    diff.compare(path.display().to_string(), ir);
+                    files.status = None;
+                }
+                Err(err) => {
+                    error!("{:?}", err);
+                    files.status = Some(Err(format!("{:#}", err)));
+                }
+            }
+        }
+    })
+    .response
+    .on_hover_text(".json files are stored as json, anything else as text");
+    match files.status.as_ref() {
+        Some(Ok(msg)) => {
+            ui.colored_label(egui::color::Rgba::GREEN, msg.as_str());
+        }
+        Some(Err(msg)) => {
+            ui.colored_label(egui::color::Rgba::RED, msg.as_str());
+        }
+        None => {
+            ui.label("Drop a program file on the window to import it");
+        }
+    }
+}
+
+pub fn file_drop_system(
+    mut events: EventReader<FileDragAndDrop>,
+    mut program: ResMut<CurrentProgram>,
+    mut files: ResMut<ProgramFiles>,
+    mut history: ResMut<EditHistory>,
+) {
+    for event in events.iter() {
+        if let FileDragAndDrop::DroppedFile { path_buf, .. } = event {
+            files.path = path_buf.display().to_string();
+            load_into(
+                path_buf.as_path(),
+                &mut *program,
+                &mut *files,
+                &mut *history,
+            );
+        }
+    }
+}
@@ -0,0 +1,437 @@
+//! Local sandbox running the compiled current program in a cao-lang [Vm]
+//!
+//! Natives of the schema are stubs logging their arguments and returning configurable values.
+//! The VM can not be paused mid-program and does not report which card an instruction was
+//! compiled from, so the sandbox runs a copy of the program calling a tracing native before
+//! every card. The run records the stack and the globals before each card, stepping only moves
+//! through the recorded trace.
+
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContext};
+use cao_lang::{
+    compiler::{CallNode, CaoIr, Card, IntegerNode},
+    prelude::{CaoProgram, ExecutionError, Value, Vm},
+    InputString,
+};
+
+use super::{CurrentLocalCompileError, CurrentProgram, EditorFocus};
+use crate::cao_lang_client::CaoLangSchema;
+
+/// Instructions a run may execute before it's stopped, so infinite loops don't freeze the editor
+const MAX_INSTRUCTIONS: u64 = 100_000;
+
+/// Native the instrumented program calls with the lane and card index before every card
+const TRACE_NATIVE: &str = "__sandbox_trace";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunState {
+    Finished,
+    Failed(String),
+}
+
+/// State of the VM right before a card was executed
+pub struct Step {
+    pub lane: usize,
+    pub card: usize,
+    /// top of the stack last
+    pub stack: Vec<String>,
+    pub globals: Vec<(String, String)>,
+    /// number of native calls made before the card
+    pub log_len: usize,
+}
+
+/// The VM's auxiliary data
+#[derive(Default)]
+pub struct Trace {
+    steps: Vec<Step>,
+    /// calls of the natives
+    log: Vec<String>,
+}
+
+pub struct SandboxRun {
+    steps: Vec<Step>,
+    /// index of the next card in `steps`, `steps.len()` once the end of the run is shown
+    pub position: usize,
+    pub state: RunState,
+    /// state at the end of the run, top of the stack last
+    pub stack: Vec<String>,
+    pub globals: Vec<(String, String)>,
+    pub log: Vec<String>,
+}
+
+/// State of the `Run` window
+#[derive(Default)]
+pub struct Sandbox {
+    pub run: Option<SandboxRun>,
+    /// stub return values by native name, one per schema output
+    pub natives: HashMap<String, Vec<String>>,
+}
+
+impl Sandbox {
+    /// `(lane, card)` the run is stopped before
+    pub fn current_card(&self) -> Option<(usize, usize)> {
+        self.run
+            .as_ref()
+            .and_then(|run| run.current())
+            .map(|step| (step.lane, step.card))
+    }
+}
+
+/// Parse a stub value: `nil`, an integer or a float, `None` for anything else
+pub fn parse_value(s: &str) -> Option<Value> {
+    let s = s.trim();
+    if s == "nil" {
+        return Some(Value::Nil);
+    }
+    if let Ok(i) = s.parse() {
+        return Some(Value::Integer(i));
+    }
+    s.parse().ok().map(Value::Floating)
+}
+
+/// Value a native returns when no stub value is configured
+fn default_value(ty: &str) -> Value {
+    let ty = ty.to_lowercase();
+    if ty.contains("int") {
+        Value::Integer(0)
+    } else if ty.contains("float") || ty.contains("number") {
+        Value::Floating(0.0)
+    } else {
+        Value::Nil
+    }
+}
+
+fn format_values(values: &[Value]) -> String {
+    values
+        .iter()
+        .map(|v| format!("{:?}", v))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn global_names(ir: &CaoIr) -> Vec<String> {
+    let mut names: Vec<String> = ir
+        .lanes
+        .iter()
+        .flat_map(|l| l.cards.iter())
+        .filter_map(|card| match card {
+            Card::SetGlobalVar(var) => Some(var.0.as_str().to_string()),
+            _ => None,
+        })
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Copy of the program calling [TRACE_NATIVE] before every card
+fn instrument(ir: &CaoIr) -> Result<CaoIr, String> {
+    let trace = InputString::from(TRACE_NATIVE).map_err(|err| format!("{:?}", err))?;
+    let mut ir = ir.clone();
+    for (lane_index, lane) in ir.lanes.iter_mut().enumerate() {
+        let cards = std::mem::take(&mut lane.cards);
+        for (card_index, card) in cards.into_iter().enumerate() {
+            lane.cards.extend(vec![
+                Card::ScalarInt(IntegerNode(lane_index as _)),
+                Card::ScalarInt(IntegerNode(card_index as _)),
+                Card::CallNative(Box::new(CallNode(trace.clone()))),
+                card,
+            ]);
+        }
+    }
+    Ok(ir)
+}
+
+fn stack_snapshot(vm: &Vm<Trace>) -> Vec<String> {
+    vm.runtime_data
+        .stack
+        .as_slice()
+        .iter()
+        .map(|v| format!("{:?}", v))
+        .collect()
+}
+
+fn register_natives(
+    vm: &mut Vm<Trace>,
+    schema: &CaoLangSchema,
+    natives: &HashMap<String, Vec<String>>,
+) {
+    for node in schema.0.iter().filter(|n| n.ty == "Call") {
+        let name = node.name.clone();
+        let inputs = node.input.len();
+        let stubs = natives.get(&node.name);
+        let returns: Vec<Value> = node
+            .output
+            .iter()
+            .enumerate()
+            .map(|(i, ty)| {
+                stubs
+                    .and_then(|s| s.get(i))
+                    .and_then(|s| parse_value(s))
+                    .unwrap_or_else(|| default_value(ty))
+            })
+            .collect();
+        vm.register_function(
+            node.name.as_str(),
+            move |vm: &mut Vm<Trace>| -> Result<(), ExecutionError> {
+                let mut args: Vec<Value> = (0..inputs).map(|_| vm.stack_pop()).collect();
+                args.reverse();
+                vm.auxiliary_data.log.push(format!(
+                    "{}({}) -> [{}]",
+                    name,
+                    format_values(&args),
+                    format_values(&returns)
+                ));
+                for value in returns.iter().cloned() {
+                    vm.stack_push(value)?;
+                }
+                Ok(())
+            },
+        );
+    }
+}
+
+fn read_globals(vm: &Vm<Trace>, names: &[String], program: &CaoProgram) -> Vec<(String, String)> {
+    names
+        .iter()
+        .map(|name| {
+            let value = vm
+                .read_var_by_name(name.as_str(), &program.variables)
+                .map(|v| format!("{:?}", v))
+                .unwrap_or_else(|| "-".to_string());
+            (name.clone(), value)
+        })
+        .collect()
+}
+
+impl SandboxRun {
+    /// Run the program to its end, recording the state before every card
+    pub fn new(
+        ir: &CaoIr,
+        schema: &CaoLangSchema,
+        natives: &HashMap<String, Vec<String>>,
+    ) -> Result<Self, String> {
+        let program = cao_lang::compiler::compile(&instrument(ir)?, None)
+            .map_err(|err| err.payload.to_string())?;
+        let global_names = global_names(ir);
+        let mut vm = Vm::new(Trace::default()).map_err(|err| err.to_string())?;
+        vm.max_instr = MAX_INSTRUCTIONS;
+        register_natives(&mut vm, schema, natives);
+        {
+            let program = program.clone();
+            let global_names = global_names.clone();
+            vm.register_function(
+                TRACE_NATIVE,
+                move |vm: &mut Vm<Trace>| -> Result<(), ExecutionError> {
+                    let card = vm.stack_pop();
+                    let lane = vm.stack_pop();
+                    if let (Value::Integer(lane), Value::Integer(card)) = (lane, card) {
+                        let step = Step {
+                            lane: lane as usize,
+                            card: card as usize,
+                            stack: stack_snapshot(vm),
+                            globals: read_globals(vm, &global_names, &program),
+                            log_len: vm.auxiliary_data.log.len(),
+                        };
+                        vm.auxiliary_data.steps.push(step);
+                    }
+                    Ok(())
+                },
+            );
+        }
+
+        let state = match vm.run(&program) {
+            Ok(_) => RunState::Finished,
+            Err(ExecutionError::Timeout) => RunState::Failed(format!(
+                "Stopped after {} instructions, the program might not terminate",
+                MAX_INSTRUCTIONS
+            )),
+            Err(err) => RunState::Failed(err.to_string()),
+        };
+        let stack = stack_snapshot(&vm);
+        let globals = read_globals(&vm, &global_names, &program);
+        let trace = std::mem::take(&mut vm.auxiliary_data);
+        Ok(Self {
+            steps: trace.steps,
+            position: 0,
+            state,
+            stack,
+            globals,
+            log: trace.log,
+        })
+    }
+
+    /// The step the run is stopped before, `None` once the end of the run is shown
+    pub fn current(&self) -> Option<&Step> {
+        self.steps.get(self.position)
+    }
+
+    pub fn step(&mut self) {
+        self.position = (self.position + 1).min(self.steps.len());
+    }
+
+    pub fn run_to_end(&mut self) {
+        self.position = self.steps.len();
+    }
+}
+
+fn natives_ui(
+    ui: &mut egui::Ui,
+    schema: &CaoLangSchema,
+    natives: &mut HashMap<String, Vec<String>>,
+) {
+    ui.label("Stubs return nil, an integer or a float");
+    for node in schema.0.iter().filter(|n| n.ty == "Call") {
+        ui.label(format!("{}({})", node.name, node.input.join(", ")));
+        let stubs = natives.entry(node.name.clone()).or_default();
+        stubs.resize(node.output.len(), String::new());
+        ui.indent(("sandbox-native", node.name.as_str()), |ui| {
+            if node.output.is_empty() {
+                ui.label("returns nothing");
+            }
+            for (stub, ty) in stubs.iter_mut().zip(node.output.iter()) {
+                ui.horizontal(|ui| {
+                    ui.label(ty.as_str());
+                    ui.add(egui::TextEdit::singleline(stub).hint_text("default"));
+                });
+            }
+        });
+    }
+}
+
+pub fn sandbox_ui_system(
+    egui_ctx: ResMut<EguiContext>, // exclusive ownership
+    ir: Res<CurrentProgram>,
+    compile_error: Res<CurrentLocalCompileError>,
+    schema: Res<CaoLangSchema>,
+    mut sandbox: ResMut<Sandbox>,
+    mut focus: ResMut<EditorFocus>,
+) {
+    let sandbox = &mut *sandbox;
+    let before = sandbox.current_card();
+    egui::Window::new("Run")
+        .id(egui::Id::new("cao-lang-sandbox"))
+        .default_open(false)
+        .scroll(true)
+        .show(egui_ctx.ctx(), |ui| {
+            let compiles = compile_error.0.is_none();
+            let paused = sandbox.current_card().is_some();
+            ui.horizontal(|ui| {
+                if ui
+                    .add(egui::Button::new("⏮ Restart").enabled(compiles))
+                    .on_hover_text("Run the current program and stop before its first card")
+                    .clicked()
+                {
+                    sandbox.run = match SandboxRun::new(&ir.0, &*schema, &sandbox.natives) {
+                        Ok(run) => Some(run),
+                        Err(err) => {
+                            error!("Failed to run the program {}", err);
+                            None
+                        }
+                    };
+                }
+                if ui
+                    .add(egui::Button::new("⏭ Step").enabled(paused))
+                    .on_hover_text("Execute the next card")
+                    .clicked()
+                {
+                    if let Some(run) = sandbox.run.as_mut() {
+                        run.step();
+                    }
+                }
+                if ui.add(egui::Button::new("▶ Run").enabled(paused)).clicked() {
+                    if let Some(run) = sandbox.run.as_mut() {
+                        run.run_to_end();
+                    }
+                }
+                if ui
+                    .add(egui::Button::new("⏹ Stop").enabled(sandbox.run.is_some()))
+                    .clicked()
+                {
+                    sandbox.run = None;
+                }
+            });
+            if !compiles {
+                ui.colored_label(
+                    egui::color::Rgba::RED,
+                    "Fix the compilation errors to run the program",
+                );
+            }
+
+            if let Some(run) = sandbox.run.as_ref() {
+                let (stack, globals, log) = match run.current() {
+                    Some(step) => {
+                        let lane =
+                            ir.0.lanes
+                                .get(step.lane)
+                                .and_then(|lane| lane.name.clone())
+                                .unwrap_or_else(|| format!("lane {}", step.lane));
+                        ui.label(format!(
+                            "Stopped before card {} of {} (step {} of {})",
+                            step.card,
+                            lane,
+                            run.position + 1,
+                            run.steps.len()
+                        ));
+                        (&step.stack, &step.globals, &run.log[..step.log_len])
+                    }
+                    None => {
+                        match &run.state {
+                            RunState::Finished => {
+                                ui.colored_label(egui::color::Rgba::GREEN, "Finished");
+                            }
+                            RunState::Failed(err) => {
+                                ui.colored_label(egui::color::Rgba::RED, err.as_str());
+                            }
+                        }
+                        (&run.stack, &run.globals, &run.log[..])
+                    }
+                };
+                egui::CollapsingHeader::new("Stack")
+                    .default_open(true)
+                    .show(ui, |ui| {
+                        if stack.is_empty() {
+                            ui.label("empty");
+                        }
+                        // top of the stack first
+                        for value in stack.iter().rev() {
+                            ui.label(value.as_str());
+                        }
+                    });
+                egui::CollapsingHeader::new("Globals")
+                    .default_open(true)
+                    .show(ui, |ui| {
+                        if globals.is_empty() {
+                            ui.label("-");
+                        }
+                        egui::Grid::new("sandbox-globals").show(ui, |ui| {
+                            for (name, value) in globals.iter() {
+                                ui.label(name.as_str());
+                                ui.label(value.as_str());
+                                ui.end_row();
+                            }
+                        });
+                    });
+                egui::CollapsingHeader::new("Side effects").show(ui, |ui| {
+                    if log.is_empty() {
+                        ui.label("No natives were called");
+                    }
+                    for line in log.iter() {
+                        ui.label(line.as_str());
+                    }
+                });
+            }
+            egui::CollapsingHeader::new("Natives").show(ui, |ui| {
+                natives_ui(ui, &*schema, &mut sandbox.natives);
+            });
+        });
+    // bring the card the run stopped before into view
+    let current = sandbox.current_card();
+    if current != before {
+        if let Some((lane, card)) = current {
+            focus.0 = Some((lane, Some(card)));
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! Search, grouping and favorites of the schema window
+
+use std::collections::HashSet;
+
+use crate::cao_lang_client::{cao_lang_model::SchemaNode, CaoLangSchema};
+
+/// Groups in display order, nodes of other types are listed last
+pub const GROUPS: &[&str] = &["Instruction", "Branch", "Object", "Call"];
+pub const FAVORITES_GROUP: &str = "★ Favorites";
+pub const OTHER_GROUP: &str = "Other";
+
+#[derive(Default)]
+pub struct SchemaPalette {
+    pub query: String,
+    /// names of the favorite nodes
+    pub favorites: HashSet<String>,
+}
+
+/// Score of `query` as a case insensitive subsequence of `text`, `None` if it does not match
+///
+/// Consecutive matches and matches at word starts score higher.
+pub fn fuzzy_score(query: &str, text: &str) -> Option<i32> {
+    let mut score = 0;
+    let mut text = text.chars().flat_map(char::to_lowercase).peekable();
+    let mut prev: Option<char> = None;
+    let mut consecutive = false;
+    for q in query
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .flat_map(char::to_lowercase)
+    {
+        loop {
+            let c = text.next()?;
+            let word_start = prev.map(|p| !p.is_alphanumeric()).unwrap_or(true);
+            prev = Some(c);
+            if c == q {
+                score += 1;
+                if consecutive {
+                    score += 2;
+                }
+                if word_start {
+                    score += 3;
+                }
+                consecutive = true;
+                break;
+            }
+            consecutive = false;
+        }
+    }
+    Some(score)
+}
+
+impl SchemaPalette {
+    fn score(&self, node: &SchemaNode) -> Option<i32> {
+        if self.query.trim().is_empty() {
+            return Some(0);
+        }
+        // prefer name matches over description matches
+        let name = fuzzy_score(self.query.as_str(), node.name.as_str()).map(|s| s * 2);
+        let description = fuzzy_score(self.query.as_str(), node.description.as_str());
+        name.max(description)
+    }
+
+    pub fn is_favorite(&self, node: &SchemaNode) -> bool {
+        self.favorites.contains(node.name.as_str())
+    }
+
+    pub fn toggle_favorite(&mut self, node: &SchemaNode) {
+        if !self.favorites.remove(node.name.as_str()) {
+            self.favorites.insert(node.name.clone());
+        }
+    }
+
+    /// Indices of the matching schema nodes by group, best matches first
+    ///
+    /// Favorites are listed in their own group as well as in their type's group.
+    pub fn groups<'a>(&self, schema: &'a CaoLangSchema) -> Vec<(&'a str, Vec<usize>)> {
+        let matches: Vec<(usize, i32)> = schema
+            .0
+            .iter()
+            .enumerate()
+            .filter_map(|(i, node)| self.score(node).map(|score| (i, score)))
+            .collect();
+        let group = |filter: &dyn Fn(&SchemaNode) -> bool| {
+            let mut nodes: Vec<_> = matches
+                .iter()
+                .filter(|(i, _)| filter(&schema.0[*i]))
+                .copied()
+                .collect();
+            // stable sort, keeps the server's order between equal scores
+            nodes.sort_by_key(|(_, score)| -score);
+            nodes.into_iter().map(|(i, _)| i).collect::<Vec<_>>()
+        };
+
+        let mut groups = Vec::with_capacity(GROUPS.len() + 2);
+        groups.push((FAVORITES_GROUP, group(&|node| self.is_favorite(node))));
+        for ty in GROUPS {
+            groups.push((*ty, group(&|node| node.ty == *ty)));
+        }
+        groups.push((
+            OTHER_GROUP,
+            group(&|node| !GROUPS.contains(&node.ty.as_str())),
+        ));
+        groups.retain(|(_, nodes)| !nodes.is_empty());
+        groups
+    }
+}
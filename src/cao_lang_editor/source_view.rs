@@ -0,0 +1,99 @@
+//! Text editor mode, editing the current program in the [text format](super::text_format)
+//!
+//! Edits that parse replace the program, edits that don't are kept until they're fixed.
+//! Changes made elsewhere (undo, loading a program) re-render the text.
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContext};
+
+use super::{
+    history::{EditCommand, EditHistory, ReplaceSource},
+    text_format::{self, TextFormatError},
+    CurrentProgram,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditorMode {
+    Cards,
+    Text,
+}
+
+impl Default for EditorMode {
+    fn default() -> Self {
+        EditorMode::Cards
+    }
+}
+
+#[derive(Default)]
+pub struct SourceView {
+    pub text: String,
+    /// key of the program the text was rendered from or parsed into
+    synced: Option<serde_hashkey::Key>,
+    pub error: Option<TextFormatError>,
+    /// incremented whenever the text is re-rendered
+    session: u32,
+}
+
+pub fn source_view_system(
+    egui_ctx: ResMut<EguiContext>, // exclusive ownership
+    mode: Res<EditorMode>,
+    mut view: ResMut<SourceView>,
+    mut ir: ResMut<CurrentProgram>,
+    mut history: ResMut<EditHistory>,
+) {
+    if *mode != EditorMode::Text {
+        view.synced = None;
+        return;
+    }
+    let key = serde_hashkey::to_key(&ir.0).ok();
+    if view.synced.is_none() || view.synced != key {
+        // changed outside of the text view
+        view.text = text_format::to_text(&ir.0);
+        view.error = None;
+        view.synced = key;
+        view.session = view.session.wrapping_add(1);
+    }
+
+    let view = &mut *view;
+    egui::CentralPanel::default().show(egui_ctx.ctx(), |ui| {
+        match view.error.as_ref() {
+            Some(err) => {
+                ui.colored_label(egui::color::Rgba::RED, err.to_string());
+            }
+            None => {
+                ui.label("One card per line, `lane <name>:` starts a lane");
+            }
+        }
+        egui::ScrollArea::auto_sized().show(ui, |ui| {
+            let response = ui.add(
+                egui::TextEdit::multiline(&mut view.text)
+                    .code_editor()
+                    .desired_width(f32::INFINITY)
+                    .desired_rows(30),
+            );
+            if !response.changed() {
+                return;
+            }
+            match text_format::from_text(view.text.as_str()) {
+                Ok(program) => {
+                    view.error = None;
+                    view.synced = serde_hashkey::to_key(&program).ok();
+                    if view.synced != serde_hashkey::to_key(&ir.0).ok() {
+                        let before = ir.0.clone();
+                        history.apply(
+                            &mut ir.0,
+                            EditCommand::Replace {
+                                before,
+                                after: program,
+                                source: ReplaceSource::Text {
+                                    session: view.session,
+                                },
+                            },
+                        );
+                    }
+                }
+                Err(err) => view.error = Some(err),
+            }
+        });
+    });
+}
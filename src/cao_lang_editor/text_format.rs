@@ -0,0 +1,448 @@
+//! Human readable listing of a [CaoIr], one card per line
+//!
+//! ```text
+//! # comments start with '#'
+//! lane Main:
+//!     ScalarInt 1
+//!     StringLiteral "hello"
+//!     SetVar foo
+//!     IfElse @then @"other lane"
+//!     Jump #2
+//! ```
+//!
+//! Lanes are referenced by name (`@name`) or by index (`#2`). Names are quoted unless they're
+//! made of letters, digits, `_`, `-` and `.`. Strings and quoted names use json escapes.
+
+use std::fmt::Write;
+
+use cao_lang::{
+    compiler::{
+        CallNode, CaoIr, Card, FloatNode, IntegerNode, Lane, LaneNode, StringNode, VarNode,
+    },
+    InputString, VarName,
+};
+use thiserror::Error;
+
+pub const FILE_EXTENSION: &str = "cao";
+
+#[derive(Debug, Clone, Error)]
+#[error("line {line}: {msg}")]
+pub struct TextFormatError {
+    /// 1 based
+    pub line: usize,
+    pub msg: String,
+}
+
+pub fn card_keyword(card: &Card) -> &'static str {
+    match card {
+        Card::Pass => "Pass",
+        Card::Add => "Add",
+        Card::Sub => "Sub",
+        Card::Mul => "Mul",
+        Card::Div => "Div",
+        Card::CopyLast => "CopyLast",
+        Card::Less => "Less",
+        Card::LessOrEq => "LessOrEq",
+        Card::Equals => "Equals",
+        Card::NotEquals => "NotEquals",
+        Card::Pop => "Pop",
+        Card::ClearStack => "ClearStack",
+        Card::And => "And",
+        Card::Or => "Or",
+        Card::Xor => "Xor",
+        Card::Not => "Not",
+        Card::Return => "Return",
+        Card::ScalarNil => "ScalarNil",
+        Card::CreateTable => "CreateTable",
+        Card::Len => "Len",
+        Card::Abort => "Abort",
+        Card::SetProperty => "SetProperty",
+        Card::GetProperty => "GetProperty",
+        Card::ScalarInt(_) => "ScalarInt",
+        Card::ScalarFloat(_) => "ScalarFloat",
+        Card::StringLiteral(_) => "StringLiteral",
+        Card::CallNative(_) => "CallNative",
+        Card::SetGlobalVar(_) => "SetGlobalVar",
+        Card::SetVar(_) => "SetVar",
+        Card::ReadVar(_) => "ReadVar",
+        Card::IfTrue(_) => "IfTrue",
+        Card::IfFalse(_) => "IfFalse",
+        Card::Jump(_) => "Jump",
+        Card::Repeat(_) => "Repeat",
+        Card::While(_) => "While",
+        Card::IfElse { .. } => "IfElse",
+        Card::ForEach { .. } => "ForEach",
+    }
+}
+
+/// Names printed without quotes, anything else is quoted so it reads back the same
+fn is_bare(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+fn quote(s: &str) -> String {
+    serde_json::to_string(s).unwrap_or_default()
+}
+
+fn name_token(s: &str) -> String {
+    if is_bare(s) {
+        s.to_string()
+    } else {
+        quote(s)
+    }
+}
+
+fn lane_ref(node: &LaneNode) -> String {
+    match node {
+        LaneNode::LaneId(i) => format!("#{}", i),
+        LaneNode::LaneName(name) => format!("@{}", name_token(name.as_str())),
+    }
+}
+
+/// The line of a single card, without indentation
+pub fn card_to_text(card: &Card) -> String {
+    let keyword = card_keyword(card);
+    let args = match card {
+        Card::ScalarInt(n) => n.0.to_string(),
+        Card::ScalarFloat(n) => format!("{:?}", n.0),
+        Card::StringLiteral(n) => quote(n.0.as_str()),
+        Card::CallNative(n) => name_token(n.0.as_str()),
+        Card::SetGlobalVar(v) | Card::SetVar(v) | Card::ReadVar(v) => name_token(&v.0.to_string()),
+        Card::IfTrue(l) | Card::IfFalse(l) | Card::Jump(l) | Card::Repeat(l) | Card::While(l) => {
+            lane_ref(l)
+        }
+        Card::IfElse { then, r#else } => format!("{} {}", lane_ref(then), lane_ref(r#else)),
+        Card::ForEach { variable, lane } => {
+            format!("{} {}", name_token(&variable.0.to_string()), lane_ref(lane))
+        }
+        _ => String::new(),
+    };
+    if args.is_empty() {
+        keyword.to_string()
+    } else {
+        format!("{} {}", keyword, args)
+    }
+}
+
+pub fn to_text(ir: &CaoIr) -> String {
+    let mut out = String::new();
+    for (i, lane) in ir.lanes.iter().enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        match lane.name.as_ref() {
+            Some(name) => writeln!(out, "lane {}:", name_token(name.as_str())),
+            None => writeln!(out, "lane:"),
+        }
+        .unwrap();
+        for card in lane.cards.iter() {
+            writeln!(out, "    {}", card_to_text(card)).unwrap();
+        }
+    }
+    out
+}
+
+/// Split a line into whitespace separated tokens, quoted tokens may contain whitespace
+fn tokenize(line: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = line.trim_start();
+    while !rest.is_empty() {
+        if rest.starts_with('#') && tokens.is_empty() {
+            break;
+        }
+        // `@"name"` is a single token
+        let prefix = if rest.starts_with("@\"") { "@" } else { "" };
+        let quoted = rest[prefix.len()..].starts_with('"');
+        let end = if quoted {
+            let s = &rest[prefix.len()..];
+            let mut escaped = false;
+            let mut end = None;
+            for (i, c) in s.char_indices().skip(1) {
+                match c {
+                    '\\' if !escaped => escaped = true,
+                    '"' if !escaped => {
+                        end = Some(prefix.len() + i + 1);
+                        break;
+                    }
+                    _ => escaped = false,
+                }
+            }
+            end.ok_or_else(|| "unterminated string".to_string())?
+        } else {
+            rest.find(char::is_whitespace).unwrap_or_else(|| rest.len())
+        };
+        let token = &rest[..end];
+        if quoted {
+            let s: String = serde_json::from_str(&token[prefix.len()..])
+                .map_err(|err| format!("invalid string {}: {}", token, err))?;
+            tokens.push(format!("{}\u{0}{}", prefix, s));
+        } else {
+            tokens.push(token.to_string());
+        }
+        rest = rest[end..].trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Quoted tokens are marked with a NUL after their prefix, so `"#1"` is not a lane id
+fn unquote(token: &str) -> (&str, bool) {
+    match token.find('\u{0}') {
+        Some(i) => (&token[i + 1..], true),
+        None => (token, false),
+    }
+}
+
+fn parse_lane_ref(token: &str) -> Result<LaneNode, String> {
+    let (value, quoted) = unquote(token);
+    if quoted {
+        return match token.strip_prefix('@') {
+            Some(_) => Ok(LaneNode::LaneName(value.to_string())),
+            None => Err(format!("expected a lane reference, found \"{}\"", value)),
+        };
+    }
+    if let Some(id) = value.strip_prefix('#') {
+        return id
+            .parse()
+            .map(LaneNode::LaneId)
+            .map_err(|_| format!("invalid lane index {}", value));
+    }
+    if let Some(name) = value.strip_prefix('@') {
+        if !name.is_empty() {
+            return Ok(LaneNode::LaneName(name.to_string()));
+        }
+    }
+    Err(format!(
+        "expected a lane reference (@name or #index), found {}",
+        value
+    ))
+}
+
+fn parse_var(token: &str) -> Result<VarNode, String> {
+    let (value, _) = unquote(token);
+    let name = VarName::from(value).map_err(|err| format!("invalid variable name: {:?}", err))?;
+    Ok(VarNode(Box::new(name)))
+}
+
+pub fn parse_card(tokens: &[String]) -> Result<Card, String> {
+    let (keyword, args) = match tokens.split_first() {
+        Some((k, args)) => (unquote(k).0, args),
+        None => return Err("expected a card".to_string()),
+    };
+    let arity = |n: usize| {
+        if args.len() == n {
+            Ok(())
+        } else {
+            Err(format!(
+                "{} expects {} argument(s), found {}",
+                keyword,
+                n,
+                args.len()
+            ))
+        }
+    };
+    let card = match keyword {
+        "Pass" => Card::Pass,
+        "Add" => Card::Add,
+        "Sub" => Card::Sub,
+        "Mul" => Card::Mul,
+        "Div" => Card::Div,
+        "CopyLast" => Card::CopyLast,
+        "Less" => Card::Less,
+        "LessOrEq" => Card::LessOrEq,
+        "Equals" => Card::Equals,
+        "NotEquals" => Card::NotEquals,
+        "Pop" => Card::Pop,
+        "ClearStack" => Card::ClearStack,
+        "And" => Card::And,
+        "Or" => Card::Or,
+        "Xor" => Card::Xor,
+        "Not" => Card::Not,
+        "Return" => Card::Return,
+        "ScalarNil" => Card::ScalarNil,
+        "CreateTable" => Card::CreateTable,
+        "Len" => Card::Len,
+        "Abort" => Card::Abort,
+        "SetProperty" => Card::SetProperty,
+        "GetProperty" => Card::GetProperty,
+        "ScalarInt" => {
+            arity(1)?;
+            let value = unquote(&args[0]).0;
+            let value = value
+                .parse()
+                .map_err(|_| format!("invalid integer {}", value))?;
+            Card::ScalarInt(IntegerNode(value))
+        }
+        "ScalarFloat" => {
+            arity(1)?;
+            let value = unquote(&args[0]).0;
+            let value = value
+                .parse()
+                .map_err(|_| format!("invalid number {}", value))?;
+            Card::ScalarFloat(FloatNode(value))
+        }
+        "StringLiteral" => {
+            arity(1)?;
+            Card::StringLiteral(StringNode(unquote(&args[0]).0.to_string()))
+        }
+        "CallNative" => {
+            arity(1)?;
+            let name = unquote(&args[0]).0;
+            let name = InputString::from(name)
+                .map_err(|err| format!("invalid function name {}: {:?}", name, err))?;
+            Card::CallNative(Box::new(CallNode(name)))
+        }
+        "SetGlobalVar" => {
+            arity(1)?;
+            Card::SetGlobalVar(parse_var(&args[0])?)
+        }
+        "SetVar" => {
+            arity(1)?;
+            Card::SetVar(parse_var(&args[0])?)
+        }
+        "ReadVar" => {
+            arity(1)?;
+            Card::ReadVar(parse_var(&args[0])?)
+        }
+        "IfTrue" => {
+            arity(1)?;
+            Card::IfTrue(parse_lane_ref(&args[0])?)
+        }
+        "IfFalse" => {
+            arity(1)?;
+            Card::IfFalse(parse_lane_ref(&args[0])?)
+        }
+        "Jump" => {
+            arity(1)?;
+            Card::Jump(parse_lane_ref(&args[0])?)
+        }
+        "Repeat" => {
+            arity(1)?;
+            Card::Repeat(parse_lane_ref(&args[0])?)
+        }
+        "While" => {
+            arity(1)?;
+            Card::While(parse_lane_ref(&args[0])?)
+        }
+        "IfElse" => {
+            arity(2)?;
+            Card::IfElse {
+                then: parse_lane_ref(&args[0])?,
+                r#else: parse_lane_ref(&args[1])?,
+            }
+        }
+        "ForEach" => {
+            arity(2)?;
+            Card::ForEach {
+                variable: parse_var(&args[0])?,
+                lane: parse_lane_ref(&args[1])?,
+            }
+        }
+        _ => return Err(format!("unknown card {}", keyword)),
+    };
+    Ok(card)
+}
+
+pub fn from_text(text: &str) -> Result<CaoIr, TextFormatError> {
+    let mut lanes: Vec<Lane> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let err = |msg: String| TextFormatError { line: i + 1, msg };
+        let tokens = tokenize(line).map_err(err)?;
+        if tokens.is_empty() {
+            continue;
+        }
+        if let Some(header) = lane_header(&tokens) {
+            let mut lane = Lane::default();
+            lane.name = header.map_err(err)?;
+            lanes.push(lane);
+            continue;
+        }
+        let card = parse_card(&tokens).map_err(err)?;
+        match lanes.last_mut() {
+            Some(lane) => lane.cards.push(card),
+            None => return Err(err("cards must be inside a lane".to_string())),
+        }
+    }
+    Ok(CaoIr { lanes })
+}
+
+/// `None` if the line is not a lane header
+fn lane_header(tokens: &[String]) -> Option<Result<Option<String>, String>> {
+    let first = unquote(&tokens[0]).0;
+    if tokens[0] == "lane:" {
+        return Some(if tokens.len() == 1 {
+            Ok(None)
+        } else {
+            Err("unexpected tokens after the lane header".to_string())
+        });
+    }
+    if first != "lane" || tokens.len() == 1 {
+        return None;
+    }
+    let res = match tokens.len() {
+        // `lane name:` or `lane "quoted name":`
+        2 => match tokens[1].strip_suffix(':') {
+            Some(name) => Ok(Some(name.to_string())),
+            None => Err("expected ':' after the lane name".to_string()),
+        },
+        3 if tokens[2] == ":" => Ok(Some(unquote(&tokens[1]).0.to_string())),
+        _ => Err("expected `lane <name>:`".to_string()),
+    };
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_round_trip(ir: &CaoIr) {
+        let text = to_text(ir);
+        let parsed = from_text(text.as_str()).unwrap_or_else(|err| panic!("{}\n{}", err, text));
+        assert_eq!(
+            serde_json::to_value(&parsed).unwrap(),
+            serde_json::to_value(ir).unwrap(),
+            "{}",
+            text
+        );
+    }
+
+    #[test]
+    fn names_round_trip() {
+        let names = [
+            "Main",
+            "snake_case-1.2",
+            "two words",
+            "a:b",
+            "lane:",
+            "lane",
+            "\"quoted\"",
+            "mid\"quote",
+            "#1",
+            "@at",
+            "",
+            "tab\tand\nnewline",
+            "nul\u{0}byte",
+            "ünïcödé",
+        ];
+        let mut lanes: Vec<Lane> = names
+            .iter()
+            .map(|name| {
+                let mut lane = Lane::default();
+                lane.name = Some(name.to_string());
+                lane.cards
+                    .push(Card::Jump(LaneNode::LaneName(name.to_string())));
+                lane.cards
+                    .push(Card::StringLiteral(StringNode(name.to_string())));
+                lane
+            })
+            .collect();
+        let mut unnamed = Lane::default();
+        unnamed.cards.push(Card::IfElse {
+            then: LaneNode::LaneId(0),
+            r#else: LaneNode::LaneName("two words".to_string()),
+        });
+        lanes.push(unnamed);
+        assert_round_trip(&CaoIr { lanes });
+    }
+}
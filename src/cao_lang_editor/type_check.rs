@@ -0,0 +1,161 @@
+//! Client side stack type simulation using the schema's `inputs` and `outputs`
+//!
+//! Every card pops its inputs, the last input being the top of the stack, then pushes its outputs.
+//! The first lane starts with an empty stack, other lanes may be called with arguments so what
+//! is below their first value is unknown. Cards calling other lanes leave the stack unknown.
+
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use cao_lang::compiler::{CaoIr, Card};
+
+use super::{text_format::card_keyword, CurrentProgram};
+use crate::cao_lang_client::{cao_lang_model::SchemaNode, CaoLangSchema};
+
+/// Type names accepting or providing any value
+const WILDCARDS: &[&str] = &["any", "value", "*", ""];
+
+/// Explanations by `(lane, card)` index
+#[derive(Default)]
+pub struct StackTypeErrors(pub HashMap<(usize, usize), String>);
+
+/// Name of the schema node the card was created from
+fn schema_name(card: &Card) -> &str {
+    match card {
+        Card::CallNative(node) => node.0.as_str(),
+        Card::SetVar(_) => "SetLocalVar",
+        _ => card_keyword(card),
+    }
+}
+
+/// Whether the card was created from the schema node
+pub fn node_matches(node: &SchemaNode, card: &Card) -> bool {
+    let is_call = matches!(card, Card::CallNative(_));
+    (node.ty == "Call") == is_call && node.name == schema_name(card)
+}
+
+fn find_node<'a>(schema: &'a CaoLangSchema, card: &Card) -> Option<&'a SchemaNode> {
+    schema.0.iter().find(|node| node_matches(node, card))
+}
+
+fn compatible(required: &str, provided: &str) -> bool {
+    let is_wildcard = |ty: &str| WILDCARDS.contains(&ty.to_lowercase().as_str());
+    is_wildcard(required) || is_wildcard(provided) || required.eq_ignore_ascii_case(provided)
+}
+
+/// Whether the card transfers control to another lane
+fn calls_lane(card: &Card) -> bool {
+    matches!(
+        card,
+        Card::IfTrue(_)
+            | Card::IfFalse(_)
+            | Card::IfElse { .. }
+            | Card::Jump(_)
+            | Card::Repeat(_)
+            | Card::While(_)
+            | Card::ForEach { .. }
+    )
+}
+
+struct Value {
+    ty: String,
+    /// index of the card that pushed the value
+    card: usize,
+}
+
+#[derive(Default)]
+struct Stack {
+    values: Vec<Value>,
+    /// what is below `values` is unknown, missing inputs may be there
+    unknown_base: bool,
+}
+
+impl Stack {
+    fn reset(&mut self, unknown_base: bool) {
+        self.values.clear();
+        self.unknown_base = unknown_base;
+    }
+}
+
+fn check_card(stack: &mut Stack, card_index: usize, node: &SchemaNode) -> Option<String> {
+    let mut error = None;
+    for (i, required) in node.input.iter().enumerate().rev() {
+        match stack.values.pop() {
+            Some(value) => {
+                if error.is_none() && !compatible(required, value.ty.as_str()) {
+                    error = Some(format!(
+                        "Input {} expects {}, but card #{} pushes {}",
+                        i + 1,
+                        required,
+                        value.card,
+                        value.ty
+                    ));
+                }
+            }
+            None if stack.unknown_base => {}
+            None => {
+                if error.is_none() {
+                    error = Some(format!(
+                        "Input {} ({}) is not produced by the preceding cards, {} input(s) are required",
+                        i + 1,
+                        required,
+                        node.input.len()
+                    ));
+                }
+            }
+        }
+    }
+    stack.values.extend(node.output.iter().map(|ty| Value {
+        ty: ty.clone(),
+        card: card_index,
+    }));
+    error
+}
+
+pub fn check_program(ir: &CaoIr, schema: &CaoLangSchema) -> StackTypeErrors {
+    let mut errors = HashMap::new();
+    let mut stack = Stack::default();
+    for (lane_index, lane) in ir.lanes.iter().enumerate() {
+        stack.reset(lane_index != 0);
+        for (card_index, card) in lane.cards.iter().enumerate() {
+            match card {
+                Card::ClearStack => {
+                    stack.reset(false);
+                    continue;
+                }
+                // unknown stack effect
+                Card::Return | Card::Abort => break,
+                _ => {}
+            }
+            match find_node(schema, card) {
+                Some(node) => {
+                    if let Some(err) = check_card(&mut stack, card_index, node) {
+                        errors.insert((lane_index, card_index), err);
+                    }
+                }
+                None => stack.reset(true),
+            }
+            if calls_lane(card) {
+                stack.reset(true);
+            }
+        }
+    }
+    StackTypeErrors(errors)
+}
+
+pub fn type_check_system(
+    ir: Res<CurrentProgram>,
+    schema: Res<CaoLangSchema>,
+    mut errors: ResMut<StackTypeErrors>,
+    mut last_key: Local<serde_hashkey::Key>,
+) {
+    let key = match serde_hashkey::to_key(&ir.0) {
+        Ok(key) => key,
+        Err(_) => return,
+    };
+    if key == *last_key && !schema.is_changed() {
+        return;
+    }
+    *last_key = key;
+    *errors = check_program(&ir.0, &*schema);
+}
@@ -3,7 +3,9 @@
 
 pub mod cao_client;
 pub mod cao_sim_model;
+pub mod replay;
 pub mod terrain_model;
+pub mod wire_format;
 
 use anyhow::Context;
 use bevy::{
@@ -25,10 +27,17 @@ use std::{
 use self::{
     cao_client::CaoClient,
     cao_sim_model::{AxialPos, TerrainTy},
+    replay::{ReplayConfig, SessionRecorder},
+    wire_format::WireFormat,
+};
+use crate::{
+    account::{AuthSession, AuthToken, CurrentAuthToken},
+    api_client::{self, ApiErrorEvent, ApiResult, Retry},
+    server_config::{arg_or_env, ServerConfig, ServerProfileChanged},
 };
 
 pub struct CaoSimPlugin;
-pub struct NewEntities(pub Arc<cao_sim_model::EntitiesPayload>);
+pub struct NewEntities(pub EntitiesUpdate);
 pub struct NewTerrain {
     pub room_id: AxialPos,
     pub offset: AxialPos,
@@ -36,6 +45,42 @@ pub struct NewTerrain {
 }
 pub struct Connected;
 pub struct TerrainLayout(pub Vec<AxialPos>);
+type LayoutTask = Task<ApiResult<TerrainLayout>>;
+
+/// Seconds before retrying the first failed layout fetch, doubled on every further failure
+const LAYOUT_RETRY_BASE_SECS: f32 = 2.0;
+const LAYOUT_RETRY_MAX_SECS: f32 = 60.0;
+
+/// Backoff of the layout fetch, the stream listener can not start without a layout
+#[derive(Default)]
+struct LayoutRetry {
+    timer: Option<Timer>,
+    attempt: i32,
+}
+
+#[derive(Debug, Clone)]
+pub enum EntitiesUpdate {
+    /// Every entity in the room
+    Snapshot(Arc<cao_sim_model::EntitiesPayload>),
+    /// Changes since the previous update of the room
+    Delta(Arc<cao_sim_model::EntitiesDeltaPayload>),
+}
+
+impl EntitiesUpdate {
+    pub fn time(&self) -> i64 {
+        match self {
+            EntitiesUpdate::Snapshot(pl) => pl.time,
+            EntitiesUpdate::Delta(pl) => pl.time,
+        }
+    }
+
+    pub fn room_id(&self) -> AxialPos {
+        match self {
+            EntitiesUpdate::Snapshot(pl) => pl.room_id,
+            EntitiesUpdate::Delta(pl) => pl.room_id,
+        }
+    }
+}
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[repr(usize)]
@@ -44,6 +89,8 @@ pub enum ConnectionState {
     Online = 1,
     Closed = 2,
     Error = 3,
+    /// The server rejected the credentials
+    Unauthorized = 4,
 }
 
 #[derive(Debug, Clone)]
@@ -66,6 +113,7 @@ impl ConnectionStateRes {
             1 => ConnectionState::Online,
             2 => ConnectionState::Closed,
             3 => ConnectionState::Error,
+            4 => ConnectionState::Unauthorized,
             _ => unreachable!(),
         }
     }
@@ -102,15 +150,14 @@ fn send_connected_event_system(recv: Res<ConnectedRcv>, mut on_reconnect: EventW
 }
 
 fn handle_message(
-    txt: &str,
+    format: WireFormat,
+    payload: &[u8],
     terrain_sender: &crossbeam::channel::Sender<NewTerrain>,
     entities_sender: &crossbeam::channel::Sender<NewEntities>,
     layout: &[AxialPos],
 ) -> anyhow::Result<()> {
-    trace!("Incoming message");
-    let msg = serde_json::from_str::<cao_sim_model::Message>(txt)
-        .with_context(|| "Failed to deserialize msg")?;
-    match msg {
+    trace!("Incoming {} message", format);
+    match format.decode(payload)? {
         cao_sim_model::Message::Terrain(Some(terrain)) => {
             info!(
                 "Got terrain for room: {:?}, offset: {:?}",
@@ -133,7 +180,16 @@ fn handle_message(
         cao_sim_model::Message::Entities(ent) => {
             debug!("New entities, time: {}, room: {:?}", ent.time, ent.room_id);
             entities_sender
-                .send(NewEntities(Arc::new(ent)))
+                .send(NewEntities(EntitiesUpdate::Snapshot(Arc::new(ent))))
+                .with_context(|| "Failed to send new entities")?;
+        }
+        cao_sim_model::Message::EntitiesDelta(delta) => {
+            debug!(
+                "New entities delta, time: {}, room: {:?}",
+                delta.time, delta.room_id
+            );
+            entities_sender
+                .send(NewEntities(EntitiesUpdate::Delta(Arc::new(delta))))
                 .with_context(|| "Failed to send new entities")?;
         }
     }
@@ -142,6 +198,8 @@ fn handle_message(
 
 async fn listen_to_cao_rt(
     layout: Vec<AxialPos>,
+    mut ws_url: tokio::sync::watch::Receiver<String>,
+    mut auth_token: tokio::sync::watch::Receiver<Option<AuthToken>>,
     state: ConnectionStateRes,
     runtime: Arc<tokio::runtime::Runtime>,
     msg_recv: crossbeam::channel::Receiver<tungstenite::Message>,
@@ -149,18 +207,35 @@ async fn listen_to_cao_rt(
     entities_sender: crossbeam::channel::Sender<NewEntities>,
     terrain_sender: crossbeam::channel::Sender<NewTerrain>,
     reconnect_sender: crossbeam::channel::Sender<Connected>,
+    mut recorder: Option<SessionRecorder>,
 ) {
+    let wire_formats = offered_wire_formats();
     let mut backoff = 1;
     loop {
         info!("Connecting to caosim stream");
 
         state.store(ConnectionState::Connecting, Ordering::Release);
 
+        let url = ws_url.borrow_and_update().clone();
+        // re-read on every reconnect, the token might have been refreshed since
+        let token = auth_token.borrow_and_update().clone();
         let ws_stream;
-        match get_connection().await {
-            Ok(s) => {
+        let format;
+        match get_connection(url.as_str(), token.as_deref(), wire_formats.as_slice()).await {
+            Ok((s, f)) => {
                 backoff = 1;
                 ws_stream = s;
+                format = f;
+            }
+            Err(tungstenite::Error::Http(resp)) if is_auth_failure(resp.status()) => {
+                warn!("Object-stream rejected the credentials ({})", resp.status());
+                state.store(ConnectionState::Unauthorized, Ordering::Release);
+                // retrying with the same credentials is pointless
+                futures::select! {
+                    _ = auth_token.changed().fuse() => info!("Credentials changed, reconnecting"),
+                    _ = ws_url.changed().fuse() => info!("Server changed, reconnecting"),
+                };
+                continue;
             }
             Err(_) => {
                 debug!("Retrying");
@@ -178,17 +253,57 @@ async fn listen_to_cao_rt(
         let msg_sender = runtime.spawn(msg_sender(msg_recv.clone(), tx));
         tokio::task::yield_now().await;
 
-        info!("Successfully connected to caosim stream");
+        info!("Successfully connected to caosim stream, using {}", format);
         state.store(ConnectionState::Online, Ordering::Release);
         reconnect_sender.send(Connected).unwrap();
 
         let entities_sender = entities_sender.clone();
         let terrain_sender = terrain_sender.clone();
-        while let Some(msg) = rx.next().await {
+        let mut unauthorized = false;
+        loop {
+            let msg = futures::select! {
+                msg = rx.next().fuse() => msg,
+                _ = ws_url.changed().fuse() => {
+                    info!("Server changed, reconnecting");
+                    break;
+                }
+                _ = auth_token.changed().fuse() => {
+                    info!("Credentials changed, reconnecting");
+                    break;
+                }
+            };
+            let msg = match msg {
+                Some(msg) => msg,
+                None => break,
+            };
             match msg {
                 Ok(tungstenite::Message::Text(txt)) => {
+                    if let Some(rec) = recorder.as_mut() {
+                        if let Err(err) = rec.record_text(txt.as_str()) {
+                            error!("Failed to record message, stopping the recording {:?}", err);
+                            recorder = None;
+                        }
+                    }
+                    if let Err(err) = handle_message(
+                        WireFormat::Json,
+                        txt.as_bytes(),
+                        &terrain_sender,
+                        &entities_sender,
+                        layout.as_slice(),
+                    ) {
+                        error!("Failed to handle message {:?}", err);
+                    }
+                }
+                Ok(tungstenite::Message::Binary(payload)) => {
+                    if let Some(rec) = recorder.as_mut() {
+                        if let Err(err) = rec.record_binary(format, payload.as_slice()) {
+                            error!("Failed to record message, stopping the recording {:?}", err);
+                            recorder = None;
+                        }
+                    }
                     if let Err(err) = handle_message(
-                        txt.as_str(),
+                        format,
+                        payload.as_slice(),
                         &terrain_sender,
                         &entities_sender,
                         layout.as_slice(),
@@ -196,6 +311,12 @@ async fn listen_to_cao_rt(
                         error!("Failed to handle message {:?}", err);
                     }
                 }
+                Ok(tungstenite::Message::Close(Some(frame)))
+                    if frame.code == tungstenite::protocol::frame::coding::CloseCode::Policy =>
+                {
+                    warn!("Object-stream closed the session: {}", frame.reason);
+                    unauthorized = true;
+                }
                 Ok(tungstenite::Message::Pong(_)) => {
                     trace!("Server pong received")
                 }
@@ -212,7 +333,14 @@ async fn listen_to_cao_rt(
                 }
             }
         }
-        state.store(ConnectionState::Closed, Ordering::Release);
+        state.store(
+            if unauthorized {
+                ConnectionState::Unauthorized
+            } else {
+                ConnectionState::Closed
+            },
+            Ordering::Release,
+        );
         msg_sender.abort(); // abort this future, otherwise we might send events to it that it can not handle in the future
     }
 }
@@ -220,18 +348,44 @@ async fn listen_to_cao_rt(
 fn handle_tasks_system(
     mut commands: Commands,
     mut layout: ResMut<TerrainLayout>,
-    q: Query<(Entity, &mut Task<TerrainLayout>)>,
+    q: Query<(Entity, &mut LayoutTask)>,
     //
     client: Res<CaoClient>,
     state: Res<ConnectionStateRes>,
+    replay_config: Res<ReplayConfig>,
+    mut retry: ResMut<LayoutRetry>,
+    mut api_errors: EventWriter<ApiErrorEvent>,
 ) {
     q.for_each_mut(|(e, mut t)| {
-        if let Some(stuff) = future::block_on(future::poll_once(&mut *t)) {
-            *layout = stuff;
-            commands.entity(e).remove::<Task<TerrainLayout>>();
+        if let Some(res) = future::block_on(future::poll_once(&mut *t)) {
+            commands.entity(e).remove::<LayoutTask>();
+            match res {
+                Ok(l) => {
+                    *layout = l;
+                    *retry = LayoutRetry::default();
+                }
+                Err(err) => {
+                    let secs = (LAYOUT_RETRY_BASE_SECS * 2f32.powi(retry.attempt))
+                        .min(LAYOUT_RETRY_MAX_SECS);
+                    debug!("Retrying the layout fetch in {} seconds", secs);
+                    retry.timer = Some(Timer::from_seconds(secs, false));
+                    retry.attempt += 1;
+                    state.store(ConnectionState::Error, Ordering::Release);
+                    api_errors.send(ApiErrorEvent::new("Fetching the world layout", err));
+                    return;
+                }
+            }
+
+            let recorder = replay_config.record.as_ref().and_then(|path| {
+                SessionRecorder::open(path, layout.0.as_slice())
+                    .map_err(|err| error!("Failed to start recording {:?}", err))
+                    .ok()
+            });
 
             client.runtime.spawn(listen_to_cao_rt(
                 layout.0.clone(),
+                client.ws_url.1.clone(),
+                client.auth_token.1.clone(),
                 state.clone(),
                 client.runtime.clone(),
                 client.send_message.1.clone(),
@@ -239,18 +393,48 @@ fn handle_tasks_system(
                 client.on_new_entities.0.clone(),
                 client.on_new_terrain.0.clone(),
                 client.on_connected.0.clone(),
+                recorder,
             ));
         }
     });
 }
 
-async fn get_layout(q: &GetLayoutQuery) -> Vec<AxialPos> {
-    surf::get(format!("{}/world/room-terrain-layout", crate::API_BASE_URL))
-        .query(q)
-        .expect("Failed to set query param")
-        .recv_json()
-        .await
-        .expect("Failed to get layout")
+fn setup_replay_system(
+    replay_config: Res<ReplayConfig>,
+    client: Res<CaoClient>,
+    state: Res<ConnectionStateRes>,
+) {
+    let path = match replay_config.replay.clone() {
+        Some(p) => p,
+        None => return,
+    };
+    let state = state.clone();
+    let msg_recv = client.send_message.1.clone();
+    let entities_sender = client.on_new_entities.0.clone();
+    let terrain_sender = client.on_new_terrain.0.clone();
+    let reconnect_sender = client.on_connected.0.clone();
+    std::thread::spawn(move || {
+        if let Err(err) = replay::play_session(
+            path,
+            state.clone(),
+            msg_recv,
+            entities_sender,
+            terrain_sender,
+            reconnect_sender,
+        ) {
+            error!("Replay failed {:?}", err);
+            state.store(ConnectionState::Error, Ordering::Release);
+        }
+    });
+}
+
+async fn get_layout(api_url: &str, q: &GetLayoutQuery) -> ApiResult<Vec<AxialPos>> {
+    api_client::send_json(Retry::Transient, || {
+        surf::get(format!("{}/world/room-terrain-layout", api_url))
+            .query(q)
+            .map_err(|err| api_client::CaoApiError::Request(err.to_string()))
+    })
+    .await
 }
 
 /// Fire NewEntities event and reset current_entities
@@ -259,24 +443,73 @@ fn send_new_entities_system(
     mut on_new_entities: EventWriter<NewEntities>,
 ) {
     while let Ok(entities) = recv.0.recv_timeout(Duration::from_micros(1)) {
-        let entities = entities.0;
-        on_new_entities.send(NewEntities(entities.clone()));
+        on_new_entities.send(entities);
     }
 }
 
-async fn get_connection() -> Result<Ws, tungstenite::error::Error> {
-    async_tungstenite::tokio::connect_async(
-        format!("{}/object-stream", crate::WS_BASE_URL).as_str(),
-    )
-    .await
-    .map(|(stream, _resp)| {
-        debug!("Successfully connected to object-stream");
-        stream
-    })
-    .map_err(|err| {
-        error!("Failed to connect to object-stream {:?}", err);
-        err
-    })
+/// Formats to offer in the handshake, `--wire-format` or `CAO_WIRE_FORMAT` restricts it to a
+/// single one
+fn offered_wire_formats() -> Vec<WireFormat> {
+    match arg_or_env("wire-format", "CAO_WIRE_FORMAT").map(|f| f.parse::<WireFormat>()) {
+        Some(Ok(format)) => vec![format],
+        Some(Err(err)) => {
+            warn!("{}, offering all formats", err);
+            WireFormat::ALL.to_vec()
+        }
+        None => WireFormat::ALL.to_vec(),
+    }
+}
+
+fn is_auth_failure(status: tungstenite::http::StatusCode) -> bool {
+    status == tungstenite::http::StatusCode::UNAUTHORIZED
+        || status == tungstenite::http::StatusCode::FORBIDDEN
+}
+
+async fn get_connection(
+    ws_url: &str,
+    auth_token: Option<&str>,
+    formats: &[WireFormat],
+) -> Result<(Ws, WireFormat), tungstenite::error::Error> {
+    use tungstenite::{
+        client::IntoClientRequest,
+        http::{header::AUTHORIZATION, HeaderValue},
+    };
+
+    let mut request = format!("{}/object-stream", ws_url).into_client_request()?;
+    if let Some(token) = auth_token {
+        match HeaderValue::from_str(token) {
+            Ok(value) => {
+                request.headers_mut().insert(AUTHORIZATION, value);
+            }
+            Err(err) => error!("Invalid auth token {:?}", err),
+        }
+    }
+    request.headers_mut().insert(
+        wire_format::SUBPROTOCOL_HEADER,
+        HeaderValue::from_str(WireFormat::offer(formats).as_str())
+            .expect("Failed to build subprotocol header"),
+    );
+    request.headers_mut().insert(
+        wire_format::ENTITY_DELTAS_HEADER,
+        HeaderValue::from_static("1"),
+    );
+    async_tungstenite::tokio::connect_async(request)
+        .await
+        .map(|(stream, resp)| {
+            debug!("Successfully connected to object-stream");
+            // servers unaware of the negotiation don't answer and speak json
+            let format = resp
+                .headers()
+                .get(wire_format::SUBPROTOCOL_HEADER)
+                .and_then(|h| h.to_str().ok())
+                .and_then(WireFormat::from_subprotocol)
+                .unwrap_or_default();
+            (stream, format)
+        })
+        .map_err(|err| {
+            error!("Failed to connect to object-stream {:?}", err);
+            err
+        })
 }
 
 async fn msg_sender<S>(msg_recv: crossbeam::channel::Receiver<tungstenite::Message>, mut tx: S)
@@ -302,29 +535,131 @@ where
     }
 }
 
-fn setup_layout_task_system(mut commands: Commands, task_pool: Res<IoTaskPool>) {
-    let handle = task_pool.spawn(async move {
-        let res = get_layout(&GetLayoutQuery {
-            radius: 30, // TODO
-        })
-        .await;
-        TerrainLayout(res)
+fn spawn_layout_task(commands: &mut Commands, task_pool: &IoTaskPool, api_url: String) {
+    let handle: LayoutTask = task_pool.spawn(async move {
+        let res = get_layout(
+            api_url.as_str(),
+            &GetLayoutQuery {
+                radius: 30, // TODO
+            },
+        )
+        .await?;
+        Ok(TerrainLayout(res))
     });
 
     commands.spawn().insert(handle);
 }
 
+fn setup_layout_task_system(
+    mut commands: Commands,
+    task_pool: Res<IoTaskPool>,
+    config: Res<ServerConfig>,
+) {
+    spawn_layout_task(
+        &mut commands,
+        &*task_pool,
+        config.current().api_base_url.clone(),
+    );
+}
+
+fn retry_layout_system(
+    mut commands: Commands,
+    time: Res<Time>,
+    task_pool: Res<IoTaskPool>,
+    config: Res<ServerConfig>,
+    mut retry: ResMut<LayoutRetry>,
+) {
+    let due = match retry.timer.as_mut() {
+        Some(timer) => timer.tick(time.delta()).finished(),
+        None => return,
+    };
+    if due {
+        retry.timer = None;
+        spawn_layout_task(
+            &mut commands,
+            &*task_pool,
+            config.current().api_base_url.clone(),
+        );
+    }
+}
+
+/// Forward the selected websocket url to the stream listener
+fn sync_ws_url_system(config: Res<ServerConfig>, client: Res<CaoClient>) {
+    if config.is_changed() {
+        let url = config.current().ws_base_url.clone();
+        if *client.ws_url.1.borrow() != url {
+            client.ws_url.0.send(url).unwrap_or_default();
+        }
+    }
+}
+
+/// Forward the logged in user's token to the stream listener, if it was issued by the server the
+/// stream connects to
+fn sync_auth_token_system(
+    token: Res<CurrentAuthToken>,
+    session: Res<AuthSession>,
+    config: Res<ServerConfig>,
+    client: Res<CaoClient>,
+) {
+    if !token.is_changed() && !session.is_changed() && !config.is_changed() {
+        return;
+    }
+    let token = token
+        .0
+        .clone()
+        .filter(|_| session.api_url == config.current().api_base_url);
+    if *client.auth_token.1.borrow() != token {
+        client.auth_token.0.send(token).unwrap_or_default();
+    }
+}
+
+/// The stream listener is only started once the layout is known, so if the previous server failed
+/// to provide one we have to retry with the new server
+fn on_server_changed_system(
+    mut commands: Commands,
+    mut events: EventReader<ServerProfileChanged>,
+    task_pool: Res<IoTaskPool>,
+    config: Res<ServerConfig>,
+    layout: Res<TerrainLayout>,
+    mut retry: ResMut<LayoutRetry>,
+    tasks: Query<Entity, With<LayoutTask>>,
+) {
+    if events.iter().last().is_none() || !layout.0.is_empty() {
+        return;
+    }
+    *retry = LayoutRetry::default();
+    for e in tasks.iter() {
+        commands.entity(e).remove::<LayoutTask>();
+    }
+    spawn_layout_task(
+        &mut commands,
+        &*task_pool,
+        config.current().api_base_url.clone(),
+    );
+}
+
 impl Plugin for CaoSimPlugin {
     fn build(&self, app: &mut AppBuilder) {
         let client = CaoClient::new();
-        app.add_startup_system(setup_layout_task_system.system())
-            .add_event::<NewEntities>()
+        let replay_config = ReplayConfig::load();
+        if replay_config.replay.is_some() {
+            app.add_startup_system(setup_replay_system.system());
+        } else {
+            app.add_startup_system(setup_layout_task_system.system())
+                .add_system(retry_layout_system.system())
+                .add_system(on_server_changed_system.system());
+        }
+        app.add_event::<NewEntities>()
             .add_event::<NewTerrain>()
             .add_event::<Connected>()
             .add_system(send_new_entities_system.system())
             .add_system(send_new_terrain_system.system())
             .add_system(send_connected_event_system.system())
             .add_system(handle_tasks_system.system())
+            .add_system(sync_ws_url_system.system())
+            .add_system(sync_auth_token_system.system())
+            .insert_resource(replay_config)
+            .init_resource::<LayoutRetry>()
             .insert_resource(TerrainLayout(Vec::with_capacity(10000)))
             .insert_resource(NewEntitiesRcv(client.on_new_entities.1.clone()))
             .insert_resource(NewTerrainRcv(client.on_new_terrain.1.clone()))
@@ -2,6 +2,8 @@
 
 use std::sync::Arc;
 
+use crate::{account::AuthToken, server_config::ServerProfile};
+
 use super::{cao_sim_model::AxialPos, Connected, NewEntities, NewTerrain};
 
 #[derive(Clone)]
@@ -23,6 +25,16 @@ pub struct CaoClient {
         crossbeam::channel::Sender<Connected>,
         crossbeam::channel::Receiver<Connected>,
     ),
+    /// websocket url of the currently selected server
+    pub ws_url: (
+        Arc<tokio::sync::watch::Sender<String>>,
+        tokio::sync::watch::Receiver<String>,
+    ),
+    /// token sent in the object-stream handshake, `None` connects anonymously
+    pub auth_token: (
+        Arc<tokio::sync::watch::Sender<Option<AuthToken>>>,
+        tokio::sync::watch::Receiver<Option<AuthToken>>,
+    ),
 }
 
 impl CaoClient {
@@ -36,12 +48,17 @@ impl CaoClient {
         let on_new_terrain = crossbeam::channel::bounded(4);
         let on_reconnect = crossbeam::channel::bounded(2);
         let send_message = crossbeam::channel::bounded(64);
+        let (ws_url_tx, ws_url_rx) =
+            tokio::sync::watch::channel(ServerProfile::local().ws_base_url);
+        let (auth_token_tx, auth_token_rx) = tokio::sync::watch::channel(None);
         Self {
             runtime,
             on_new_entities,
             send_message,
             on_new_terrain,
             on_connected: on_reconnect,
+            ws_url: (Arc::new(ws_url_tx), ws_url_rx),
+            auth_token: (Arc::new(auth_token_tx), auth_token_rx),
         }
     }
 
@@ -1,19 +1,21 @@
 use super::hex_axial_to_pixel;
 
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, serde::Deserialize)]
 pub struct GetLayoutQuery {
     pub radius: i32,
 }
 
-#[derive(Debug, Clone, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 #[serde(tag = "ty", content = "payload")]
 pub enum Message {
     Entities(EntitiesPayload),
+    /// Changes since the previous entities message of the room
+    EntitiesDelta(EntitiesDeltaPayload),
     Terrain(Option<TerrainPayload>),
 }
 
-#[derive(Default, Debug, Clone, serde::Deserialize)]
+#[derive(Default, Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct TerrainPayload {
     pub room_id: AxialPos,
@@ -29,7 +31,7 @@ pub enum TerrainTy {
     Bridge,
 }
 
-#[derive(Default, Debug, Clone, serde::Deserialize)]
+#[derive(Default, Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 #[serde(default)]
 pub struct EntitiesPayload {
@@ -40,7 +42,75 @@ pub struct EntitiesPayload {
     pub resources: Vec<Resource>,
 }
 
-#[derive(Default, Debug, Clone, PartialEq, serde::Deserialize, Eq, Hash)]
+#[derive(Default, Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(default)]
+pub struct EntitiesDeltaPayload {
+    pub time: i64,
+    pub room_id: AxialPos,
+    pub bots: EntityDelta<Bot>,
+    pub structures: EntityDelta<Structure>,
+    pub resources: EntityDelta<Resource>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(default)]
+pub struct EntityDelta<T> {
+    pub spawned: Vec<T>,
+    /// entities with any changed field, sent in full
+    pub updated: Vec<T>,
+    /// ids of the entities no longer in the room
+    pub removed: Vec<u64>,
+}
+
+impl<T> Default for EntityDelta<T> {
+    fn default() -> Self {
+        Self {
+            spawned: Vec::new(),
+            updated: Vec::new(),
+            removed: Vec::new(),
+        }
+    }
+}
+
+impl<T> EntityDelta<T> {
+    pub fn is_empty(&self) -> bool {
+        self.spawned.is_empty() && self.updated.is_empty() && self.removed.is_empty()
+    }
+
+    fn apply(&self, entities: &mut Vec<T>, id: impl Fn(&T) -> u64)
+    where
+        T: Clone,
+    {
+        entities.retain(|e| !self.removed.contains(&id(e)));
+        for e in self.spawned.iter().chain(self.updated.iter()) {
+            match entities.iter_mut().find(|x| id(x) == id(e)) {
+                Some(x) => *x = e.clone(),
+                None => entities.push(e.clone()),
+            }
+        }
+    }
+}
+
+impl EntitiesDeltaPayload {
+    pub fn is_empty(&self) -> bool {
+        self.bots.is_empty() && self.structures.is_empty() && self.resources.is_empty()
+    }
+}
+
+impl EntitiesPayload {
+    /// Snapshot of the room after applying the delta
+    pub fn apply_delta(&mut self, delta: &EntitiesDeltaPayload) {
+        debug_assert_eq!(self.room_id, delta.room_id);
+        self.time = delta.time;
+        delta.bots.apply(&mut self.bots, |b| b.id);
+        delta.structures.apply(&mut self.structures, |s| s.id);
+        delta.resources.apply(&mut self.resources, |r| r.id);
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, Eq, Hash)]
 #[serde(default)]
 #[serde(rename_all = "camelCase")]
 pub struct EntityPosition {
@@ -65,7 +135,7 @@ impl std::fmt::Display for AxialPos {
     }
 }
 
-#[derive(Default, Debug, Clone, serde::Deserialize)]
+#[derive(Default, Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(default)]
 #[serde(rename_all = "camelCase")]
 pub struct Bot {
@@ -82,19 +152,19 @@ pub struct Bot {
     pub dropoff_intent: Option<DropoffIntent>,
 }
 
-#[derive(Default, Debug, Clone, serde::Deserialize)]
+#[derive(Default, Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DropoffIntent {
     pub target_id: u64,
 }
 
-#[derive(Default, Debug, Clone, serde::Deserialize)]
+#[derive(Default, Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MineIntent {
     pub target_id: u64,
 }
 
-#[derive(Default, Debug, Clone, serde::Deserialize)]
+#[derive(Default, Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(default)]
 #[serde(rename_all = "camelCase")]
 pub struct BoundedValue {
@@ -102,21 +172,21 @@ pub struct BoundedValue {
     pub value_max: i64,
 }
 
-#[derive(Default, Debug, Clone, serde::Deserialize)]
+#[derive(Default, Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(default)]
 #[serde(rename_all = "camelCase")]
 pub struct Script {
     pub data: String,
 }
 
-#[derive(Default, Debug, Clone, PartialEq, serde::Deserialize)]
+#[derive(Default, Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 #[serde(default)]
 pub struct Owner {
     pub data: String,
 }
 
-#[derive(Default, Debug, Clone, serde::Deserialize)]
+#[derive(Default, Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 #[serde(default)]
 pub struct Decay {
@@ -125,7 +195,7 @@ pub struct Decay {
     pub time_remaining: i64,
 }
 
-#[derive(Debug, Clone, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Structure {
     pub id: u64,
@@ -134,18 +204,21 @@ pub struct Structure {
     pub energy: Option<BoundedValue>,
     pub energy_regen: Option<i64>,
     pub owner: Option<Owner>,
+    /// spawns run this script on the bots they spawn
+    #[serde(default)]
+    pub script: Option<Script>,
     #[serde(rename = "StructureBody")]
     pub structure_body: StructureBody,
 }
 
-#[derive(Debug, Clone, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub enum StructureBody {
     #[serde(rename = "Spawn")]
     Spawn(Spawn),
 }
 
-#[derive(Default, Debug, Clone, serde::Deserialize)]
+#[derive(Default, Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 #[serde(default)]
 pub struct Spawn {
@@ -154,7 +227,7 @@ pub struct Spawn {
     pub spawn_queue: Vec<u64>,
 }
 
-#[derive(Default, Debug, Clone, serde::Deserialize)]
+#[derive(Default, Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 #[serde(default)]
 pub struct Resource {
@@ -164,7 +237,7 @@ pub struct Resource {
     pub resource_type: ResourceType,
 }
 
-#[derive(Default, Debug, Clone, serde::Deserialize)]
+#[derive(Default, Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 #[serde(default)]
 pub struct ResourceType {
@@ -0,0 +1,232 @@
+//! Recording and playback of object-stream sessions
+//!
+//! Session files are gzip compressed, newline delimited json [Record]s. Every recording appends to
+//! the file, starting with the layout used to decode the terrain messages. Each record is written
+//! as its own complete gzip member, the client may exit at any point without closing the file.
+//! Records are written on a dedicated thread, so the stream listener never waits for the disk.
+//! Binary frames are stored base64 encoded, together with the wire format they were received in.
+//!
+//! Record with `--record <path>` or `CAO_RECORD`, play back with `--replay <path>` or
+//! `CAO_REPLAY`.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use bevy::prelude::*;
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+
+use super::{
+    cao_sim_model::AxialPos, handle_message, wire_format::WireFormat, Connected, ConnectionState,
+    ConnectionStateRes, NewEntities, NewTerrain,
+};
+use crate::server_config::arg_or_env;
+
+#[derive(Debug, Clone, Default)]
+pub struct ReplayConfig {
+    pub record: Option<PathBuf>,
+    pub replay: Option<PathBuf>,
+}
+
+impl ReplayConfig {
+    pub fn load() -> Self {
+        let config = Self {
+            record: arg_or_env("record", "CAO_RECORD").map(PathBuf::from),
+            replay: arg_or_env("replay", "CAO_REPLAY").map(PathBuf::from),
+        };
+        if config.record.is_some() && config.replay.is_some() {
+            warn!("Both recording and replay were requested, the session will not be recorded");
+        }
+        config
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(tag = "ty", content = "payload")]
+pub enum Record {
+    Layout(Vec<AxialPos>),
+    /// Raw text frame
+    Text {
+        /// milliseconds since the start of the recording
+        ts: u64,
+        data: String,
+    },
+    /// Raw binary frame
+    Binary {
+        /// milliseconds since the start of the recording
+        ts: u64,
+        format: WireFormat,
+        /// base64 encoded frame
+        data: String,
+    },
+}
+
+/// Sends the records to the thread writing them
+pub struct SessionRecorder {
+    records: crossbeam::channel::Sender<Record>,
+    start: Instant,
+}
+
+impl SessionRecorder {
+    pub fn open(path: &Path, layout: &[AxialPos]) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open session file {:?}", path))?;
+        let (records, receiver) = crossbeam::channel::unbounded();
+        let writer = BufWriter::new(file);
+        let thread_path = path.to_path_buf();
+        std::thread::Builder::new()
+            .name("session-recorder".to_string())
+            .spawn(move || write_records(writer, receiver, thread_path))
+            .with_context(|| "Failed to start the recorder thread")?;
+        let recorder = Self {
+            records,
+            start: Instant::now(),
+        };
+        recorder.send(Record::Layout(layout.to_vec()))?;
+        info!("Recording session to {:?}", path);
+        Ok(recorder)
+    }
+
+    pub fn record_text(&mut self, data: &str) -> anyhow::Result<()> {
+        let ts = self.start.elapsed().as_millis() as u64;
+        self.send(Record::Text {
+            ts,
+            data: data.to_string(),
+        })
+    }
+
+    pub fn record_binary(&mut self, format: WireFormat, data: &[u8]) -> anyhow::Result<()> {
+        let ts = self.start.elapsed().as_millis() as u64;
+        self.send(Record::Binary {
+            ts,
+            format,
+            data: base64::encode(data),
+        })
+    }
+
+    /// Fails once the writer thread stopped
+    fn send(&self, record: Record) -> anyhow::Result<()> {
+        self.records
+            .send(record)
+            .map_err(|_| anyhow::anyhow!("The recording stopped"))
+    }
+}
+
+fn write_record(writer: &mut BufWriter<File>, record: &Record) -> anyhow::Result<()> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    serde_json::to_writer(&mut encoder, record).with_context(|| "Failed to serialize record")?;
+    encoder.write_all(b"\n")?;
+    let member = encoder
+        .finish()
+        .with_context(|| "Failed to compress record")?;
+    writer
+        .write_all(member.as_slice())
+        .with_context(|| "Failed to write record")?;
+    // flush every record so a crashed client still leaves a readable session behind
+    writer.flush().with_context(|| "Failed to flush record")
+}
+
+/// Writes the records until the recorder is dropped or a write fails
+fn write_records(
+    mut writer: BufWriter<File>,
+    records: crossbeam::channel::Receiver<Record>,
+    path: PathBuf,
+) {
+    for record in records.iter() {
+        if let Err(err) = write_record(&mut writer, &record) {
+            error!(
+                "Failed to record to {:?}, stopping the recording {:?}",
+                path, err
+            );
+            return;
+        }
+    }
+}
+
+fn sleep_until(due: Instant) {
+    let now = Instant::now();
+    if due > now {
+        std::thread::sleep(due - now);
+    }
+}
+
+/// Feeds the recorded messages into the entity and terrain channels, in (recorded) real time
+///
+/// Blocks until the session file is exhausted
+pub fn play_session(
+    path: PathBuf,
+    state: ConnectionStateRes,
+    msg_recv: crossbeam::channel::Receiver<tungstenite::Message>,
+    entities_sender: crossbeam::channel::Sender<NewEntities>,
+    terrain_sender: crossbeam::channel::Sender<NewTerrain>,
+    reconnect_sender: crossbeam::channel::Sender<Connected>,
+) -> anyhow::Result<()> {
+    let file = File::open(&path).with_context(|| format!("Failed to open session {:?}", path))?;
+    let reader = BufReader::new(MultiGzDecoder::new(file));
+
+    info!("Replaying session {:?}", path);
+    state.store(ConnectionState::Online, Ordering::Release);
+    reconnect_sender.send(Connected).unwrap_or_default();
+
+    let mut layout = Vec::new();
+    let mut segment_start = Instant::now();
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            // sessions recorded by older clients may end in an unfinished gzip member
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                warn!("Session {:?} ends with a truncated record", path);
+                break;
+            }
+            Err(err) => return Err(err).with_context(|| "Failed to read session"),
+        };
+        // nobody is listening to subscriptions during replay
+        while msg_recv.try_recv().is_ok() {}
+
+        match serde_json::from_str(line.as_str()).with_context(|| "Failed to parse record")? {
+            Record::Layout(l) => {
+                debug!("New recording segment");
+                layout = l;
+                segment_start = Instant::now();
+            }
+            Record::Text { ts, data } => {
+                sleep_until(segment_start + Duration::from_millis(ts));
+                if let Err(err) = handle_message(
+                    WireFormat::Json,
+                    data.as_bytes(),
+                    &terrain_sender,
+                    &entities_sender,
+                    &layout,
+                ) {
+                    error!("Failed to handle recorded message {:?}", err);
+                }
+            }
+            Record::Binary { ts, format, data } => {
+                let data = base64::decode(data.as_str())
+                    .with_context(|| "Failed to decode binary record")?;
+                sleep_until(segment_start + Duration::from_millis(ts));
+                if let Err(err) = handle_message(
+                    format,
+                    data.as_slice(),
+                    &terrain_sender,
+                    &entities_sender,
+                    &layout,
+                ) {
+                    error!("Failed to handle recorded message {:?}", err);
+                }
+            }
+        }
+    }
+    info!("Replay of {:?} finished", path);
+    state.store(ConnectionState::Closed, Ordering::Release);
+    Ok(())
+}
@@ -0,0 +1,112 @@
+//! Encodings of the object-stream messages
+//!
+//! The encoding is negotiated during the websocket handshake via the `Sec-WebSocket-Protocol`
+//! header. The client offers the formats it accepts in order of preference, the server answers
+//! with the one it picked. Servers not answering are assumed to speak json.
+//!
+//! Only the server -> client messages are affected, room subscriptions are always sent as json.
+//!
+//! Clients able to apply `EntitiesDelta` messages announce it with the [ENTITY_DELTAS_HEADER].
+
+use std::{fmt, str::FromStr};
+
+use anyhow::Context;
+
+use super::cao_sim_model::Message;
+
+pub const SUBPROTOCOL_HEADER: &str = "Sec-WebSocket-Protocol";
+/// Set by clients accepting delta compressed entity payloads
+pub const ENTITY_DELTAS_HEADER: &str = "Caolo-Entity-Deltas";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum WireFormat {
+    Json,
+    MessagePack,
+}
+
+impl Default for WireFormat {
+    fn default() -> Self {
+        WireFormat::Json
+    }
+}
+
+impl WireFormat {
+    /// Formats offered by the client, most preferred first
+    pub const ALL: [WireFormat; 2] = [WireFormat::MessagePack, WireFormat::Json];
+
+    pub fn subprotocol(self) -> &'static str {
+        match self {
+            WireFormat::Json => "caolo.json",
+            WireFormat::MessagePack => "caolo.msgpack",
+        }
+    }
+
+    pub fn from_subprotocol(protocol: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|f| f.subprotocol() == protocol.trim())
+    }
+
+    /// Value of the subprotocol header offering the given formats
+    pub fn offer(formats: &[WireFormat]) -> String {
+        formats
+            .iter()
+            .map(|f| f.subprotocol())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Pick the first format of the offer that is also in `supported`
+    pub fn negotiate(offer: &str, supported: &[WireFormat]) -> Option<Self> {
+        offer
+            .split(',')
+            .filter_map(Self::from_subprotocol)
+            .find(|f| supported.contains(f))
+    }
+
+    pub fn decode(self, payload: &[u8]) -> anyhow::Result<Message> {
+        match self {
+            WireFormat::Json => {
+                serde_json::from_slice(payload).with_context(|| "Failed to deserialize json msg")
+            }
+            WireFormat::MessagePack => rmp_serde::from_read_ref(payload)
+                .with_context(|| "Failed to deserialize msgpack msg"),
+        }
+    }
+
+    pub fn encode(self, msg: &Message) -> anyhow::Result<Vec<u8>> {
+        match self {
+            WireFormat::Json => {
+                serde_json::to_vec(msg).with_context(|| "Failed to serialize json msg")
+            }
+            // struct fields are encoded as maps, so `#[serde(default)]` fields may be omitted
+            WireFormat::MessagePack => {
+                rmp_serde::to_vec_named(msg).with_context(|| "Failed to serialize msgpack msg")
+            }
+        }
+    }
+}
+
+impl fmt::Display for WireFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            WireFormat::Json => "json",
+            WireFormat::MessagePack => "msgpack",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for WireFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(WireFormat::Json),
+            "msgpack" | "messagepack" => Ok(WireFormat::MessagePack),
+            _ => Err(anyhow::anyhow!("Unknown wire format {}", s)),
+        }
+    }
+}
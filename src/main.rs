@@ -1,4 +1,5 @@
 mod account;
+mod api_client;
 mod bots;
 mod camera_control;
 mod cao_entities;
@@ -10,14 +11,13 @@ mod mining;
 mod resources;
 mod room_interaction;
 mod room_ui;
+mod script_assignment;
+mod server_config;
 mod structures;
 mod terrain;
 
 use bevy::prelude::*;
 
-pub const API_BASE_URL: &str = "http://localhost:8000/v1";
-pub const WS_BASE_URL: &str = "ws://localhost:8080";
-
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub enum AppState {
     MainMenu,
@@ -45,6 +45,7 @@ fn main() {
         })
         .insert_resource(DefaultTaskPoolOptions::default())
         .add_plugins(DefaultPlugins)
+        .add_plugin(server_config::ServerConfigPlugin)
         .add_plugin(cao_sim_client::CaoSimPlugin)
         .add_plugin(bots::BotsPlugin)
         .add_plugin(terrain::TerrainPlugin)
@@ -56,7 +57,9 @@ fn main() {
         .add_plugin(main_menu::MainMenuPlugin)
         .add_plugin(bevy_egui::EguiPlugin)
         .add_plugin(room_ui::RoomUiPlugin)
+        .add_plugin(script_assignment::ScriptAssignmentPlugin)
         .add_plugin(cao_lang_client::CaoLangPlugin)
+        .add_plugin(api_client::ApiClientPlugin)
         .add_plugin(account::AccountPlugin)
         .add_plugin(cao_lang_editor::CaoLangEditorPlugin)
         .add_plugin(cao_entities::CaoEntityPlugin)
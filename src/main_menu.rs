@@ -1,6 +1,8 @@
 use crate::{
     account,
+    cao_lang_client::{MyPrograms, RefreshMyPrograms},
     cao_sim_client::{ConnectionState, ConnectionStateRes},
+    server_config::{ServerConfig, ServerProfileChanged},
     AppState,
 };
 use bevy::{ecs::schedule::ShouldRun, prelude::*};
@@ -35,25 +37,72 @@ fn login_via_env_system(mut login_event: EventWriter<account::StartLoginEvent>)
     if let Ok((username, password)) =
         username.and_then(|uname| std::env::var("CAO_PW").map(|pw| (uname, pw)))
     {
-        let event = account::StartLoginEvent { username, password };
+        let event = account::StartLoginEvent {
+            username,
+            password,
+            remember: false,
+        };
         login_event.send(event);
     } else {
         debug!("No login credentials were provided via env variables");
     }
 }
 
+fn server_select_ui(
+    ui: &mut egui::Ui,
+    config: &mut ServerConfig,
+    server_changed: &mut EventWriter<ServerProfileChanged>,
+    enabled: bool,
+) {
+    let mut selected = config.current_index();
+    ui.horizontal(|ui| {
+        ui.label("server");
+        ui.set_enabled(enabled);
+        egui::ComboBox::from_id_source("server-profile")
+            .selected_text(config.current().name.as_str())
+            .show_ui(ui, |ui| {
+                for (i, profile) in config.profiles.iter().enumerate() {
+                    ui.selectable_value(&mut selected, i, profile.name.as_str())
+                        .on_hover_text(format!(
+                            "{}\n{}",
+                            profile.api_base_url, profile.ws_base_url
+                        ));
+                }
+            });
+    });
+    if config.select(selected) {
+        info!("Selected server profile {:?}", config.current());
+        server_changed.send(ServerProfileChanged);
+    }
+}
+
 fn login_system(
-    mut local_event: Local<account::StartLoginEvent>,
+    mut form: Local<account::StartRegisterEvent>,
+    mut registering: Local<bool>,
     egui_ctx: ResMut<EguiContext>, // exclusive ownership
     mut login_event: EventWriter<account::StartLoginEvent>,
+    mut register_event: EventWriter<account::StartRegisterEvent>,
     mut state: ResMut<State<AppState>>,
+    mut config: ResMut<ServerConfig>,
+    mut server_changed: EventWriter<ServerProfileChanged>,
     error: Res<account::LastLoginError>,
-    q_login: Query<(), With<account::LoginRequestTask>>,
+    q_login: Query<(), Or<(With<account::LoginRequestTask>, With<account::RegisterTask>)>>,
 ) {
-    let has_login_request_in_flight = q_login.single().is_ok();
+    let has_login_request_in_flight = q_login.iter().next().is_some();
     egui::CentralPanel::default().show(egui_ctx.ctx(), |ui| {
         ui.vertical_centered(|ui| {
-            ui.heading("Login");
+            ui.horizontal(|ui| {
+                ui.selectable_value(&mut *registering, false, "Login");
+                ui.selectable_value(&mut *registering, true, "Register");
+            });
+
+            server_select_ui(
+                ui,
+                &mut *config,
+                &mut server_changed,
+                !has_login_request_in_flight,
+            );
+            ui.separator();
 
             if let Some(ref error) = error.0 {
                 ui.colored_label(egui::color::Rgba::RED, error);
@@ -61,20 +110,40 @@ fn login_system(
 
             ui.horizontal(|ui| {
                 ui.label("username");
-                ui.text_edit_singleline(&mut local_event.username);
+                ui.text_edit_singleline(&mut form.username);
             });
+            if *registering {
+                ui.horizontal(|ui| {
+                    ui.label("email");
+                    ui.text_edit_singleline(&mut form.email);
+                });
+            }
             ui.separator();
             ui.horizontal(|ui| {
                 ui.label("password");
-                ui.add(egui::TextEdit::singleline(&mut local_event.password).password(true));
+                ui.add(egui::TextEdit::singleline(&mut form.password).password(true));
             });
+            if *registering {
+                ui.horizontal(|ui| {
+                    ui.label("confirm password");
+                    ui.add(egui::TextEdit::singleline(&mut form.password_confirm).password(true));
+                });
+            }
+            ui.checkbox(&mut form.remember, "Remember me")
+                .on_hover_text("Keep me logged in on this computer");
 
             if has_login_request_in_flight {
                 ui.label("…");
-            } else {
-                if ui.button("Login").clicked() {
-                    login_event.send(local_event.clone());
+            } else if *registering {
+                if ui.button("Register").clicked() {
+                    register_event.send(form.clone());
                 }
+            } else if ui.button("Login").clicked() {
+                login_event.send(account::StartLoginEvent {
+                    username: form.username.clone(),
+                    password: form.password.clone(),
+                    remember: form.remember,
+                });
             }
             if ui.button("CaoLang").clicked() {
                 state.set(AppState::CaoLangEditor).unwrap_or_default();
@@ -83,16 +152,83 @@ fn login_system(
     });
 }
 
+fn profile_ui(
+    ui: &mut egui::Ui,
+    user: &account::CurrentUser,
+    programs: &MyPrograms,
+    refresh_programs: &mut EventWriter<RefreshMyPrograms>,
+) {
+    ui.heading("Profile");
+    let user = match user.0.as_ref() {
+        Some(u) => u,
+        None => {
+            ui.label("Loading…");
+            return;
+        }
+    };
+    egui::Grid::new("profile-grid").show(ui, |ui| {
+        ui.label("Username");
+        ui.label(user.username.as_str());
+        ui.end_row();
+        ui.label("User id");
+        ui.label(user.user_id.as_str());
+        ui.end_row();
+        if let Some(email) = user.email.as_ref() {
+            ui.label("Email");
+            ui.label(email.as_str());
+            ui.end_row();
+        }
+        if let Some(count) = user.bot_count {
+            ui.label("Bots");
+            ui.label(count.to_string());
+            ui.end_row();
+        }
+        if let Some(count) = user.structure_count {
+            ui.label("Structures");
+            ui.label(count.to_string());
+            ui.end_row();
+        }
+    });
+
+    ui.horizontal(|ui| {
+        ui.label("Programs");
+        if ui.small_button("⟳").on_hover_text("Refresh").clicked() {
+            refresh_programs.send(RefreshMyPrograms);
+        }
+    });
+    if programs.0.is_empty() {
+        ui.label("No programs yet");
+    }
+    for program in programs.0.iter() {
+        ui.label(program.name.as_str()).on_hover_text(format!(
+            "id: {}\ncreated: {}\nupdated: {}",
+            program.program_id,
+            program.created.as_deref().unwrap_or("-"),
+            program.updated.as_deref().unwrap_or("-"),
+        ));
+    }
+}
+
 fn update_menu_system(
     egui_ctx: ResMut<EguiContext>, // exclusive ownership
     mut state: ResMut<State<AppState>>,
     connection_state: Res<ConnectionStateRes>,
+    config: Res<ServerConfig>,
+    mut login_error: ResMut<account::LastLoginError>,
+    mut logout: EventWriter<account::LogoutEvent>,
+    user: Res<account::CurrentUser>,
+    programs: Res<MyPrograms>,
+    mut refresh_programs: EventWriter<RefreshMyPrograms>,
 ) {
     let connection_state = connection_state.load(std::sync::atomic::Ordering::Relaxed);
     let connected = matches!(connection_state, ConnectionState::Online);
 
     egui::CentralPanel::default().show(egui_ctx.ctx(), |ui| {
         ui.vertical_centered(|ui| {
+            ui.horizontal(|ui| {
+                ui.label("Server: ");
+                ui.label(config.current().name.as_str());
+            });
             ui.horizontal(|ui| {
                 ui.label("Connection state: ");
 
@@ -101,11 +237,23 @@ fn update_menu_system(
                     ConnectionState::Online => "Online",
                     ConnectionState::Closed => "Closed",
                     ConnectionState::Error => "Error",
+                    ConnectionState::Unauthorized => "Unauthorized",
                 };
 
                 ui.label(pl);
             });
 
+            if matches!(connection_state, ConnectionState::Unauthorized) {
+                ui.colored_label(
+                    egui::color::Rgba::RED,
+                    "The server rejected your session, please log in again",
+                );
+                if ui.button("Log in").clicked() {
+                    logout.send(account::LogoutEvent);
+                    login_error.0 = Some("Session expired".to_string());
+                }
+            }
+
             if connected {
                 if ui.button("Let's go").clicked() {
                     state.set(AppState::Room).unwrap_or_default();
@@ -114,6 +262,12 @@ fn update_menu_system(
                     state.set(AppState::CaoLangEditor).unwrap_or_default();
                 }
             }
+            ui.separator();
+            profile_ui(ui, &*user, &*programs, &mut refresh_programs);
+            ui.separator();
+            if ui.button("Logout").clicked() {
+                logout.send(account::LogoutEvent);
+            }
         });
     });
 }
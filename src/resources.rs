@@ -10,7 +10,7 @@ use bevy::{
 };
 
 use crate::{
-    cao_entities::{pos_2d_to_3d, EntityMetadata, EntityMovedEvent, NewEntityEvent},
+    cao_entities::{pos_2d_to_3d, Despawning, EntityMetadata, EntityMovedEvent, NewEntityEvent},
     cao_sim_client::cao_sim_model::{self, EntityPosition},
 };
 
@@ -68,6 +68,14 @@ fn on_new_entities(
     }
 }
 
+/// depleted resources fade away
+fn despawn_animation_system(mut q: Query<(&Despawning, &mut Transform), With<Resource>>) {
+    for (despawning, mut tr) in q.iter_mut() {
+        let t = ezing::quad_out(despawning.timer.percent());
+        tr.scale = Vec3::splat(1.0 - t);
+    }
+}
+
 fn on_resource_move_system(
     mut moved_entities: EventReader<EntityMovedEvent>,
     mut res_data: Query<(&cao_sim_model::Resource, &EntityPosition, &mut Transform)>,
@@ -132,7 +140,8 @@ impl Plugin for ResourcesPlugin {
                 SystemSet::on_update(crate::AppState::Room)
                     .with_system(on_new_entities.system())
                     .with_system(on_resource_move_system.system())
-                    .with_system(update_res_materials.system()),
+                    .with_system(update_res_materials.system())
+                    .with_system(despawn_animation_system.system()),
             )
             .add_asset::<resource_assets::ResourceMaterial>()
             .init_resource::<resource_assets::ResourceRenderingAssets>();
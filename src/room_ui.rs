@@ -1,6 +1,13 @@
 use crate::{
+    account::CurrentUser,
+    bots::DeathMarker,
+    cao_entities::timeline::{PlaybackState, Timeline, TimelineCommand},
+    cao_lang_client::{cao_lang_model::ProgramSummary, MyPrograms},
     cao_sim_client::{cao_sim_model, ConnectionStateRes, NewEntities},
     room_interaction::{HoveredTile, SelectedEntity},
+    script_assignment::{
+        runs_program, AssignScriptEvent, AssignmentState, PendingAssignment, ScriptAssignments,
+    },
     terrain::CurrentRoom,
 };
 use bevy::{diagnostic::Diagnostics, prelude::*};
@@ -16,7 +23,7 @@ struct Diag {
 
 fn on_new_entities(mut data: ResMut<Diag>, mut new_entities: EventReader<NewEntities>) {
     for entities in new_entities.iter() {
-        data.time = data.time.max(entities.0.time);
+        data.time = data.time.max(entities.0.time());
     }
 }
 
@@ -26,6 +33,7 @@ fn update_ui_system(
     connection_state: Res<ConnectionStateRes>,
     current_room: Res<CurrentRoom>,
     hovered: Res<HoveredTile>,
+    death_markers: Query<&DeathMarker>,
 ) {
     let connection_state = connection_state.load(std::sync::atomic::Ordering::Relaxed);
     egui::Window::new("Room diagnostics").show(egui_ctx.ctx(), |ui| {
@@ -33,10 +41,83 @@ fn update_ui_system(
         ui.label(format!("Connection state: {:?}", connection_state));
         ui.label(format!("Current room: {:?}", current_room.room_id));
         ui.label(format!("Hovered tile: {:?}", hovered.axial));
+        for marker in death_markers.iter().filter(|m| m.pos == hovered.axial) {
+            ui.separator();
+            ui.label(format!("Bot {} decayed here", marker.bot_id.0));
+            if let Some(decay) = marker.decay.as_ref() {
+                ui.label(format!(
+                    "Decay: {} hp every {} ticks",
+                    decay.hp_amount, decay.interval
+                ));
+            }
+        }
+    });
+}
+
+const PLAYBACK_SPEEDS: &[f32] = &[0.25, 0.5, 1.0, 2.0, 4.0];
+
+fn timeline_ui_system(
+    egui_ctx: Res<EguiContext>,
+    timeline: Res<Timeline>,
+    mut commands: EventWriter<TimelineCommand>,
+) {
+    egui::Window::new("Timeline").show(egui_ctx.ctx(), |ui| {
+        let (first, last) = match (timeline.first_tick(), timeline.last_tick()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => {
+                ui.label("No ticks received yet");
+                return;
+            }
+        };
+        ui.horizontal(|ui| {
+            if ui.small_button("⏮").on_hover_text("Step back").clicked() {
+                commands.send(TimelineCommand::Step(-1));
+            }
+            match timeline.state {
+                PlaybackState::Live | PlaybackState::Playing => {
+                    if ui.small_button("⏸").on_hover_text("Pause").clicked() {
+                        commands.send(TimelineCommand::Pause);
+                    }
+                }
+                PlaybackState::Paused => {
+                    if ui.small_button("▶").on_hover_text("Play").clicked() {
+                        commands.send(TimelineCommand::Play);
+                    }
+                }
+            }
+            if ui.small_button("⏭").on_hover_text("Step forward").clicked() {
+                commands.send(TimelineCommand::Step(1));
+            }
+            let live = timeline.state == PlaybackState::Live;
+            if ui.selectable_label(live, "Live").clicked() && !live {
+                commands.send(TimelineCommand::GoLive);
+            }
+        });
+
+        let mut cursor = timeline.cursor;
+        if ui
+            .add(egui::Slider::new(&mut cursor, first..=last).text("Tick"))
+            .changed()
+        {
+            commands.send(TimelineCommand::Seek(cursor));
+        }
+
+        ui.horizontal(|ui| {
+            ui.label("Speed");
+            for speed in PLAYBACK_SPEEDS.iter().copied() {
+                if ui
+                    .selectable_label(timeline.speed == speed, format!("{}x", speed))
+                    .clicked()
+                {
+                    commands.send(TimelineCommand::SetSpeed(speed));
+                }
+            }
+        });
+        ui.label(format!("Buffered ticks: {}..={}", first, last));
     });
 }
 
-fn decode_uuid(b64id: &str) -> uuid::Uuid {
+pub fn decode_uuid(b64id: &str) -> uuid::Uuid {
     let mut payload = [0u8; 16];
     base64::decode_config_slice(
         b64id.as_bytes(),
@@ -47,7 +128,44 @@ fn decode_uuid(b64id: &str) -> uuid::Uuid {
     uuid::Uuid::from_bytes(payload)
 }
 
-fn show_bot(this: &cao_sim_model::Bot, ui: &mut Ui) {
+/// Name of the program if it's one of ours
+fn script_label(script: &cao_sim_model::Script, programs: &MyPrograms) -> String {
+    programs
+        .0
+        .iter()
+        .find(|p| runs_program(Some(script), p.program_id.as_str()))
+        .map(|p| format!("{} ✔", p.name))
+        .unwrap_or_else(|| decode_uuid(script.data.as_str()).to_string())
+}
+
+fn show_script(
+    script: Option<&cao_sim_model::Script>,
+    programs: &MyPrograms,
+    pending: Option<&PendingAssignment>,
+    ui: &mut Ui,
+) {
+    if let Some(script) = script {
+        ui.label("Script");
+        ui.label(script_label(script, programs));
+        ui.end_row();
+    }
+    if let Some(pending) = pending {
+        ui.label("Assigning");
+        let state = match pending.state {
+            AssignmentState::Sending => "compiling and sending",
+            AssignmentState::Sent => "waiting for the next tick",
+        };
+        ui.label(format!("⏳ {} ({})", pending.program.name, state));
+        ui.end_row();
+    }
+}
+
+fn show_bot(
+    this: &cao_sim_model::Bot,
+    programs: &MyPrograms,
+    pending: Option<&PendingAssignment>,
+    ui: &mut Ui,
+) {
     ui.heading("Bot");
     ui.end_row();
     ui.label("ID");
@@ -69,12 +187,7 @@ fn show_bot(this: &cao_sim_model::Bot, ui: &mut Ui) {
         ui.label(format!("{}/{}", car.value, car.value_max));
         ui.end_row();
     }
-    if let Some(script) = this.script.as_ref() {
-        let id = decode_uuid(script.data.as_str());
-        ui.label("Script");
-        ui.label(id.to_string());
-        ui.end_row();
-    }
+    show_script(this.script.as_ref(), programs, pending, ui);
     if let Some(owner) = this.owner.as_ref() {
         let id = decode_uuid(owner.data.as_str());
         ui.label("Owner");
@@ -100,7 +213,12 @@ fn show_bot(this: &cao_sim_model::Bot, ui: &mut Ui) {
     }
 }
 
-fn show_structure(this: &cao_sim_model::Structure, ui: &mut Ui) {
+fn show_structure(
+    this: &cao_sim_model::Structure,
+    programs: &MyPrograms,
+    pending: Option<&PendingAssignment>,
+    ui: &mut Ui,
+) {
     ui.heading("Structure");
     ui.end_row();
     ui.label("ID");
@@ -120,6 +238,7 @@ fn show_structure(this: &cao_sim_model::Structure, ui: &mut Ui) {
         ui.label(format!("{}", owner.data));
         ui.end_row();
     }
+    show_script(this.script.as_ref(), programs, pending, ui);
     match &this.structure_body {
         cao_sim_model::StructureBody::Spawn(s) => {
             if s.time_to_spawn > 0 {
@@ -174,13 +293,97 @@ fn show_resource(this: &cao_sim_model::Resource, ui: &mut Ui) {
     ui.end_row();
 }
 
+#[derive(Default)]
+struct AssignScriptForm {
+    entity_id: Option<u64>,
+    program: Option<ProgramSummary>,
+    confirming: bool,
+}
+
+fn assign_script_ui(
+    ui: &mut Ui,
+    entity_id: u64,
+    form: &mut AssignScriptForm,
+    programs: &MyPrograms,
+    assign: &mut EventWriter<AssignScriptEvent>,
+) {
+    if form.entity_id != Some(entity_id) {
+        *form = AssignScriptForm {
+            entity_id: Some(entity_id),
+            ..Default::default()
+        };
+    }
+    ui.heading("Assign script");
+    // programs are empty until they're first saved, saved ones are compiled before assigning
+    let saved = programs.0.iter().filter(|p| p.version > 0);
+    let selected = form
+        .program
+        .as_ref()
+        .map(|p| p.name.as_str())
+        .unwrap_or("Select a program");
+    egui::ComboBox::from_id_source("assign-script-program")
+        .selected_text(selected)
+        .show_ui(ui, |ui| {
+            for program in saved {
+                let is_selected = form
+                    .program
+                    .as_ref()
+                    .map(|p| p.program_id == program.program_id)
+                    .unwrap_or(false);
+                if ui
+                    .selectable_label(is_selected, program.name.as_str())
+                    .clicked()
+                {
+                    form.program = Some(program.clone());
+                    form.confirming = false;
+                }
+            }
+        });
+    let program = match form.program.as_ref() {
+        Some(p) => p,
+        None => return,
+    };
+    if !form.confirming {
+        if ui.button("Assign script").clicked() {
+            form.confirming = true;
+        }
+        return;
+    }
+    ui.label(format!("Run {} on entity {}?", program.name, entity_id));
+    ui.horizontal(|ui| {
+        if ui.button("Assign").clicked() {
+            assign.send(AssignScriptEvent {
+                entity_id,
+                program: program.clone(),
+            });
+            form.confirming = false;
+        }
+        if ui.button("Cancel").clicked() {
+            form.confirming = false;
+        }
+    });
+}
+
 fn right_panel_system(
     egui_ctx: Res<EguiContext>,
     selected_entity: Res<SelectedEntity>,
+    user: Res<CurrentUser>,
+    programs: Res<MyPrograms>,
+    assignments: Res<ScriptAssignments>,
+    mut assign: EventWriter<AssignScriptEvent>,
+    mut form: Local<AssignScriptForm>,
     bot_q: Query<&cao_sim_model::Bot>,
     res_q: Query<&cao_sim_model::Resource>,
     stu_q: Query<&cao_sim_model::Structure>,
 ) {
+    let user_id = user
+        .0
+        .as_ref()
+        .and_then(|u| uuid::Uuid::parse_str(u.user_id.as_str()).ok());
+    let is_mine = |owner: Option<&cao_sim_model::Owner>| match (owner, user_id) {
+        (Some(owner), Some(id)) => decode_uuid(owner.data.as_str()) == id,
+        _ => false,
+    };
     egui::SidePanel::right("selected-entity")
         .min_width(250.)
         .resizable(false)
@@ -190,17 +393,34 @@ fn right_panel_system(
                 ui.columns(1, |uis| {
                     let ui = &mut uis[0];
 
+                    // bots and spawns of the user may be given a script
+                    let mut assignable = None;
                     egui::Grid::new("current_structure")
                         .striped(true)
                         .show(ui, |ui| {
                             if let Ok(bot) = bot_q.get(selected) {
-                                show_bot(bot, ui);
+                                show_bot(bot, &*programs, assignments.0.get(&bot.id), ui);
+                                if is_mine(bot.owner.as_ref()) {
+                                    assignable = Some(bot.id);
+                                }
                             } else if let Ok(structure) = stu_q.get(selected) {
-                                show_structure(structure, ui);
+                                show_structure(
+                                    structure,
+                                    &*programs,
+                                    assignments.0.get(&structure.id),
+                                    ui,
+                                );
+                                if is_mine(structure.owner.as_ref()) {
+                                    assignable = Some(structure.id);
+                                }
                             } else if let Ok(resource) = res_q.get(selected) {
                                 show_resource(resource, ui);
                             }
                         });
+                    if let Some(id) = assignable {
+                        ui.separator();
+                        assign_script_ui(ui, id, &mut *form, &*programs, &mut assign);
+                    }
                 });
             }
         });
@@ -230,6 +450,7 @@ impl Plugin for RoomUiPlugin {
                         update_ui_system
                             .system()
                             .chain(right_panel_system.system())
+                            .chain(timeline_ui_system.system())
                             .chain(diagnostics_ui_system.system()),
                     ),
             )
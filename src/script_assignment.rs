@@ -0,0 +1,188 @@
+//! Assigning programs to bots and spawns
+//!
+//! The saved version of the program is compiled first, programs that do not compile are not
+//! assigned. An assignment stays pending until an entities payload shows the entity running the
+//! program.
+
+use std::collections::HashMap;
+
+use bevy::{
+    prelude::*,
+    tasks::{IoTaskPool, Task},
+};
+use futures_lite::future;
+
+use crate::{
+    account::{AuthToken, CurrentAuthToken},
+    api_client::{ApiErrorEvent, CaoApiError},
+    cao_lang_client::{self, cao_lang_model::ProgramSummary, CompileProgramError},
+    cao_sim_client::cao_sim_model,
+    room_ui::decode_uuid,
+    server_config::ServerConfig,
+};
+
+pub struct AssignScriptEvent {
+    pub entity_id: u64,
+    pub program: ProgramSummary,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssignmentState {
+    /// compiling the program, then sending the assignment
+    Sending,
+    /// accepted by the server, waiting for the entity to run it
+    Sent,
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingAssignment {
+    pub program: ProgramSummary,
+    pub state: AssignmentState,
+}
+
+/// Pending assignments by sim entity id
+#[derive(Default)]
+pub struct ScriptAssignments(pub HashMap<u64, PendingAssignment>);
+
+struct AssignTask {
+    entity_id: u64,
+    task: Task<Result<(), CompileProgramError>>,
+}
+
+pub struct ScriptAssignmentPlugin;
+
+/// Whether the entity runs the program
+pub fn runs_program(script: Option<&cao_sim_model::Script>, program_id: &str) -> bool {
+    match (script, uuid::Uuid::parse_str(program_id)) {
+        (Some(script), Ok(id)) => decode_uuid(script.data.as_str()) == id,
+        _ => false,
+    }
+}
+
+async fn compile_and_assign(
+    api_url: String,
+    token: AuthToken,
+    entity_id: u64,
+    program_id: String,
+) -> Result<(), CompileProgramError> {
+    let program =
+        cao_lang_client::fetch_program(api_url.clone(), token.clone(), program_id.clone())
+            .await
+            .map_err(CompileProgramError::Api)?;
+    cao_lang_client::compile_program(api_url.clone(), program.program).await?;
+    cao_lang_client::assign_script(api_url, token, entity_id, program_id)
+        .await
+        .map_err(CompileProgramError::Api)
+}
+
+fn start_assignment_system(
+    mut cmd: Commands,
+    mut events: EventReader<AssignScriptEvent>,
+    mut assignments: ResMut<ScriptAssignments>,
+    token: Res<CurrentAuthToken>,
+    config: Res<ServerConfig>,
+    pool: Res<IoTaskPool>,
+) {
+    for event in events.iter() {
+        let token = match token.0.as_ref() {
+            Some(t) => t.clone(),
+            None => {
+                warn!("Can not assign scripts without logging in");
+                continue;
+            }
+        };
+        let task = pool.spawn(compile_and_assign(
+            config.current().api_base_url.clone(),
+            token,
+            event.entity_id,
+            event.program.program_id.clone(),
+        ));
+        cmd.spawn().insert(AssignTask {
+            entity_id: event.entity_id,
+            task,
+        });
+        assignments.0.insert(
+            event.entity_id,
+            PendingAssignment {
+                program: event.program.clone(),
+                state: AssignmentState::Sending,
+            },
+        );
+    }
+}
+
+fn assignment_tasks_system(
+    mut cmd: Commands,
+    mut assignments: ResMut<ScriptAssignments>,
+    mut api_errors: EventWriter<ApiErrorEvent>,
+    mut tasks: Query<(Entity, &mut AssignTask)>,
+) {
+    for (e, mut t) in tasks.iter_mut() {
+        if let Some(res) = future::block_on(future::poll_once(&mut t.task)) {
+            cmd.entity(e).despawn_recursive();
+            match res {
+                Ok(()) => {
+                    if let Some(pending) = assignments.0.get_mut(&t.entity_id) {
+                        pending.state = AssignmentState::Sent;
+                    }
+                }
+                Err(err) => {
+                    let name = assignments
+                        .0
+                        .remove(&t.entity_id)
+                        .map(|p| p.program.name)
+                        .unwrap_or_default();
+                    let event = match err {
+                        CompileProgramError::Compile(err) => ApiErrorEvent::new(
+                            format!("{} does not compile", name),
+                            CaoApiError::Validation(err.detail),
+                        ),
+                        CompileProgramError::Api(err) => {
+                            ApiErrorEvent::new("Assigning the script", err)
+                        }
+                    };
+                    api_errors.send(event);
+                }
+            }
+        }
+    }
+}
+
+fn confirm_assignments_system(
+    mut assignments: ResMut<ScriptAssignments>,
+    bots: Query<&cao_sim_model::Bot, Changed<cao_sim_model::Bot>>,
+    structures: Query<&cao_sim_model::Structure, Changed<cao_sim_model::Structure>>,
+) {
+    if assignments.0.is_empty() {
+        return;
+    }
+    let updated = bots
+        .iter()
+        .map(|b| (b.id, b.script.as_ref()))
+        .chain(structures.iter().map(|s| (s.id, s.script.as_ref())));
+    for (id, script) in updated {
+        let confirmed = assignments
+            .0
+            .get(&id)
+            .map(|p| {
+                p.state == AssignmentState::Sent
+                    && runs_program(script, p.program.program_id.as_str())
+            })
+            .unwrap_or(false);
+        if confirmed {
+            if let Some(p) = assignments.0.remove(&id) {
+                info!("Entity {} runs {}", id, p.program.name);
+            }
+        }
+    }
+}
+
+impl Plugin for ScriptAssignmentPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(ScriptAssignments::default())
+            .add_event::<AssignScriptEvent>()
+            .add_system(start_assignment_system.system())
+            .add_system(assignment_tasks_system.system())
+            .add_system(confirm_assignments_system.system());
+    }
+}
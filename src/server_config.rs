@@ -0,0 +1,173 @@
+//! Server endpoint configuration
+//!
+//! Profiles are resolved from the built-in defaults, the config file, environment variables and
+//! command line arguments, in this order. Later sources override earlier ones.
+//!
+//! Only the `local` profile is built in, deployments are added by the config file.
+//! Config file: `--config <path>`, `CAO_CONFIG` or `caolo.json` in the working directory, see
+//! `caolo.example.json` for the format. Its profiles replace the built-in one of the same name.
+//! Profile selection: `--server <name>` or `CAO_SERVER`.
+//! Endpoint overrides (applied to the selected profile): `--api-url`/`CAO_API_URL`,
+//! `--ws-url`/`CAO_WS_URL`.
+
+use bevy::prelude::*;
+
+pub const DEFAULT_CONFIG_PATH: &str = "caolo.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerProfile {
+    pub name: String,
+    pub api_base_url: String,
+    pub ws_base_url: String,
+}
+
+impl ServerProfile {
+    pub fn local() -> Self {
+        Self {
+            name: "local".to_string(),
+            api_base_url: "http://localhost:8000/v1".to_string(),
+            ws_base_url: "ws://localhost:8080".to_string(),
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(default)]
+struct ConfigFile {
+    /// name of the profile selected by default
+    profile: Option<String>,
+    profiles: Vec<ServerProfile>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub profiles: Vec<ServerProfile>,
+    current: usize,
+}
+
+/// Sent when the user selects a different server profile
+pub struct ServerProfileChanged;
+
+/// Returns the value of a `--name value` or `--name=value` command line argument
+pub fn cli_arg(name: &str) -> Option<String> {
+    let flag = format!("--{}", name);
+    let prefix = format!("{}=", flag);
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next();
+        }
+        if let Some(value) = arg.strip_prefix(prefix.as_str()) {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
+/// Command line argument, falling back to the environment variable
+pub fn arg_or_env(arg: &str, env: &str) -> Option<String> {
+    cli_arg(arg).or_else(|| std::env::var(env).ok())
+}
+
+fn read_config_file(path: &str) -> anyhow::Result<ConfigFile> {
+    use anyhow::Context;
+
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {}", path))?;
+    serde_json::from_str(content.as_str())
+        .with_context(|| format!("Failed to parse config file {}", path))
+}
+
+impl ServerConfig {
+    pub fn load() -> Self {
+        let explicit_path = arg_or_env("config", "CAO_CONFIG");
+        let path = explicit_path
+            .clone()
+            .unwrap_or_else(|| DEFAULT_CONFIG_PATH.to_string());
+
+        let file = match read_config_file(path.as_str()) {
+            Ok(file) => file,
+            Err(err) => {
+                // a missing default config is fine, a missing explicit config is not
+                if explicit_path.is_some() || std::path::Path::new(path.as_str()).exists() {
+                    error!("{:?}", err);
+                } else {
+                    debug!("No config file found at {}, using defaults", path);
+                }
+                ConfigFile::default()
+            }
+        };
+
+        let mut config = Self {
+            profiles: vec![ServerProfile::local()],
+            current: 0,
+        };
+        for profile in file.profiles {
+            config.upsert(profile);
+        }
+
+        if let Some(name) = arg_or_env("server", "CAO_SERVER").or(file.profile) {
+            match config.profiles.iter().position(|p| p.name == name) {
+                Some(i) => config.current = i,
+                None => warn!("Server profile {} is not configured, using default", name),
+            }
+        }
+
+        if let Some(url) = arg_or_env("api-url", "CAO_API_URL") {
+            config.profiles[config.current].api_base_url = url;
+        }
+        if let Some(url) = arg_or_env("ws-url", "CAO_WS_URL") {
+            config.profiles[config.current].ws_base_url = url;
+        }
+
+        info!("Using server profile {:?}", config.current());
+        config
+    }
+
+    fn upsert(&mut self, profile: ServerProfile) {
+        match self.profiles.iter_mut().find(|p| p.name == profile.name) {
+            Some(p) => *p = profile,
+            None => self.profiles.push(profile),
+        }
+    }
+
+    pub fn current(&self) -> &ServerProfile {
+        &self.profiles[self.current]
+    }
+
+    pub fn current_index(&self) -> usize {
+        self.current
+    }
+
+    /// returns true if the selection changed
+    pub fn select(&mut self, index: usize) -> bool {
+        if index >= self.profiles.len() || index == self.current {
+            return false;
+        }
+        self.current = index;
+        true
+    }
+}
+
+pub struct ServerConfigPlugin;
+
+impl Plugin for ServerConfigPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(ServerConfig::load())
+            .add_event::<ServerProfileChanged>();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_config_parses() {
+        let file: ConfigFile = serde_json::from_str(include_str!("../caolo.example.json")).unwrap();
+        assert!(!file.profiles.is_empty());
+        assert_eq!(file.profile.as_deref(), Some("local"));
+    }
+}
@@ -10,7 +10,7 @@ use bevy::{
 };
 
 use crate::{
-    cao_entities::{pos_2d_to_3d, EntityMetadata, EntityMovedEvent, NewEntityEvent},
+    cao_entities::{pos_2d_to_3d, Despawning, EntityMetadata, EntityMovedEvent, NewEntityEvent},
     cao_sim_client::cao_sim_model::{self, EntityPosition},
 };
 
@@ -71,6 +71,15 @@ fn on_new_entities_system(
     }
 }
 
+/// destroyed structures crumble into the ground
+fn despawn_animation_system(mut q: Query<(&Despawning, &mut Transform), With<Structure>>) {
+    for (despawning, mut tr) in q.iter_mut() {
+        let t = ezing::quad_in(despawning.timer.percent());
+        tr.scale = Vec3::new(1.0 + 0.2 * t, 1.0 - t, 1.0 + 0.2 * t);
+        tr.translation.y = -0.5 * t;
+    }
+}
+
 fn on_structure_move_system(
     mut moved_entities: EventReader<EntityMovedEvent>,
     mut res_data: Query<(&cao_sim_model::Structure, &EntityPosition, &mut Transform)>,
@@ -129,7 +138,8 @@ impl Plugin for StructuresPlugin {
                 SystemSet::on_update(crate::AppState::Room)
                     .with_system(on_new_entities_system.system())
                     .with_system(update_materials_system.system())
-                    .with_system(on_structure_move_system.system()),
+                    .with_system(on_structure_move_system.system())
+                    .with_system(despawn_animation_system.system()),
             );
     }
 }